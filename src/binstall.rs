@@ -0,0 +1,105 @@
+use futures_util::{stream, StreamExt};
+use std::io::{Error, ErrorKind, Result};
+
+use crate::download::{Downloader, Overwrite};
+
+/// Prebuilt binaries published by cargo-quickinstall, which cargo-binstall falls back to when a
+/// crate does not publish release artifacts of its own.
+pub const QUICKINSTALL_ROOT_URL: &str =
+    "https://github.com/cargo-bins/cargo-quickinstall/releases/download";
+
+/// A tool to mirror, given as `name` (latest release in the mirrored index) or `name@version`.
+#[derive(Debug, Clone)]
+pub struct Tool {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl std::str::FromStr for Tool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => (name, Some(version.to_string())),
+            None => (s, None),
+        };
+
+        if name.is_empty() || version.as_deref() == Some("") {
+            return Err(format!("Invalid tool: {}", s));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            version,
+        })
+    }
+}
+
+/// Resolves the latest version of the tool that is neither yanked nor a pre-release, from the index
+/// in the mirror.
+fn latest_version(downloader: &Downloader, name: &str) -> Result<String> {
+    let index = downloader.bare_index();
+    let index = index
+        .open_or_clone()
+        .map_err(|err| Error::other(err.to_string()))?;
+
+    let krate = index.crate_(name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Crate {} is not in the index.", name),
+        )
+    })?;
+
+    krate
+        .versions()
+        .iter()
+        .rev()
+        .find(|version| !version.is_yanked() && !version.version().contains('-'))
+        .map(|version| version.version().to_string())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Crate {} has no released versions.", name),
+            )
+        })
+}
+
+/// Mirrors the quickinstall artifacts of the tools for the architectures, using the same layout as the
+/// release downloads: `/quickinstall/<name>-<version>/<name>-<version>-<target>.tar.gz`.
+pub async fn mirror(
+    downloader: &Downloader,
+    concurrency: usize,
+    tools: &[Tool],
+    architectures: &[String],
+) -> Result<()> {
+    let mut paths = Vec::new();
+
+    for tool in tools {
+        let version = match &tool.version {
+            Some(version) => version.clone(),
+            None => latest_version(downloader, &tool.name)?,
+        };
+
+        let release = format!("{}-{}", tool.name, version);
+        for arch in architectures {
+            paths.push(format!("/{}/{}-{}.tar.gz", release, release, arch));
+        }
+    }
+
+    let total = paths.len();
+    stream::iter(paths.iter().enumerate())
+        .for_each_concurrent(concurrency, |(i, path)| async move {
+            log::info!("Downloading binstall artifact [{}/{}]", i + 1, total);
+
+            let url = format!("{}{}", QUICKINSTALL_ROOT_URL, path);
+            let local = format!("/quickinstall{}", path);
+
+            // Not every tool is built for every target, so a missing artifact is expected.
+            if let Err(error) = downloader.download_url(url, &local, Overwrite::False).await {
+                log::warn!("Error downloading binstall artifact {}: {}", path, error);
+            }
+        })
+        .await;
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Result;
+use std::path::Path;
+
+/// A line of a crate file in the index. Only the fields needed to detect changes are parsed.
+#[derive(Debug, Deserialize)]
+struct IndexVersion {
+    name: String,
+    vers: String,
+    cksum: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Version {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChecksumChange {
+    pub name: String,
+    pub version: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The changes to crate versions in the index between two commits.
+#[derive(Debug, Default, Serialize)]
+pub struct Changes {
+    pub from: String,
+    pub to: String,
+    pub added: Vec<Version>,
+    pub removed: Vec<Version>,
+    pub yanked: Vec<Version>,
+    pub unyanked: Vec<Version>,
+    /// Versions whose checksum changed, which should never happen for a published version.
+    pub checksum_changed: Vec<ChecksumChange>,
+}
+
+fn versions(repo: &Repository, blob: Oid) -> Result<BTreeMap<String, IndexVersion>> {
+    if blob.is_zero() {
+        return Ok(BTreeMap::new());
+    }
+
+    let blob = repo.find_blob(blob).map_err(std::io::Error::other)?;
+
+    Ok(blob
+        .content()
+        .split(|b| *b == b'\n')
+        .filter_map(|line| serde_json::from_slice::<IndexVersion>(line).ok())
+        .map(|version| (version.vers.clone(), version))
+        .collect())
+}
+
+impl Changes {
+    /// Compares the crate files that differ between two commits of the index.
+    pub fn between(index_path: &Path, from: &str, to: &str) -> Result<Self> {
+        let repo = Repository::open(index_path).map_err(std::io::Error::other)?;
+        let tree = |commit: &str| {
+            Oid::from_str(commit)
+                .and_then(|oid| repo.find_commit(oid))
+                .and_then(|commit| commit.tree())
+                .map_err(std::io::Error::other)
+        };
+
+        let diff = repo
+            .diff_tree_to_tree(Some(&tree(from)?), Some(&tree(to)?), None)
+            .map_err(std::io::Error::other)?;
+
+        let mut changes = Changes {
+            from: from.to_string(),
+            to: to.to_string(),
+            ..Default::default()
+        };
+
+        for delta in diff.deltas() {
+            let old = versions(&repo, delta.old_file().id())?;
+            let new = versions(&repo, delta.new_file().id())?;
+
+            for (vers, version) in &new {
+                let entry = Version {
+                    name: version.name.clone(),
+                    version: vers.clone(),
+                };
+
+                match old.get(vers) {
+                    None => changes.added.push(entry),
+                    Some(previous) => {
+                        if previous.cksum != version.cksum {
+                            changes.checksum_changed.push(ChecksumChange {
+                                name: version.name.clone(),
+                                version: vers.clone(),
+                                old: previous.cksum.clone(),
+                                new: version.cksum.clone(),
+                            });
+                        }
+
+                        if !previous.yanked && version.yanked {
+                            changes.yanked.push(entry);
+                        } else if previous.yanked && !version.yanked {
+                            changes.unyanked.push(entry);
+                        }
+                    }
+                }
+            }
+
+            for (vers, version) in &old {
+                if !new.contains_key(vers) {
+                    changes.removed.push(Version {
+                        name: version.name.clone(),
+                        version: vers.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Index changes {}..{}\n", self.from, self.to);
+
+        let sections = [
+            ("Added", &self.added),
+            ("Removed", &self.removed),
+            ("Yanked", &self.yanked),
+            ("Unyanked", &self.unyanked),
+        ];
+
+        for (title, versions) in sections {
+            let _ = writeln!(text, "\n{} ({}):", title, versions.len());
+            for version in versions {
+                let _ = writeln!(text, "  {} {}", version.name, version.version);
+            }
+        }
+
+        let _ = writeln!(
+            text,
+            "\nChecksum changed ({}):",
+            self.checksum_changed.len()
+        );
+        for change in &self.checksum_changed {
+            let _ = writeln!(
+                text,
+                "  {} {}: {} -> {}",
+                change.name, change.version, change.old, change.new
+            );
+        }
+
+        text
+    }
+
+    /// Writes the report as `<name>.txt` and `<name>.json` to the directory.
+    pub fn write(&self, directory: &str, name: &str) -> Result<()> {
+        let directory = Path::new(directory);
+        std::fs::create_dir_all(directory)?;
+
+        std::fs::write(directory.join(format!("{}.txt", name)), self.to_text())?;
+        std::fs::write(
+            directory.join(format!("{}.json", name)),
+            serde_json::to_vec_pretty(self)?,
+        )?;
+
+        Ok(())
+    }
+}
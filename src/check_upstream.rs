@@ -0,0 +1,241 @@
+use clap::ArgMatches;
+use reqwest::Client;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::changes::Changes;
+use crate::download::RUSTLANG_ROOT_URL;
+use crate::manifest::{self, Manifest};
+use crate::paths::local_path;
+use crate::state::{State, STATE_FILE};
+
+/// The ref that the upstream index is fetched into, so `FETCH_HEAD` (the commit the mirror is at) is
+/// left alone. The next sync reuses the fetched objects.
+const UPSTREAM_REF: &str = "refs/squire/upstream";
+
+/// The version of the compiler in a channel manifest, e.g. `1.52.1`.
+fn rust_version(manifest: &Manifest) -> Option<&str> {
+    manifest.pkg.get("rust")?.version.split(' ').next()
+}
+
+fn describe(manifest: &Manifest) -> String {
+    match rust_version(manifest) {
+        Some(version) => format!("{} ({})", version, manifest.date),
+        None => manifest.date.clone(),
+    }
+}
+
+/// Compares the manifest of each mirrored channel with the one upstream.
+async fn channels(client: &Client, output_directory: &str, state: &State) -> usize {
+    println!("\nChannels:");
+
+    let mut behind = 0;
+    for (name, channel) in state.channels() {
+        let local = Manifest::load(&local_path(output_directory, &manifest::path(&name))).ok();
+        let current = local.as_ref().map_or(channel.date.clone(), describe);
+
+        match Manifest::fetch(client, &name).await {
+            Ok(upstream) if upstream.date == channel.date => {
+                println!("  {:<16} {:<32} up to date", name, current);
+            }
+            Ok(upstream) => {
+                behind += 1;
+                println!(
+                    "  {:<16} {:<32} behind, {} is available",
+                    name,
+                    current,
+                    describe(&upstream)
+                );
+            }
+            Err(error) => println!("  {:<16} {:<32} unable to check: {}", name, current, error),
+        }
+    }
+
+    behind
+}
+
+/// Compares the version in `release-stable.toml` with the latest rustup release.
+async fn rustup(client: &Client, output_directory: &str) -> Result<usize> {
+    println!("\nrustup:");
+
+    let release_path = "/rustup/release-stable.toml";
+    let current = match std::fs::read_to_string(local_path(output_directory, release_path)) {
+        Ok(release) => crate::rustup_release_version(&release),
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            println!("  not mirrored");
+            return Ok(0);
+        }
+        Err(error) => return Err(error),
+    };
+
+    let upstream = client
+        .get(format!("{}{}", RUSTLANG_ROOT_URL, release_path))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(Error::other)?
+        .text()
+        .await
+        .map_err(Error::other)?;
+    let current = current.unwrap_or_else(|| "unknown".to_string());
+
+    match crate::rustup_release_version(&upstream) {
+        Some(latest) if latest == current => {
+            println!("  {:<49} up to date", current);
+            Ok(0)
+        }
+        Some(latest) => {
+            println!("  {:<49} behind, {} is available", current, latest);
+            Ok(1)
+        }
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Unable to read the rustup version from release-stable.toml.",
+        )),
+    }
+}
+
+/// Fetches the upstream index into `UPSTREAM_REF` and counts the crate versions published since the
+/// commit the mirror is at. With `fetch` false (an index maintained by another tool), only the commit
+/// upstream is looked up.
+fn fetch_index(
+    index_path: &Path,
+    url: &str,
+    proxy: Option<&str>,
+    fetch: bool,
+) -> std::result::Result<(String, Option<usize>), git2::Error> {
+    let repo = git2::Repository::open(index_path)?;
+    let current = repo
+        .refname_to_id("FETCH_HEAD")
+        .or_else(|_| repo.refname_to_id("HEAD"))?;
+
+    let mut proxy_options = git2::ProxyOptions::new();
+    match proxy {
+        Some(proxy) => proxy_options.url(proxy),
+        None => proxy_options.auto(),
+    };
+
+    let mut remote = repo.remote_anonymous(url)?;
+    if !fetch {
+        let connection = remote.connect_auth(git2::Direction::Fetch, None, Some(proxy_options))?;
+        let head = connection
+            .list()?
+            .iter()
+            .find(|head| head.name() == "HEAD")
+            .map(|head| head.oid());
+
+        return match head {
+            Some(head) if head == current => Ok((head.to_string(), Some(0))),
+            Some(head) => Ok((head.to_string(), None)),
+            None => Err(git2::Error::from_str("The remote has no HEAD")),
+        };
+    }
+
+    let mut options = git2::FetchOptions::new();
+    options.proxy_options(proxy_options).update_fetchhead(false);
+    remote.fetch(
+        &[&format!("+HEAD:{}", UPSTREAM_REF)],
+        Some(&mut options),
+        None,
+    )?;
+    let upstream = repo.refname_to_id(UPSTREAM_REF)?;
+
+    if upstream == current {
+        return Ok((upstream.to_string(), Some(0)));
+    }
+
+    let changes = Changes::between(index_path, &current.to_string(), &upstream.to_string())
+        .map_err(|err| git2::Error::from_str(&err.to_string()))?;
+    Ok((upstream.to_string(), Some(changes.added.len())))
+}
+
+/// Compares the commit of the crates.io-index in the mirror with the one upstream.
+async fn index(global: &ArgMatches, output_directory: &str) -> Result<usize> {
+    println!("\nIndex:");
+
+    if global.is_present("db-dump-index") {
+        println!("  not compared with --db-dump-index");
+        return Ok(0);
+    }
+
+    let (index_path, fetch) = match global.value_of("index-path") {
+        Some(path) => (PathBuf::from(path), false),
+        None => (local_path(output_directory, "/index"), true),
+    };
+    if !index_path.exists() {
+        println!("  not mirrored");
+        return Ok(0);
+    }
+
+    let url = global
+        .value_of("index-url")
+        .unwrap_or(crate::CRATES_INDEX_URL)
+        .to_string();
+    let proxy = crate::proxy_url(global)?.map(|proxy| Url::to_string(&proxy));
+    let current = match crate::crate_list::index_commit(&index_path) {
+        Ok(current) => current,
+        Err(_) => {
+            println!("  not fetched yet");
+            return Ok(1);
+        }
+    };
+
+    let (upstream, new_versions) = tokio::task::spawn_blocking(move || {
+        fetch_index(&index_path, &url, proxy.as_deref(), fetch)
+    })
+    .await
+    .map_err(Error::other)?
+    .map_err(|err| Error::other(err.message().to_string()))?;
+
+    let current = &current[..current.len().min(12)];
+    match new_versions {
+        Some(0) => {
+            println!("  {:<49} up to date", current);
+            Ok(0)
+        }
+        Some(new_versions) => {
+            println!(
+                "  {:<49} behind, {} new crate versions (upstream at {})",
+                current,
+                new_versions,
+                &upstream[..12]
+            );
+            Ok(1)
+        }
+        None => {
+            println!("  {:<49} behind, upstream at {}", current, &upstream[..12]);
+            Ok(1)
+        }
+    }
+}
+
+/// Reports how far a mirror is behind upstream, fetching only the channel manifests, the rustup
+/// release and the crates.io-index, but no archives or crates. Exits with status 1 if the mirror is
+/// behind, so it can be used as a monitoring probe.
+pub async fn run(client: &Client, global: &ArgMatches, matches: &ArgMatches) -> Result<()> {
+    let output_directory = matches.value_of("OUTPUT-DIRECTORY").unwrap();
+
+    if !Path::new(output_directory).join(STATE_FILE).exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} has not been synced yet.", output_directory),
+        ));
+    }
+
+    let state = State::open(output_directory)?;
+
+    println!("Mirror: {}", output_directory);
+
+    let behind = channels(client, output_directory, &state).await
+        + rustup(client, output_directory).await?
+        + index(global, output_directory).await?;
+
+    if behind > 0 {
+        println!("\nThe mirror is behind upstream.");
+        std::process::exit(1);
+    }
+
+    println!("\nThe mirror is up to date.");
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use clap::App;
+use clap_generate::generate;
+use clap_generate::generators::{Bash, Elvish, Fish, PowerShell, Zsh};
+use std::io::Result;
+
+pub const SHELLS: [&str; 5] = ["bash", "elvish", "fish", "powershell", "zsh"];
+
+/// Writes the completions of the command line for a shell to stdout, e.g. to be saved as
+/// `/usr/share/bash-completion/completions/squire`.
+pub fn run(mut app: App, shell: &str) -> Result<()> {
+    let name = app.get_name().to_string();
+    let stdout = &mut std::io::stdout();
+
+    match shell {
+        "bash" => generate::<Bash, _>(&mut app, name, stdout),
+        "elvish" => generate::<Elvish, _>(&mut app, name, stdout),
+        "fish" => generate::<Fish, _>(&mut app, name, stdout),
+        "powershell" => generate::<PowerShell, _>(&mut app, name, stdout),
+        "zsh" => generate::<Zsh, _>(&mut app, name, stdout),
+        _ => unreachable!("Checked by the possible values of SHELL"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use futures_util::{stream, StreamExt};
+use std::collections::BTreeSet;
+use std::io::Result;
+
+use crate::download::{Downloader, Overwrite};
+use crate::manifest::{self, Manifest};
+
+/// The availability of the components of each nightly, by target, as published by rustup.
+pub const COMPONENTS_HISTORY_ROOT_URL: &str =
+    "https://rust-lang.github.io/rustup-components-history";
+
+/// The components of the nightly channel under the names rustup knows them by (`clippy` rather than
+/// `clippy-preview`), from the mirrored manifest or the one upstream.
+async fn nightly_components(downloader: &Downloader) -> Result<BTreeSet<String>> {
+    let local = downloader.local_path(&manifest::path("nightly"));
+    let manifest = match Manifest::load(&local) {
+        Ok(manifest) => manifest,
+        Err(_) => Manifest::fetch(&downloader.client, "nightly").await?,
+    };
+
+    Ok(manifest
+        .pkg
+        .keys()
+        .map(|pkg| match manifest.aliases(pkg).first() {
+            Some(alias) => alias.to_string(),
+            None => pkg.clone(),
+        })
+        .collect())
+}
+
+/// Mirrors the rustup-components-history of the architectures at `/rustup-components-history`: the
+/// page and JSON matrix of each target, and the date of the last nightly that shipped each component
+/// (`/rustup-components-history/<target>/<component>`). The files change daily, so they are always
+/// downloaded again.
+pub async fn mirror(
+    downloader: &Downloader,
+    concurrency: usize,
+    architectures: &[String],
+) -> Result<()> {
+    let components = nightly_components(downloader).await?;
+
+    let mut paths = vec!["/index.html".to_string()];
+    for arch in architectures {
+        paths.push(format!("/{}.html", arch));
+        paths.push(format!("/{}.json", arch));
+        for component in &components {
+            paths.push(format!("/{}/{}", arch, component));
+        }
+    }
+
+    let total = paths.len();
+    stream::iter(paths.iter().enumerate())
+        .for_each_concurrent(concurrency, |(i, path)| async move {
+            log::info!("Downloading components history [{}/{}]", i + 1, total);
+
+            let url = format!("{}{}", COMPONENTS_HISTORY_ROOT_URL, path);
+            let local = format!("/rustup-components-history{}", path);
+
+            // Components that are not built for a target have no history for it.
+            if let Err(error) = downloader.download_url(url, &local, Overwrite::True).await {
+                log::warn!("Error downloading components history {}: {}", path, error);
+            }
+        })
+        .await;
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// What to mirror for one group of users, e.g. `ci-linux` or `embedded`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Target filter of the toolchains of its channels, in the same syntax as `--targets`.
+    pub targets: Option<String>,
+    /// Components that are not built per target (e.g. `rust-src`) to mirror for its channels, as in a
+    /// toolchain file.
+    #[serde(default)]
+    pub components: Vec<String>,
+    /// Regex of the crate names to mirror, as `--crates-filter`. All crates if missing.
+    pub crates_filter: Option<String>,
+}
+
+/// The profiles selected for a run. Each channel is mirrored for the targets and components of the
+/// profiles that list it, while the crates are shared by all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    /// The channels of all the profiles, without duplicates.
+    pub channels: Vec<String>,
+    pub profiles: Vec<Profile>,
+    /// Matches the crates of any of the profiles, `None` if one of them mirrors all crates.
+    pub crates_filter: Option<String>,
+}
+
+impl Selection {
+    /// The target filters of the profiles that apply to `channel`: those that list it, and those
+    /// without channels of their own. `None` stands for the filter of `--targets`.
+    pub fn targets<'a>(&'a self, channel: &'a str) -> Vec<Option<&'a str>> {
+        self.for_channel(channel)
+            .map(|profile| profile.targets.as_deref())
+            .collect()
+    }
+
+    /// The components of the profiles that apply to `channel`.
+    pub fn components(&self, channel: &str) -> Vec<String> {
+        self.for_channel(channel)
+            .flat_map(|profile| profile.components.iter().cloned())
+            .collect()
+    }
+
+    fn for_channel<'a>(&'a self, channel: &'a str) -> impl Iterator<Item = &'a Profile> {
+        self.profiles.iter().filter(move |profile| {
+            profile.channels.is_empty() || profile.channels.iter().any(|c| c == channel)
+        })
+    }
+}
+
+/// The configuration file given with `--config`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        toml::from_str(&std::fs::read_to_string(path)?).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid config file {}: {}", path.display(), err),
+            )
+        })
+    }
+
+    /// Selects the named profiles (or all of them, for `all`).
+    pub fn select(&self, names: &[&str]) -> Result<Selection> {
+        let profiles: Vec<(&String, &Profile)> = if names.contains(&"all") {
+            self.profiles.iter().collect()
+        } else {
+            names
+                .iter()
+                .map(|name| {
+                    self.profiles.get_key_value(*name).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Unknown profile: {}", name),
+                        )
+                    })
+                })
+                .collect::<Result<_>>()?
+        };
+
+        let mut selected = Selection::default();
+        let mut filters = Vec::new();
+        let mut all_crates = false;
+
+        for (name, profile) in profiles {
+            log::info!("Using profile {}.", name);
+
+            for channel in &profile.channels {
+                if !selected.channels.contains(channel) {
+                    selected.channels.push(channel.clone());
+                }
+            }
+
+            selected.profiles.push(profile.clone());
+
+            match &profile.crates_filter {
+                Some(filter) => filters.push(format!("(?:{})", filter)),
+                None => all_crates = true,
+            }
+        }
+
+        if !all_crates && !filters.is_empty() {
+            selected.crates_filter = Some(filters.join("|"));
+        }
+
+        Ok(selected)
+    }
+}
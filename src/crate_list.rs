@@ -0,0 +1,358 @@
+use crates_index::{BareIndexRepo, Crate, Version};
+use regex::Regex;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const CACHE_FILE: &str = ".squire/crates.list";
+
+/// A crate version to mirror: (name, version, checksum).
+pub type Entry = (String, String, [u8; 32]);
+
+/// The crate versions of the index that are mirrored. Everything is mirrored by default.
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Regex of the crate names, from `--crates-filter`.
+    pub filter: Option<Regex>,
+    /// Crate names from the database dump, for `--crates-category` and `--crates-keyword`.
+    pub names: Option<HashSet<String>>,
+    /// (name, version) of the versions added or changed since the commit of `--index-since`.
+    pub versions: Option<HashSet<(String, String)>>,
+    /// Whether the dependencies of the selected versions are mirrored too, from `--with-dependencies`.
+    pub with_dependencies: bool,
+    /// (name, version) of the versions locked by the `Cargo.lock` files of `--lockfile-dir`, or
+    /// resolved by `--with-dependencies`. These are mirrored even if [`from_index`] leaves them out, as
+    /// cargo downloads them to build the projects (or the selected versions).
+    pub explicit: Option<HashSet<(String, String)>>,
+}
+
+impl Selection {
+    pub fn contains(&self, name: &str, version: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(name))
+            && self.names.as_ref().is_none_or(|names| names.contains(name))
+            && self
+                .versions
+                .as_ref()
+                .is_none_or(|versions| versions.contains(&(name.to_string(), version.to_string())))
+            && self
+                .explicit
+                .as_ref()
+                .is_none_or(|explicit| explicit.contains(&(name.to_string(), version.to_string())))
+    }
+}
+
+/// Yields the versions of an owned crate one at a time, so only a single crate is held in memory while
+/// iterating over the index.
+struct Versions<T> {
+    krate: Crate,
+    position: usize,
+    map: fn(&Version) -> Option<T>,
+}
+
+impl<T> Versions<T> {
+    fn new(krate: Crate, map: fn(&Version) -> Option<T>) -> Self {
+        Self {
+            krate,
+            position: 0,
+            map,
+        }
+    }
+}
+
+impl<T> Iterator for Versions<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        while let Some(version) = self.krate.versions().get(self.position) {
+            self.position += 1;
+            if let Some(item) = (self.map)(version) {
+                return Some(item);
+            }
+        }
+
+        None
+    }
+}
+
+/// The crate versions to mirror from the index: all versions that are not yanked, of crates with at
+/// least two published versions. The index is read lazily, one crate at a time.
+pub fn from_index<'a>(index: &'a BareIndexRepo<'_>) -> impl Iterator<Item = Entry> + 'a {
+    index
+        .crates()
+        .filter(|c| c.versions().len() >= 2)
+        .flat_map(|c| {
+            Versions::new(c, |v| {
+                (!v.is_yanked())
+                    .then(|| (v.name().to_string(), v.version().to_string(), *v.checksum()))
+            })
+        })
+}
+
+/// The `versions` that are in the index, sorted by name, whether they are yanked or not and however
+/// many versions their crate has.
+pub fn explicit_versions(
+    index: &BareIndexRepo<'_>,
+    versions: &HashSet<(String, String)>,
+) -> Vec<Entry> {
+    let names: BTreeSet<&str> = versions.iter().map(|(name, _)| name.as_str()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| index.crate_(name))
+        .flat_map(|c| {
+            c.versions()
+                .iter()
+                .filter(|v| versions.contains(&(v.name().to_string(), v.version().to_string())))
+                .map(|v| (v.name().to_string(), v.version().to_string(), *v.checksum()))
+                .collect::<Vec<Entry>>()
+        })
+        .collect()
+}
+
+/// The yanked versions in the index: (name, version).
+pub fn yanked<'a>(index: &'a BareIndexRepo<'_>) -> impl Iterator<Item = (String, String)> + 'a {
+    index.crates().flat_map(|c| {
+        Versions::new(c, |v| {
+            v.is_yanked()
+                .then(|| (v.name().to_string(), v.version().to_string()))
+        })
+    })
+}
+
+/// The commit of the local index clone that `BareIndexRepo` reads from.
+pub fn index_commit(index_path: &Path) -> Result<String> {
+    let repo = git2::Repository::open(index_path).map_err(std::io::Error::other)?;
+    let head = repo
+        .refname_to_id("FETCH_HEAD")
+        .or_else(|_| repo.refname_to_id("HEAD"))
+        .map_err(std::io::Error::other)?;
+
+    Ok(head.to_string())
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let mut parts = line.split(' ');
+    let name = parts.next()?;
+    let version = parts.next()?;
+    let mut checksum = [0; 32];
+    hex::decode_to_slice(parts.next()?, &mut checksum).ok()?;
+
+    Some((name.to_string(), version.to_string(), checksum))
+}
+
+/// The flattened list of crate versions from the last run, stored as one `name version checksum` line
+/// per version, preceded by the index commit it was built from.
+pub struct Cache {
+    path: PathBuf,
+}
+
+impl Cache {
+    pub fn new(output_directory: &str) -> Self {
+        Self {
+            path: Path::new(output_directory).join(CACHE_FILE),
+        }
+    }
+
+    /// Reads the cached list if it was built from `commit`.
+    pub fn load(&self, commit: &str) -> Result<Option<impl Iterator<Item = Entry>>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        match lines.next() {
+            Some(Ok(header)) if header == commit => {}
+            _ => return Ok(None),
+        }
+
+        Ok(Some(lines.map_while(|line| match line {
+            Ok(line) => parse_line(&line),
+            Err(error) => {
+                log::warn!("Unable to read crate list cache: {}", error);
+                None
+            }
+        })))
+    }
+
+    /// Starts writing a new list for `commit`. The previous list is only replaced once
+    /// [`CacheWriter::finish`] is called.
+    pub fn writer(&self, commit: &str) -> Result<CacheWriter> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.path.with_extension("list.tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        writeln!(file, "{}", commit)?;
+
+        Ok(CacheWriter {
+            path: self.path.clone(),
+            tmp,
+            file: Mutex::new(Some(file)),
+        })
+    }
+}
+
+pub struct CacheWriter {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: Mutex<Option<BufWriter<File>>>,
+}
+
+impl CacheWriter {
+    /// Appends an entry. Write errors abandon the cache rather than failing the sync.
+    pub fn push(&self, (name, version, checksum): &Entry) {
+        let mut file = self.file.lock().unwrap();
+        if let Some(writer) = file.as_mut() {
+            if let Err(error) = writeln!(writer, "{} {} {}", name, version, hex::encode(checksum)) {
+                log::warn!("Unable to write crate list cache: {}", error);
+                *file = None;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.file.into_inner().unwrap() {
+            Some(mut file) => {
+                file.flush()?;
+                drop(file);
+                std::fs::rename(&self.tmp, &self.path)
+            }
+            None => {
+                let _ = std::fs::remove_file(&self.tmp);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crates_index::BareIndex;
+
+    /// A version of a test index: (name, version, yanked, dependencies), the dependencies as
+    /// (name, requirement).
+    pub(crate) type IndexVersion<'a> = (&'a str, &'a str, bool, &'a [(&'a str, &'a str)]);
+
+    /// Creates an index with `versions` at a temporary path.
+    pub(crate) fn index(name: &str, versions: &[IndexVersion<'_>]) -> BareIndex {
+        let path =
+            std::env::temp_dir().join(format!("squire-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let repo = git2::Repository::init(&path).unwrap();
+
+        let mut files: std::collections::BTreeMap<PathBuf, String> = Default::default();
+        for (name, version, yanked, dependencies) in versions {
+            let file = match name.len() {
+                1 => PathBuf::from("1").join(name),
+                2 => PathBuf::from("2").join(name),
+                3 => PathBuf::from("3").join(&name[..1]).join(name),
+                _ => PathBuf::from(&name[..2]).join(&name[2..4]).join(name),
+            };
+            let deps: Vec<serde_json::Value> = dependencies
+                .iter()
+                .map(|(name, req)| {
+                    serde_json::json!({
+                        "name": name, "req": req, "features": [], "optional": false,
+                        "default_features": true, "target": null, "kind": "normal"
+                    })
+                })
+                .collect();
+            let line = serde_json::json!({
+                "name": name, "vers": version, "deps": deps, "features": {},
+                "cksum": hex::encode([version.len() as u8; 32]), "yanked": yanked
+            });
+            let contents = files.entry(file).or_default();
+            contents.push_str(&line.to_string());
+            contents.push('\n');
+        }
+        for (file, contents) in &files {
+            std::fs::create_dir_all(path.join(file).parent().unwrap()).unwrap();
+            std::fs::write(path.join(file), contents).unwrap();
+        }
+
+        let mut git_index = repo.index().unwrap();
+        git_index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(git_index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@localhost").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "index", &tree, &[])
+            .unwrap();
+
+        BareIndex::with_path(path, "https://github.com/rust-lang/crates.io-index")
+    }
+
+    fn versions(entries: &[Entry]) -> Vec<(&str, &str)> {
+        entries
+            .iter()
+            .map(|(name, version, _)| (name.as_str(), version.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn locked_versions() {
+        let index = index(
+            "locked",
+            &[
+                ("app", "1.0.0", false, &[]),
+                ("app", "1.1.0", true, &[]),
+                ("single", "0.1.0", false, &[]),
+            ],
+        );
+        let index = index.open_or_clone().unwrap();
+
+        assert_eq!(
+            versions(&from_index(&index).collect::<Vec<_>>()),
+            [("app", "1.0.0")]
+        );
+
+        let locked: HashSet<(String, String)> = [
+            ("app", "1.1.0"),
+            ("single", "0.1.0"),
+            ("single", "0.2.0"),
+            ("missing", "1.0.0"),
+        ]
+        .iter()
+        .map(|(name, version)| (name.to_string(), version.to_string()))
+        .collect();
+        assert_eq!(
+            versions(&explicit_versions(&index, &locked)),
+            [("app", "1.1.0"), ("single", "0.1.0")]
+        );
+    }
+
+    #[test]
+    fn single_version_dependency() {
+        let index = index(
+            "single-version",
+            &[
+                ("app", "1.0.0", false, &[("single", "^0.1")]),
+                ("app", "1.1.0", false, &[("single", "^0.1")]),
+                ("single", "0.1.0", false, &[]),
+            ],
+        );
+        let index = index.open_or_clone().unwrap();
+
+        let baseline: Vec<Entry> = from_index(&index).collect();
+        assert_eq!(versions(&baseline), [("app", "1.0.0"), ("app", "1.1.0")]);
+
+        let selection = Selection {
+            versions: Some([("app".to_string(), "1.1.0".to_string())].into()),
+            with_dependencies: true,
+            ..Default::default()
+        };
+        let resolved = crate::dependencies::resolve(&index, &selection);
+        assert_eq!(
+            versions(&explicit_versions(&index, &resolved)),
+            [("app", "1.1.0"), ("single", "0.1.0")]
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+use crate::crate_list::Entry;
+use crate::download::{Downloader, CRATES_ROOT_URL};
+
+/// The crates.io database dump, which is regenerated every day.
+pub const DB_DUMP_PATH: &str = "/db-dump.tar.gz";
+
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Deserialize)]
+struct Crate {
+    id: u64,
+    name: String,
+    /// Only in dumps from before the counts were moved to `crate_downloads.csv`.
+    #[serde(default)]
+    downloads: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateDownloads {
+    crate_id: u64,
+    downloads: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateVersion {
+    crate_id: u64,
+    num: String,
+    checksum: String,
+    /// `t` or `f`, as exported by PostgreSQL.
+    yanked: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Category {
+    id: u64,
+    slug: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Keyword {
+    id: u64,
+    keyword: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateCategory {
+    crate_id: u64,
+    category_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateKeyword {
+    crate_id: u64,
+    keyword_id: u64,
+}
+
+/// Where the database dump is kept, next to the state. It is only read by squire, so unlike the
+/// files of the mirror it is not replicated to `--mirror-to`, stored in the content-addressed store or
+/// counted towards `--max-total-bytes`.
+const DB_DUMP_FILE: &str = ".squire/db-dump.tar.gz";
+
+/// Downloads the database dump to `path`, through a temporary file.
+async fn fetch(downloader: &Downloader, path: &Path) -> Result<()> {
+    let url = format!("{}{}", CRATES_ROOT_URL, DB_DUMP_PATH);
+    let _permit = downloader.requests(&url).acquire().await.unwrap();
+
+    let mut res = tokio::time::timeout(downloader.timeout, downloader.client.get(&url).send())
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out"))?
+        .and_then(|res| res.error_for_status())
+        .map_err(Error::other)?;
+
+    let part_path = path.with_extension("gz.part");
+    let mut file = tokio::fs::File::create(&part_path).await?;
+    while let Some(chunk) = tokio::time::timeout(downloader.timeout, res.chunk())
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out"))?
+        .map_err(Error::other)?
+    {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+
+    tokio::fs::rename(&part_path, path).await
+}
+
+/// Downloads the database dump, unless the existing one is less than a day old.
+pub async fn download(downloader: &Downloader) -> Result<PathBuf> {
+    let path = Path::new(&downloader.output_directory).join(DB_DUMP_FILE);
+    if let Some(parent) = path.parent() {
+        downloader.permissions.create_dir_all(parent)?;
+    }
+
+    // Earlier versions kept it in the mirror.
+    let mirrored = downloader.local_path(DB_DUMP_PATH);
+    if mirrored.exists() && !path.exists() {
+        std::fs::rename(&mirrored, &path)?;
+    }
+
+    let is_recent = std::fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age < MAX_AGE);
+
+    if !is_recent {
+        log::info!("Downloading crates.io database dump...");
+
+        let mut attempt = 0;
+        let result = loop {
+            match fetch(downloader, &path).await {
+                Err(error) if attempt < downloader.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Error downloading the database dump, retrying ({}/{}): {}",
+                        attempt,
+                        downloader.retries,
+                        error
+                    );
+                }
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(error) if path.exists() => {
+                log::warn!(
+                    "Unable to update the database dump, using the old one: {}",
+                    error
+                )
+            }
+            Err(error) => {
+                return Err(Error::other(format!(
+                    "Unable to download the crates.io database dump: {}",
+                    error
+                )))
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+fn read_csv<T: for<'de> Deserialize<'de>>(reader: impl Read) -> Result<Vec<T>> {
+    csv::Reader::from_reader(reader)
+        .deserialize()
+        .collect::<std::result::Result<Vec<T>, csv::Error>>()
+        .map_err(std::io::Error::other)
+}
+
+/// Names of the crates in any of the categories (including their subcategories, e.g. `no-std` also
+/// matches `no-std::no-alloc`) or with any of the keywords, according to the database dump.
+pub fn crates_matching(
+    path: &Path,
+    categories: &[String],
+    keywords: &[String],
+) -> Result<HashSet<String>> {
+    let mut crates = Vec::new();
+    let mut all_categories = Vec::new();
+    let mut all_keywords = Vec::new();
+    let mut crates_categories = Vec::new();
+    let mut crates_keywords = Vec::new();
+
+    // The tables are in `<date>/data/<table>.csv`.
+    let mut archive = tar::Archive::new(GzDecoder::new(std::fs::File::open(path)?));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let table = entry
+            .path()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+
+        match table.as_deref() {
+            Some("crates.csv") => crates = read_csv::<Crate>(entry)?,
+            Some("categories.csv") => all_categories = read_csv::<Category>(entry)?,
+            Some("keywords.csv") => all_keywords = read_csv::<Keyword>(entry)?,
+            Some("crates_categories.csv") => crates_categories = read_csv::<CrateCategory>(entry)?,
+            Some("crates_keywords.csv") => crates_keywords = read_csv::<CrateKeyword>(entry)?,
+            _ => {}
+        }
+    }
+
+    let category_ids: HashSet<u64> = all_categories
+        .into_iter()
+        .filter(|category| {
+            categories.iter().any(|slug| {
+                category.slug == *slug || category.slug.starts_with(&format!("{}::", slug))
+            })
+        })
+        .map(|category| category.id)
+        .collect();
+
+    let keyword_ids: HashSet<u64> = all_keywords
+        .into_iter()
+        .filter(|keyword| keywords.contains(&keyword.keyword))
+        .map(|keyword| keyword.id)
+        .collect();
+
+    let crate_ids: HashSet<u64> = crates_categories
+        .into_iter()
+        .filter(|c| category_ids.contains(&c.category_id))
+        .map(|c| c.crate_id)
+        .chain(
+            crates_keywords
+                .into_iter()
+                .filter(|c| keyword_ids.contains(&c.keyword_id))
+                .map(|c| c.crate_id),
+        )
+        .collect();
+
+    let names: HashMap<u64, String> = crates.into_iter().map(|c| (c.id, c.name)).collect();
+
+    Ok(crate_ids
+        .into_iter()
+        .filter_map(|id| names.get(&id).cloned())
+        .collect())
+}
+
+/// The number of times each crate has been downloaded from crates.io, according to the database dump.
+/// The counts are in `crate_downloads.csv`, or the `downloads` column of `crates.csv` in older dumps.
+pub fn download_counts(path: &Path) -> Result<HashMap<String, u64>> {
+    let mut crates = None;
+    let mut crate_downloads = None;
+
+    let mut archive = tar::Archive::new(GzDecoder::new(std::fs::File::open(path)?));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let table = entry
+            .path()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+
+        match table.as_deref() {
+            Some("crates.csv") => crates = Some(read_csv::<Crate>(entry)?),
+            Some("crate_downloads.csv") => {
+                crate_downloads = Some(read_csv::<CrateDownloads>(entry)?)
+            }
+            _ => {}
+        }
+    }
+
+    let crates = crates.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "The database dump has no crates table.",
+        )
+    })?;
+
+    let counts: HashMap<u64, u64> = match crate_downloads {
+        Some(crate_downloads) => crate_downloads
+            .into_iter()
+            .map(|row| (row.crate_id, row.downloads))
+            .collect(),
+        None => crates
+            .iter()
+            .map(|krate| Some((krate.id, krate.downloads?)))
+            .collect::<Option<_>>()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "The database dump has no download counts.",
+                )
+            })?,
+    };
+
+    // Crates published after the counts were exported have none yet.
+    Ok(crates
+        .into_iter()
+        .map(|krate| {
+            let downloads = counts.get(&krate.id).copied().unwrap_or(0);
+            (krate.name, downloads)
+        })
+        .collect())
+}
+
+/// The crate versions to mirror according to the database dump, like [`crate::crate_list::from_index`]
+/// does with the index: all versions that are not yanked, of crates with at least two published
+/// versions, and the `explicit` versions (see [`crate::crate_list::Selection::explicit`]) whether they
+/// are or not. Ordered by crate name, then by publication.
+pub fn crate_versions(
+    path: &Path,
+    explicit: Option<&HashSet<(String, String)>>,
+) -> Result<Vec<Entry>> {
+    let mut crates = Vec::new();
+    let mut versions = Vec::new();
+
+    let mut archive = tar::Archive::new(GzDecoder::new(std::fs::File::open(path)?));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let table = entry
+            .path()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+
+        match table.as_deref() {
+            Some("crates.csv") => crates = read_csv::<Crate>(entry)?,
+            Some("versions.csv") => versions = read_csv::<CrateVersion>(entry)?,
+            _ => {}
+        }
+    }
+
+    let names: HashMap<u64, String> = crates.into_iter().map(|c| (c.id, c.name)).collect();
+    let mut published: HashMap<u64, usize> = HashMap::new();
+    for version in &versions {
+        *published.entry(version.crate_id).or_default() += 1;
+    }
+
+    let mut entries = Vec::new();
+    for version in versions {
+        let name = match names.get(&version.crate_id) {
+            Some(name)
+                if (published[&version.crate_id] >= 2 && version.yanked != "t")
+                    || explicit.is_some_and(|explicit| {
+                        explicit.contains(&(name.clone(), version.num.clone()))
+                    }) =>
+            {
+                name
+            }
+            _ => continue,
+        };
+
+        let mut checksum = [0; 32];
+        match hex::decode_to_slice(&version.checksum, &mut checksum) {
+            Ok(()) => entries.push((name.clone(), version.num, checksum)),
+            Err(_) => log::warn!(
+                "No valid checksum for {}-{} in the database dump, skipping it.",
+                name,
+                version.num
+            ),
+        }
+    }
+
+    // The versions table is ordered by id, so the sort keeps the versions of a crate in order.
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a database dump with the `tables` (file name, contents) to a temporary path.
+    fn dump(name: &str, tables: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "squire-test-{}-{}.tar.gz",
+            name,
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).unwrap();
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::fast(),
+        ));
+        for (table, contents) in tables {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(
+                    &mut header,
+                    format!("2021-06-01-020000/data/{}", table),
+                    contents.as_bytes(),
+                )
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+
+        path
+    }
+
+    #[test]
+    fn download_counts_from_crate_downloads() {
+        let path = dump(
+            "crate-downloads",
+            &[
+                ("crates.csv", "id,name\n1,serde\n2,tokio\n3,new\n"),
+                ("crate_downloads.csv", "crate_id,downloads\n1,300\n2,200\n"),
+            ],
+        );
+        let counts = download_counts(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(counts["serde"], 300);
+        assert_eq!(counts["tokio"], 200);
+        assert_eq!(counts["new"], 0);
+    }
+
+    #[test]
+    fn download_counts_from_crates() {
+        let path = dump(
+            "crates-downloads",
+            &[(
+                "crates.csv",
+                "id,name,downloads\n1,serde,300\n2,tokio,200\n",
+            )],
+        );
+        let counts = download_counts(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(counts["serde"], 300);
+        assert_eq!(counts["tokio"], 200);
+    }
+
+    #[test]
+    fn no_download_counts() {
+        let path = dump("no-downloads", &[("crates.csv", "id,name\n1,serde\n")]);
+        let error = download_counts(&path).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            error.to_string(),
+            "The database dump has no download counts."
+        );
+    }
+}
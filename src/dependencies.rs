@@ -0,0 +1,109 @@
+use crates_index::{BareIndexRepo, Crate, DependencyKind};
+use semver::{Version, VersionReq};
+use std::collections::{HashMap, HashSet};
+
+use crate::crate_list::Selection;
+
+/// The newest version of a crate that is not yanked and matches the requirement, which is what cargo
+/// picks when resolving without a lockfile.
+fn newest_matching(krate: &Crate, requirement: &VersionReq) -> Option<String> {
+    krate
+        .versions()
+        .iter()
+        .filter(|version| !version.is_yanked())
+        .filter_map(|version| Some((Version::parse(version.version()).ok()?, version)))
+        .filter(|(parsed, _)| requirement.matches(parsed))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, version)| version.version().to_string())
+}
+
+/// The selected crate versions and, transitively, the newest matching version of each of their normal
+/// and build dependencies (including optional ones, which may be enabled by features). Dev-dependencies
+/// are not needed to build a crate, so they are skipped.
+pub fn resolve(index: &BareIndexRepo<'_>, selection: &Selection) -> HashSet<(String, String)> {
+    let mut crates: HashMap<String, Option<Crate>> = HashMap::new();
+    let mut queue = Vec::new();
+
+    for krate in index.crates() {
+        let selected: Vec<String> = krate
+            .versions()
+            .iter()
+            .filter(|version| selection.contains(version.name(), version.version()))
+            .map(|version| version.version().to_string())
+            .collect();
+
+        if !selected.is_empty() {
+            let name = krate.name().to_string();
+            queue.extend(selected.into_iter().map(|version| (name.clone(), version)));
+            crates.insert(name, Some(krate));
+        }
+    }
+
+    log::info!(
+        "Resolving the dependencies of {} selected crate versions...",
+        queue.len()
+    );
+
+    let mut resolved = HashSet::new();
+    while let Some((name, version)) = queue.pop() {
+        if !resolved.insert((name.clone(), version.clone())) {
+            continue;
+        }
+
+        let dependencies: Vec<(String, String)> = match crates.get(&name) {
+            Some(Some(krate)) => krate
+                .versions()
+                .iter()
+                .find(|v| v.version() == version)
+                .map(|v| {
+                    v.dependencies()
+                        .iter()
+                        .filter(|dependency| dependency.kind() != DependencyKind::Dev)
+                        .map(|dependency| {
+                            (
+                                dependency.crate_name().to_string(),
+                                dependency.requirement().to_string(),
+                            )
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => continue,
+        };
+
+        for (dependency, requirement) in dependencies {
+            let krate = crates
+                .entry(dependency.clone())
+                .or_insert_with(|| index.crate_(&dependency));
+
+            let requirement = match VersionReq::parse(&requirement) {
+                Ok(requirement) => requirement,
+                Err(_) => {
+                    log::warn!(
+                        "Unable to parse requirement {} of {} on {}, skipping it.",
+                        requirement,
+                        name,
+                        dependency
+                    );
+                    continue;
+                }
+            };
+
+            match krate
+                .as_ref()
+                .and_then(|krate| newest_matching(krate, &requirement))
+            {
+                Some(version) => queue.push((dependency, version)),
+                None => log::warn!(
+                    "No version of {} matches {} (required by {}-{}).",
+                    dependency,
+                    requirement,
+                    name,
+                    version
+                ),
+            }
+        }
+    }
+
+    resolved
+}
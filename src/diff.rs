@@ -0,0 +1,107 @@
+use clap::ArgMatches;
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::io::Result;
+
+use crate::download::{num_cpus, sha256_file};
+use crate::scan::{self, MIRROR_DIRECTORIES};
+
+#[derive(Debug, Default, Serialize)]
+struct Diff {
+    /// Files that only exist in the first mirror.
+    only_in_a: Vec<String>,
+    /// Files that only exist in the second mirror.
+    only_in_b: Vec<String>,
+    /// Files that exist in both mirrors, but differ in size or (with `--checksums`) content.
+    differing: Vec<String>,
+}
+
+impl Diff {
+    fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// Compares the files of two mirrors (e.g. staging and the air-gapped copy) by size, and optionally
+/// by SHA-256 digest. Exits with status 1 if they differ.
+pub async fn run(matches: &ArgMatches) -> Result<()> {
+    let a = matches.value_of("MIRROR-A").unwrap();
+    let b = matches.value_of("MIRROR-B").unwrap();
+
+    let files_a = scan::scan(a, &MIRROR_DIRECTORIES).await?;
+    let files_b = scan::scan(b, &MIRROR_DIRECTORIES).await?;
+
+    let paths: BTreeSet<&String> = files_a.keys().chain(files_b.keys()).collect();
+    let mut diff = Diff::default();
+    let mut same_size = Vec::new();
+
+    for path in paths {
+        match (files_a.get(path), files_b.get(path)) {
+            (Some(_), None) => diff.only_in_a.push(path.clone()),
+            (None, Some(_)) => diff.only_in_b.push(path.clone()),
+            (Some(size_a), Some(size_b)) if size_a != size_b => diff.differing.push(path.clone()),
+            _ => same_size.push(path.clone()),
+        }
+    }
+
+    if matches.is_present("checksums") {
+        let mut differing: Vec<String> = stream::iter(same_size)
+            .map(|path| {
+                let file_a = crate::paths::local_path(a, &path);
+                let file_b = crate::paths::local_path(b, &path);
+                tokio::task::spawn_blocking(move || {
+                    let differs = sha256_file(&file_a)? != sha256_file(&file_b)?;
+                    Ok::<_, std::io::Error>(differs.then_some(path))
+                })
+            })
+            .buffer_unordered(num_cpus())
+            .filter_map(|result| async move {
+                match result {
+                    Ok(Ok(path)) => path,
+                    Ok(Err(error)) => {
+                        log::warn!("Unable to compare file: {}", error);
+                        None
+                    }
+                    Err(error) => {
+                        log::warn!("Unable to compare file: {}", error);
+                        None
+                    }
+                }
+            })
+            .collect()
+            .await;
+
+        diff.differing.append(&mut differing);
+        diff.differing.sort();
+    }
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        for path in &diff.only_in_a {
+            println!("- {}", path);
+        }
+        for path in &diff.only_in_b {
+            println!("+ {}", path);
+        }
+        for path in &diff.differing {
+            println!("~ {}", path);
+        }
+
+        println!(
+            "{} only in {}, {} only in {}, {} differing.",
+            diff.only_in_a.len(),
+            a,
+            diff.only_in_b.len(),
+            b,
+            diff.differing.len()
+        );
+    }
+
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use clap::ArgMatches;
+use reqwest::header::DATE;
+use reqwest::Client;
+use std::io::Result;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::download::{CRATES_ROOT_URL, RUSTLANG_ROOT_URL};
+use crate::format_size;
+
+/// Less free space than this is reported, since a full mirror takes several hundred GB.
+const LOW_DISK_SPACE: u64 = 50 * 1024 * 1024 * 1024;
+
+/// A larger difference from the clock of static.rust-lang.org is reported.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Files that are requested to check the connectivity to each upstream.
+const UPSTREAMS: [(&str, &str); 2] = [
+    (RUSTLANG_ROOT_URL, "/dist/channel-rust-stable.toml.sha256"),
+    (CRATES_ROOT_URL, "/db-dump.tar.gz"),
+];
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: String) {
+        println!("  ok    {}", message);
+    }
+
+    fn warn(&mut self, message: String, hint: &str) {
+        self.warnings += 1;
+        print("warn", &message, hint);
+    }
+
+    fn fail(&mut self, message: String, hint: &str) {
+        self.failures += 1;
+        print("FAIL", &message, hint);
+    }
+}
+
+fn print(level: &str, message: &str, hint: &str) {
+    println!("  {:<5} {}", level, message);
+    if !hint.is_empty() {
+        println!("        {}", hint);
+    }
+}
+
+/// Requests a file with HEAD, returning the status and the Date header of the response.
+async fn probe(client: &Client, url: &str) -> std::result::Result<(u16, Option<String>), String> {
+    let res = client
+        .head(url)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    let date = res
+        .headers()
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .map(str::to_string);
+
+    Ok((res.status().as_u16(), date))
+}
+
+async fn upstreams(
+    report: &mut Report,
+    client: std::result::Result<&Client, reqwest::Error>,
+    proxy: Option<&Url>,
+    timeout: Duration,
+) {
+    println!("Upstreams:");
+
+    let client = match client {
+        Ok(client) => client,
+        Err(error) => {
+            report.fail(
+                format!("Unable to create the HTTP client: {}", error),
+                "Check --pin-certificates, --proxy and the CA certificates of the system.",
+            );
+            return;
+        }
+    };
+
+    // Compare with a direct connection, to tell a broken proxy from a blocked host.
+    let direct = Client::builder()
+        .no_proxy()
+        .connect_timeout(timeout)
+        .build();
+
+    for (root, path) in UPSTREAMS {
+        let url = format!("{}{}", root, path);
+        let started = Instant::now();
+
+        match probe(client, &url).await {
+            Ok((status, date)) if status < 400 => {
+                report.ok(format!(
+                    "{} ({}, {} ms{})",
+                    root,
+                    status,
+                    started.elapsed().as_millis(),
+                    if proxy.is_some() { ", via proxy" } else { "" }
+                ));
+                if root == RUSTLANG_ROOT_URL {
+                    clock(report, date.as_deref());
+                }
+            }
+            Ok((status, _)) => report.fail(
+                format!("{} responded with {}", url, status),
+                "A proxy or firewall may be blocking the request, or the upstream is having problems.",
+            ),
+            Err(error) => report.fail(
+                format!("{} is not reachable: {}", root, error),
+                match proxy {
+                    Some(_) => "Check --proxy and --proxy-user, and that the proxy allows this host.",
+                    None => "Check DNS and the firewall, or use --proxy if outbound traffic goes through a proxy.",
+                },
+            ),
+        }
+
+        if let Some(proxy) = proxy {
+            let direct = match &direct {
+                Ok(direct) => direct,
+                Err(error) => {
+                    report.warn(
+                        format!(
+                            "Unable to create an HTTP client without the proxy: {}",
+                            error
+                        ),
+                        "Only matters if the proxy is not always used.",
+                    );
+                    continue;
+                }
+            };
+            match probe(direct, &url).await {
+                Ok((status, _)) if status < 400 => report.ok(format!("{} (direct)", root)),
+                Ok((status, _)) => report.warn(
+                    format!("{} responded with {} without the proxy", url, status),
+                    "Only matters if the proxy is not always used.",
+                ),
+                Err(_) => report.warn(
+                    format!(
+                        "{} is only reachable via {}",
+                        root,
+                        proxy.host_str().unwrap_or_default()
+                    ),
+                    "Only matters if the proxy is not always used.",
+                ),
+            }
+        }
+    }
+}
+
+/// Compares the local clock with the Date header of an upstream response.
+fn clock(report: &mut Report, date: Option<&str>) {
+    let upstream = match date.and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok()) {
+        Some(upstream) => upstream,
+        None => return,
+    };
+
+    let skew = (chrono::Utc::now() - upstream.with_timezone(&chrono::Utc))
+        .num_seconds()
+        .unsigned_abs();
+
+    if skew > MAX_CLOCK_SKEW.as_secs() {
+        report.warn(
+            format!("The clock is {} s off from static.rust-lang.org", skew),
+            "Synchronize the clock (e.g. with NTP): certificate checks and the age of the database dump depend on it.",
+        );
+    } else {
+        report.ok(format!("Clock within {} s of static.rust-lang.org", skew));
+    }
+}
+
+/// Checks that the crates.io-index can be fetched with git, or that the clone of `--index-path` can be
+/// read.
+async fn index(report: &mut Report, matches: &ArgMatches, proxy: Option<&Url>) {
+    println!("\nIndex:");
+
+    if matches.is_present("db-dump-index") {
+        report.ok("Not needed with --db-dump-index".to_string());
+        return;
+    }
+
+    if let Some(path) = matches.value_of("index-path") {
+        match git2::Repository::open(path) {
+            Ok(_) => report.ok(format!("{} is a git repository", path)),
+            Err(error) => report.fail(
+                format!("{} cannot be opened: {}", path, error.message()),
+                "--index-path must point at a clone of the crates.io-index.",
+            ),
+        }
+        return;
+    }
+
+    let url = matches
+        .value_of("index-url")
+        .unwrap_or(crate::CRATES_INDEX_URL)
+        .to_string();
+    let proxy = proxy.map(Url::to_string);
+
+    let result = tokio::task::spawn_blocking({
+        let url = url.clone();
+        move || -> std::result::Result<usize, git2::Error> {
+            let mut options = git2::ProxyOptions::new();
+            match &proxy {
+                Some(proxy) => options.url(proxy),
+                None => options.auto(),
+            };
+
+            let mut remote = git2::Remote::create_detached(&url)?;
+            let connection = remote.connect_auth(git2::Direction::Fetch, None, Some(options))?;
+            Ok(connection.list()?.len())
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(refs)) => report.ok(format!("{} ({} refs)", url, refs)),
+        Ok(Err(error)) => report.fail(
+            format!("{} cannot be fetched with git: {}", url, error.message()),
+            "Use --index-url for an internal mirror of the index, --index-path for an existing clone, or --db-dump-index where git is blocked.",
+        ),
+        Err(error) => report.fail(format!("Unable to check {}: {}", url, error), ""),
+    }
+}
+
+/// Checks the free space and that files can be created in the output directory (or the directory it
+/// will be created in).
+fn output_directory(report: &mut Report, output_directory: &str) {
+    println!("\nOutput directory:");
+
+    let path = Path::new(output_directory);
+    // A relative path that does not exist yet ends in the current directory.
+    let existing = match path
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.is_dir())
+    {
+        Some(existing) => existing,
+        None => {
+            report.fail(
+                format!("No part of {} exists", output_directory),
+                "Create the output directory first.",
+            );
+            return;
+        }
+    };
+
+    match fs2::available_space(existing) {
+        Ok(available) if available < LOW_DISK_SPACE => report.warn(
+            format!("{} free on {}", format_size(available), existing.display()),
+            "A full mirror takes several hundred GB, use `squire estimate` for the selected channels and targets.",
+        ),
+        Ok(available) => report.ok(format!("{} free on {}", format_size(available), existing.display())),
+        Err(error) => report.warn(format!("Unable to determine the free space: {}", error), ""),
+    }
+
+    let probe = existing.join(".squire-doctor");
+    match std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe)) {
+        Ok(()) => report.ok(format!("{} is writable", existing.display())),
+        Err(error) => report.fail(
+            format!("{} is not writable: {}", existing.display(), error),
+            "Run squire as a user that owns the mirror, or fix the permissions of the directory.",
+        ),
+    }
+}
+
+/// Checks the environment for a sync: connectivity to the upstreams, fetching the index, the output
+/// directory and the clock. Exits with status 1 if any check fails, including when the HTTP client
+/// cannot be created from the options.
+pub async fn run(
+    client: std::result::Result<&Client, reqwest::Error>,
+    global: &ArgMatches,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let proxy = crate::proxy_url(global)?.or_else(|| {
+        ["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"]
+            .iter()
+            .find_map(|name| std::env::var(name).ok())
+            .and_then(|proxy| Url::parse(&proxy).ok())
+    });
+    let timeout = crate::timeout_arg(global)?;
+
+    let mut report = Report::default();
+    upstreams(&mut report, client, proxy.as_ref(), timeout).await;
+    index(&mut report, global, proxy.as_ref()).await;
+    if let Some(directory) = matches.value_of("OUTPUT-DIRECTORY") {
+        output_directory(&mut report, directory);
+    }
+
+    println!(
+        "\n{} failed, {} warnings.",
+        report.failures, report.warnings
+    );
+    if report.failures > 0 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
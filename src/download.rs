@@ -0,0 +1,1799 @@
+use crates_index::BareIndex;
+use futures_util::StreamExt;
+use reqwest::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER,
+};
+use reqwest::{Client, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::io::{Read, Result, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::Semaphore;
+
+use crate::otel::Tracer;
+use crate::permissions::Permissions;
+use crate::report::{Record, Report};
+use crate::state::{LocalDigest, State, VerifiedChecksum};
+use crate::window::SyncWindow;
+
+pub const RUSTLANG_ROOT_URL: &str = "https://static.rust-lang.org";
+pub const CRATES_ROOT_URL: &str = "https://static.crates.io";
+
+/// Downloads paused because the disk is full resume once at least this much space is free.
+const DISK_FULL_RESUME: u64 = 1024 * 1024 * 1024;
+
+/// How often the free space is checked while the downloads are paused.
+const DISK_FULL_POLL: Duration = Duration::from_secs(30);
+
+/// How long the downloads are paused after a 429 response without a Retry-After header.
+const RATE_LIMIT_PAUSE: Duration = Duration::from_secs(60);
+
+/// Longer Retry-After values are cut to this.
+const MAX_RATE_LIMIT_PAUSE: Duration = Duration::from_secs(60 * 60);
+
+/// A download fails after this many 429 responses, which do not count as retries.
+const MAX_RATE_LIMITED: u32 = 10;
+
+/// The directory that files are stored in by their SHA-256 digest with `--content-addressed`, as
+/// `<first two hex digits>/<digest>`.
+pub const OBJECTS_DIRECTORY: &str = "/.squire/objects";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    True,
+    False,
+    Checksum([u8; 32]),
+}
+
+/// What is done with the partial file of a download that fails, from `--partial-files`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialFiles {
+    /// Delete it, so the next attempt starts over.
+    Delete,
+    /// Keep it as `<file>.part`, and resume it with a range request on the next attempt (or run).
+    /// Segmented downloads cannot be resumed, so they are deleted.
+    Keep,
+    /// Move it to the final path as if it was complete, where later runs skip it unless its size or
+    /// checksum is validated.
+    InPlace,
+}
+
+impl std::str::FromStr for PartialFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "delete" => Ok(PartialFiles::Delete),
+            "keep" => Ok(PartialFiles::Keep),
+            "in-place" => Ok(PartialFiles::InPlace),
+            _ => Err(format!("Unknown partial file policy: {}", s)),
+        }
+    }
+}
+
+/// The result of a single call to [`Downloader::download`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The file was written to the mirror.
+    Downloaded,
+    /// The file already exists in the mirror and did not need to be downloaded again.
+    Skipped,
+    /// The file does not exist upstream (HTTP 404/410). This is expected for some sidecar files and
+    /// components that are not built for every target, so it is not retried.
+    NotFound,
+    /// The file could not be downloaded, even after retrying.
+    Failed,
+}
+
+/// Why a single download attempt failed.
+enum Error {
+    /// A 404 or 410 response.
+    NotFound,
+    /// A failure that might be resolved by trying again (5xx, timeouts, connection problems).
+    Transient(String),
+    /// A failure that will not be resolved by trying again (other 4xx responses).
+    Permanent(String),
+    /// A 429 response, with the time to wait from its Retry-After header.
+    RateLimited(String, Option<Duration>),
+    /// Writing to the mirror failed.
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// The temporary path a file is downloaded to before it is renamed into place.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(OsString::from(".part"));
+    PathBuf::from(name)
+}
+
+fn check_status(res: &reqwest::Response) -> std::result::Result<(), Error> {
+    match res.status() {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Err(Error::NotFound),
+        StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimited(
+            format!("HTTP {}", res.status()),
+            retry_after(res),
+        )),
+        status if status.is_server_error() => Err(Error::Transient(format!("HTTP {}", status))),
+        status if !status.is_success() => Err(Error::Permanent(format!("HTTP {}", status))),
+        _ => Ok(()),
+    }
+}
+
+/// The size and modification time (in nanoseconds since the epoch) of a file, which change whenever
+/// it is rewritten.
+pub fn fingerprint(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos().try_into().ok()?))
+}
+
+/// The Retry-After header of a response, given in seconds or as an HTTP date.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            // A date in the past means the download can be retried right away.
+            let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            Some(
+                (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                    .to_std()
+                    .unwrap_or(Duration::ZERO),
+            )
+        }
+    }
+}
+
+fn content_length(res: &reqwest::Response) -> Option<u64> {
+    res.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+}
+
+/// The Last-Modified time of a response, which the file in the mirror is given so replication (e.g.
+/// with rsync) and HTTP caching see when it actually changed upstream.
+fn last_modified(res: &reqwest::Response) -> Option<SystemTime> {
+    let value = res.headers().get(LAST_MODIFIED)?.to_str().ok()?;
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(SystemTime::from)
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(time)
+}
+
+pub fn num_cpus() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// Computes the SHA-256 digest of a file without reading it into memory all at once.
+pub fn sha256_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().as_slice().try_into().unwrap())
+}
+
+/// Computes the BLAKE3 digest of a file without reading it into memory all at once.
+pub fn blake3_file(path: &Path) -> Result<blake3::Hash> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Computes both the SHA-256 and BLAKE3 digests of a file, reading it once.
+pub fn sha256_blake3_file(path: &Path) -> Result<([u8; 32], blake3::Hash)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut sha256 = Sha256::new();
+    let mut blake3 = blake3::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buffer[..n]);
+        blake3.update(&buffer[..n]);
+    }
+
+    Ok((
+        sha256.finalize().as_slice().try_into().unwrap(),
+        blake3.finalize(),
+    ))
+}
+
+/// Copies a file through a temporary file, like a download, so the target is never partially written.
+fn copy_file(permissions: &Permissions, source: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        permissions.create_dir_all(parent)?;
+    }
+
+    let part_path = part_path(target);
+    std::fs::copy(source, &part_path)?;
+    set_modified(&part_path, std::fs::metadata(source)?.modified()?)?;
+    permissions.apply_file(&part_path)?;
+    std::fs::rename(&part_path, target)
+}
+
+/// The number of hard links to a file.
+#[cfg(unix)]
+fn link_count(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+/// Replaces the file at `path` with a hard link to `object`, which is created from the file itself if
+/// no file with the same content has been stored yet.
+#[cfg(unix)]
+fn link_to_object(permissions: &Permissions, path: &Path, object: &Path) -> Result<()> {
+    if let Some(parent) = object.parent() {
+        permissions.create_dir_all(parent)?;
+    }
+
+    match std::fs::hard_link(path, object) {
+        Ok(()) => return Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(error) => return Err(error),
+    }
+
+    let part_path = part_path(path);
+    let _ = std::fs::remove_file(&part_path);
+    std::fs::hard_link(object, &part_path)?;
+    std::fs::rename(&part_path, path)
+}
+
+fn copy_tree(permissions: &Permissions, source: &Path, target: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let source = entry.path();
+        let target = target.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_tree(permissions, &source, &target)?;
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let changed = match std::fs::metadata(&target) {
+            Ok(existing) => {
+                existing.len() != metadata.len() || existing.modified()? < metadata.modified()?
+            }
+            Err(_) => true,
+        };
+
+        if changed {
+            copy_file(permissions, &source, &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub downloaded: AtomicUsize,
+    /// Total size of the downloaded files.
+    pub bytes: AtomicU64,
+    pub skipped: AtomicUsize,
+    pub retries: AtomicUsize,
+    pub not_found: Mutex<Vec<String>>,
+    pub failed: Mutex<Vec<String>>,
+    /// Downloads that failed, or could not be written to the mirror.
+    pub failures: AtomicUsize,
+    /// Failures since the last download that succeeded or was answered with not found.
+    pub consecutive_failures: AtomicUsize,
+    /// Bytes received by all downloads, including those that failed.
+    pub received: AtomicU64,
+    /// The section being synced, and when it started.
+    pub section: Mutex<Option<(String, Instant)>>,
+    /// Files of the section that have been processed, out of `total` (0 when not known up front).
+    pub done: AtomicUsize,
+    pub total: AtomicUsize,
+    /// Downloads in progress, by id.
+    pub transfers: Mutex<BTreeMap<usize, Arc<Transfer>>>,
+    next_transfer: AtomicUsize,
+    /// The disk stayed full for longer than `--disk-full-wait`.
+    pub disk_full: AtomicBool,
+    /// Targets and components that have appeared in a channel manifest since the last sync.
+    pub additions: Mutex<Vec<String>>,
+    /// 429 responses, and how long (in seconds) the downloads were paused for them in total.
+    pub rate_limited: AtomicUsize,
+    pub rate_limited_pause: AtomicU64,
+}
+
+/// A download in progress.
+#[derive(Debug)]
+pub struct Transfer {
+    pub url: String,
+    pub started: Instant,
+    pub length: Option<u64>,
+    pub received: AtomicU64,
+}
+
+/// Removes a download from the transfers in progress when it is dropped.
+struct ActiveTransfer<'a> {
+    stats: &'a Stats,
+    id: usize,
+    transfer: Arc<Transfer>,
+}
+
+impl ActiveTransfer<'_> {
+    fn receive(&self, bytes: usize) {
+        self.transfer
+            .received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.stats
+            .received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ActiveTransfer<'_> {
+    fn drop(&mut self) {
+        self.stats.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Stats {
+    /// Starts a new section, whose progress is counted from zero.
+    pub fn begin_section(&self, section: &str) {
+        *self.section.lock().unwrap() = Some((section.to_string(), Instant::now()));
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+    }
+
+    fn begin_transfer(&self, url: &str, length: Option<u64>) -> ActiveTransfer<'_> {
+        let id = self.next_transfer.fetch_add(1, Ordering::Relaxed);
+        let transfer = Arc::new(Transfer {
+            url: url.to_string(),
+            started: Instant::now(),
+            length,
+            received: AtomicU64::new(0),
+        });
+        self.transfers.lock().unwrap().insert(id, transfer.clone());
+
+        ActiveTransfer {
+            stats: self,
+            id,
+            transfer,
+        }
+    }
+}
+
+pub struct Downloader {
+    pub client: Client,
+    /// Limits the number of concurrent HTTP requests.
+    requests: Semaphore,
+    /// Limits the number of concurrent HTTP requests to specific hosts, instead of `requests`.
+    host_requests: HashMap<String, Semaphore>,
+    /// Limits the number of files being hashed concurrently on the blocking thread pool.
+    pub hashers: Semaphore,
+    pub output_directory: String,
+    /// A crates.io-index clone maintained by another tool (`--index-path`), read instead of the index
+    /// in the mirror.
+    pub index_path: Option<PathBuf>,
+    /// Where the index in the mirror is cloned and updated from.
+    pub index_url: String,
+    /// Number of additional attempts for downloads that fail with a transient error.
+    pub retries: u32,
+    /// Maximum time to wait for the next chunk of a response before the attempt is considered timed out.
+    pub timeout: Duration,
+    /// Files known to exist in the mirror (mirror path to size), from a pre-scan of the output directory.
+    /// When present, skip decisions use this instead of querying the filesystem for every file.
+    pub existing: Option<HashMap<String, u64>>,
+    /// Compare the size of existing files with the Content-Length of a HEAD request, to detect
+    /// truncated files without validating checksums.
+    pub verify_size: bool,
+    /// Flush downloaded files to disk before renaming them into place.
+    pub fsync: bool,
+    /// What is done with the partial file of a failed download.
+    pub partial_files: PartialFiles,
+    /// Number of parallel range requests used to download files of at least `segment_threshold` bytes.
+    pub segments: usize,
+    pub segment_threshold: u64,
+    /// Additional output directories that every file of the mirror is copied to.
+    pub destinations: Vec<String>,
+    /// Modes and ownership of the files and directories that are written.
+    pub permissions: Permissions,
+    /// Information about the mirror kept between runs.
+    pub state: Option<State>,
+    /// Verify checksums against BLAKE3 digests recorded in the state, when available.
+    pub local_digests: bool,
+    /// Hash existing files even if they have not changed since they were last verified.
+    pub force_revalidate: bool,
+    /// Existing files are validated against their checksums (`--validate-checksums`), so the
+    /// checksums downloads were verified against are recorded to skip hashing them again.
+    pub validate_checksums: bool,
+    /// Directory that files failing validation are moved to before they are downloaded again.
+    pub quarantine: Option<String>,
+    /// Write `.sha256` sidecars for the files that have none upstream (`--local-sha256`).
+    pub local_sha256: bool,
+    /// Store each file once in `OBJECTS_DIRECTORY`, with hard links at its paths
+    /// (`--content-addressed`).
+    pub content_addressed: bool,
+    /// Root URLs of hosts that serve the same files as static.crates.io, tried when downloading a crate
+    /// from it fails.
+    pub crates_fallbacks: Vec<String>,
+    /// Number of bytes after which no new downloads are started, from `--max-total-bytes`.
+    pub max_bytes: Option<u64>,
+    /// Number of failures, in total or in a row, after which no new downloads are started.
+    pub max_failures: Option<usize>,
+    pub max_consecutive_failures: Option<usize>,
+    /// How long the downloads are paused when the disk is full, waiting for space to be freed, before
+    /// the sync is aborted.
+    pub disk_full_wait: Duration,
+    /// Shell command run when the disk is full, from `--on-disk-full`.
+    pub on_disk_full: Option<String>,
+    /// Held while the downloads are paused because the disk is full.
+    disk_full: tokio::sync::Mutex<()>,
+    /// The end of the pause after a 429 response.
+    rate_limited_until: Mutex<Option<Instant>>,
+    /// The hours of the day that downloads are started in, from `--sync-window`.
+    pub sync_window: Option<SyncWindow>,
+    /// Set while the downloads are paused outside of the sync window.
+    outside_sync_window: AtomicBool,
+    /// Report with a record of every file that is processed.
+    pub report: Option<Report>,
+    /// Emit a progress event for every file that is processed.
+    pub events: bool,
+    /// Records spans of the downloads, for `--otel-endpoint`.
+    pub tracer: Option<Arc<Tracer>>,
+    /// Shared with the dashboard, which shows the progress of the sync.
+    pub stats: Arc<Stats>,
+}
+
+impl Downloader {
+    pub fn new(
+        client: Client,
+        output_directory: &str,
+        concurrency: usize,
+        retries: u32,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            client,
+            requests: Semaphore::new(concurrency),
+            host_requests: HashMap::new(),
+            hashers: Semaphore::new(num_cpus()),
+            output_directory: output_directory.to_string(),
+            index_path: None,
+            index_url: crate::CRATES_INDEX_URL.to_string(),
+            retries,
+            timeout,
+            existing: None,
+            verify_size: false,
+            fsync: false,
+            partial_files: PartialFiles::Delete,
+            segments: 1,
+            segment_threshold: u64::MAX,
+            destinations: Vec::new(),
+            permissions: Permissions::default(),
+            state: None,
+            local_digests: false,
+            force_revalidate: false,
+            validate_checksums: false,
+            quarantine: None,
+            local_sha256: false,
+            content_addressed: false,
+            report: None,
+            events: false,
+            tracer: None,
+            max_bytes: None,
+            max_failures: None,
+            max_consecutive_failures: None,
+            disk_full_wait: Duration::ZERO,
+            on_disk_full: None,
+            disk_full: tokio::sync::Mutex::new(()),
+            rate_limited_until: Mutex::new(None),
+            sync_window: None,
+            outside_sync_window: AtomicBool::new(false),
+            crates_fallbacks: Vec::new(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Limits the number of concurrent HTTP requests to a host separately from other hosts.
+    pub fn limit_host(&mut self, host: &str, concurrency: usize) {
+        self.host_requests
+            .insert(host.to_string(), Semaphore::new(concurrency));
+    }
+
+    /// The semaphore limiting the concurrent requests for a URL.
+    pub fn requests(&self, url: &str) -> &Semaphore {
+        url::Url::parse(url)
+            .ok()
+            .and_then(|url| self.host_requests.get(url.host_str()?))
+            .unwrap_or(&self.requests)
+    }
+
+    pub fn local_path(&self, path: &str) -> PathBuf {
+        crate::paths::local_path(&self.output_directory, path)
+    }
+
+    /// The crates.io-index clone that is read, the one in the mirror unless `--index-path` is given.
+    pub fn index_path(&self) -> PathBuf {
+        self.index_path
+            .clone()
+            .unwrap_or_else(|| self.local_path("/index"))
+    }
+
+    pub fn bare_index(&self) -> BareIndex {
+        BareIndex::with_path(self.index_path(), &self.index_url)
+    }
+
+    /// Computes the SHA-256 digest of a file on the blocking thread pool, limited by `hashers`.
+    pub async fn hash(&self, path: &Path) -> Result<[u8; 32]> {
+        self.hash_with(path, sha256_file).await
+    }
+
+    async fn hash_with<T: Send + 'static>(
+        &self,
+        path: &Path,
+        f: fn(&Path) -> Result<T>,
+    ) -> Result<T> {
+        let _permit = self.hashers.acquire().await.unwrap();
+        let path = path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || f(&path))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// Whether the SHA-256 digest of the existing file is `checksum`. Files that have not changed
+    /// (same size and modification time) since they were last verified against the same checksum are
+    /// not hashed again, unless `force_revalidate` is set.
+    async fn checksum_matches(
+        &self,
+        path: &str,
+        path_buf: &Path,
+        checksum: [u8; 32],
+    ) -> Result<bool> {
+        if let Some(state) = &self.state {
+            if !self.force_revalidate {
+                let fingerprint = fingerprint(&tokio::fs::metadata(path_buf).await?);
+                let unchanged = state.verified(path).is_some_and(|verified| {
+                    Some((verified.size, verified.modified)) == fingerprint
+                        && verified.sha256 == hex::encode(checksum)
+                });
+                if unchanged {
+                    return Ok(true);
+                }
+            }
+        }
+
+        let valid = self.hash_matches(path, path_buf, checksum).await?;
+        if valid {
+            self.set_verified(path, path_buf, checksum).await?;
+        }
+
+        Ok(valid)
+    }
+
+    /// Records that the file has been verified against `checksum`, along with its current size and
+    /// modification time.
+    async fn set_verified(&self, path: &str, path_buf: &Path, checksum: [u8; 32]) -> Result<()> {
+        if let Some(state) = &self.state {
+            if let Some((size, modified)) = fingerprint(&tokio::fs::metadata(path_buf).await?) {
+                state.set_verified(
+                    path,
+                    VerifiedChecksum {
+                        size,
+                        modified,
+                        sha256: hex::encode(checksum),
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the SHA-256 digest of the existing file is `checksum`. With `local_digests`, the file
+    /// is compared with the BLAKE3 digest recorded the last time its SHA-256 digest was verified
+    /// instead, which is much cheaper. SHA-256 is still used if there is no such digest, it was
+    /// recorded for another checksum, or the file no longer matches it.
+    async fn hash_matches(&self, path: &str, path_buf: &Path, checksum: [u8; 32]) -> Result<bool> {
+        let state = match &self.state {
+            Some(state) if self.local_digests => state,
+            _ => return Ok(checksum == self.hash(path_buf).await?),
+        };
+
+        let size = tokio::fs::metadata(path_buf).await?.len();
+
+        if let Some(digest) = state.digest(path) {
+            if digest.size == size
+                && digest.sha256 == hex::encode(checksum)
+                && self
+                    .hash_with(path_buf, blake3_file)
+                    .await?
+                    .to_hex()
+                    .as_str()
+                    == digest.blake3
+            {
+                return Ok(true);
+            }
+        }
+
+        let (sha256, blake3) = self.hash_with(path_buf, sha256_blake3_file).await?;
+        if sha256 != checksum {
+            return Ok(false);
+        }
+
+        state.set_digest(
+            path,
+            LocalDigest {
+                size,
+                blake3: blake3.to_hex().to_string(),
+                sha256: hex::encode(checksum),
+            },
+        );
+
+        Ok(true)
+    }
+
+    /// Whether `max_bytes` have been downloaded, after which the sync stops starting new downloads.
+    pub fn budget_exhausted(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| self.stats.bytes.load(Ordering::Relaxed) >= max_bytes)
+    }
+
+    /// Why the sync should be aborted, once too many downloads have failed (upstream is likely down,
+    /// or the mirror cannot be written to) or the disk has stayed full.
+    pub fn failure_limit(&self) -> Option<String> {
+        let failures = self.stats.failures.load(Ordering::Relaxed);
+        let consecutive = self.stats.consecutive_failures.load(Ordering::Relaxed);
+
+        if self.stats.disk_full.load(Ordering::Relaxed) {
+            Some(format!("The disk of {} is full.", self.output_directory))
+        } else if self.max_failures.is_some_and(|max| failures >= max) {
+            Some(format!("{} downloads failed (--max-failures).", failures))
+        } else if self
+            .max_consecutive_failures
+            .is_some_and(|max| consecutive >= max)
+        {
+            Some(format!(
+                "{} downloads failed in a row (--max-consecutive-failures).",
+                consecutive
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Whether no new downloads should be started, because the budget is exhausted or too many
+    /// downloads have failed.
+    pub fn should_stop(&self) -> bool {
+        self.budget_exhausted() || self.failure_limit().is_some()
+    }
+
+    /// Pauses the downloads while the disk of the mirror is full, until enough space is freed (to retry
+    /// the download) or `disk_full_wait` has passed (to abort the sync). Returns whether to retry.
+    async fn wait_for_space(&self, error: &std::io::Error) -> bool {
+        let _paused = match self.disk_full.try_lock() {
+            Ok(paused) => paused,
+            // Another download is already waiting for space, retry once it is done.
+            Err(_) => {
+                drop(self.disk_full.lock().await);
+                return !self.stats.disk_full.load(Ordering::Relaxed);
+            }
+        };
+        if self.stats.disk_full.load(Ordering::Relaxed) || self.disk_full_wait.is_zero() {
+            self.stats.disk_full.store(true, Ordering::Relaxed);
+            return false;
+        }
+
+        let available = fs2::available_space(&self.output_directory).unwrap_or_default();
+        log::error!(
+            "The disk of {} is full ({}), pausing the downloads until {} is free (for up to {}s)...",
+            self.output_directory,
+            error,
+            crate::format_size(DISK_FULL_RESUME),
+            self.disk_full_wait.as_secs()
+        );
+        crate::systemd::notify("STATUS=Paused, the disk is full");
+        if self.events {
+            crate::events::emit(&crate::events::Event::DiskFull {
+                output_directory: &self.output_directory,
+                available,
+            });
+        }
+        if let Some(command) = &self.on_disk_full {
+            self.run_hook(command, available).await;
+        }
+
+        let paused = Instant::now();
+        loop {
+            tokio::time::sleep(DISK_FULL_POLL.min(self.disk_full_wait)).await;
+
+            let available = fs2::available_space(&self.output_directory).unwrap_or_default();
+            if available >= DISK_FULL_RESUME {
+                log::info!(
+                    "{} free on the disk of {}, resuming the downloads.",
+                    crate::format_size(available),
+                    self.output_directory
+                );
+                crate::systemd::notify("STATUS=Syncing");
+                return true;
+            }
+            if paused.elapsed() >= self.disk_full_wait {
+                self.stats.disk_full.store(true, Ordering::Relaxed);
+                return false;
+            }
+        }
+    }
+
+    /// Runs a `--on-disk-full` command with the shell, with the output directory and the free space
+    /// (in bytes) in `SQUIRE_OUTPUT_DIRECTORY` and `SQUIRE_AVAILABLE`.
+    async fn run_hook(&self, command: &str, available: u64) {
+        let mut shell = if cfg!(windows) {
+            let mut shell = tokio::process::Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = tokio::process::Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+
+        let status = shell
+            .arg(command)
+            .env("SQUIRE_OUTPUT_DIRECTORY", &self.output_directory)
+            .env("SQUIRE_AVAILABLE", available.to_string())
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::warn!("--on-disk-full command exited with {}", status),
+            Err(error) => log::warn!("Unable to run the --on-disk-full command: {}", error),
+        }
+    }
+
+    fn count_failure(&self) {
+        self.stats.failures.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds a record of what happened to a file to the report, the progress events and the traces, if
+    /// enabled.
+    fn report(&self, url: &str, path: &str, action: &str, start: Instant, error: Option<String>) {
+        if self.report.is_none() && !self.events && self.tracer.is_none() {
+            return;
+        }
+
+        let bytes = std::fs::metadata(self.local_path(path))
+            .ok()
+            .map(|metadata| metadata.len());
+        let record = Record {
+            path,
+            url,
+            action,
+            bytes,
+            duration_ms: start.elapsed().as_millis(),
+            error,
+        };
+
+        if let Some(report) = &self.report {
+            report.record(&record);
+        }
+        if self.events {
+            crate::events::emit(&crate::events::Event::File(&record));
+        }
+        if let Some(tracer) = self.tracer.as_ref().filter(|_| action != "skipped") {
+            tracer.record_file(
+                url,
+                path,
+                action,
+                start,
+                record.bytes,
+                record.error.as_deref(),
+            );
+        }
+    }
+
+    /// Moves a file that failed validation to the quarantine (if any), next to a record of why, so it
+    /// can be analyzed after it has been downloaded again.
+    async fn quarantine(&self, path: &str, path_buf: &Path, reason: &str) -> Result<()> {
+        let quarantine = match &self.quarantine {
+            Some(quarantine) => quarantine,
+            None => return Ok(()),
+        };
+
+        // Every quarantined file is kept, even if the same file fails validation again.
+        let time = chrono::Local::now();
+        let target = crate::paths::local_path(
+            quarantine,
+            &format!("/{}{}", time.format("%Y-%m-%dT%H%M%S"), path),
+        );
+        log::warn!("Quarantining {} ({}) in {}...", path, reason, quarantine);
+
+        let sha256 = hex::encode(self.hash(path_buf).await?);
+        let record = serde_json::json!({
+            "path": path,
+            "reason": reason,
+            "sha256": sha256,
+            "quarantined": time.to_rfc3339(),
+        });
+
+        let source = path_buf.to_path_buf();
+        let permissions = self.permissions;
+        tokio::task::spawn_blocking(move || {
+            permissions.create_dir_all(target.parent().unwrap())?;
+            // Fall back to copying when the quarantine is on another file system.
+            if std::fs::rename(&source, &target).is_err() {
+                std::fs::copy(&source, &target)?;
+                std::fs::remove_file(&source)?;
+            }
+
+            let mut record_path = target.into_os_string();
+            record_path.push(".reason.json");
+            std::fs::write(record_path, serde_json::to_vec_pretty(&record)?)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    /// Whether the file exists in the mirror.
+    pub fn exists(&self, path: &str) -> bool {
+        match &self.existing {
+            Some(existing) => existing.contains_key(path) || self.local_path(path).exists(),
+            None => self.local_path(path).exists(),
+        }
+    }
+
+    /// Whether the size of the existing file matches the Content-Length reported by upstream. Assumes
+    /// that it does if upstream does not report a size or cannot be reached.
+    async fn size_matches(&self, url: &str, path: &str) -> Result<bool> {
+        let local_size = match self.existing.as_ref().and_then(|e| e.get(path)) {
+            Some(size) => *size,
+            None => tokio::fs::metadata(self.local_path(path)).await?.len(),
+        };
+
+        let _permit = self.requests(url).acquire().await.unwrap();
+        let remote_size = match self.client.head(url).send().await {
+            Ok(res) if res.status().is_success() => content_length(&res),
+            Ok(res) => {
+                log::debug!("HEAD {} returned {}", url, res.status());
+                None
+            }
+            Err(error) => {
+                log::debug!("HEAD {} failed: {}", url, error);
+                None
+            }
+        };
+
+        match remote_size {
+            Some(remote_size) if remote_size != local_size => {
+                log::info!(
+                    "Size of {} ({} bytes) does not match upstream ({} bytes).",
+                    path,
+                    local_size,
+                    remote_size
+                );
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    pub async fn download(&self, path: &str, overwrite: Overwrite) -> Result<Outcome> {
+        let url = if path.ends_with(".crate") {
+            format!("{}{}", CRATES_ROOT_URL, path)
+        } else {
+            format!("{}{}", RUSTLANG_ROOT_URL, path)
+        };
+
+        self.download_url(url, path, overwrite).await
+    }
+
+    /// Downloads `url` to `path` in the mirror, for files that are not hosted on one of the root URLs.
+    pub async fn download_url(
+        &self,
+        url: String,
+        path: &str,
+        overwrite: Overwrite,
+    ) -> Result<Outcome> {
+        let outcome = self.fetch(url, path, overwrite, None).await?;
+
+        if matches!(outcome, Outcome::Downloaded | Outcome::Skipped) {
+            self.link_object(path, None).await?;
+            self.replicate(path, outcome == Outcome::Downloaded).await?;
+        }
+
+        Ok(outcome)
+    }
+
+    /// Downloads a crate, whose SHA-256 digest is known from the index. The digest of the downloaded
+    /// bytes is compared with `checksum` before the file is renamed into place; a corrupted transfer is
+    /// discarded and retried like any other transient error.
+    pub async fn download_crate(
+        &self,
+        path: &str,
+        overwrite: Overwrite,
+        checksum: [u8; 32],
+    ) -> Result<Outcome> {
+        let url = format!("{}{}", CRATES_ROOT_URL, path);
+        let outcome = self.fetch(url, path, overwrite, Some(checksum)).await?;
+
+        if matches!(outcome, Outcome::Downloaded | Outcome::Skipped) {
+            // Only a download is known to match the checksum, without `--validate-checksums`.
+            let verified = (outcome == Outcome::Downloaded).then_some(checksum);
+            self.link_object(path, verified).await?;
+            self.replicate(path, outcome == Outcome::Downloaded).await?;
+            if self.local_sha256 {
+                self.write_sha256(path, Some(checksum), outcome == Outcome::Downloaded)
+                    .await?;
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Writes a `.sha256` sidecar for a file of the mirror that has none upstream, in the format of
+    /// `sha256sum` after a comment marking it as generated locally. The digest is computed unless it is
+    /// known (e.g. from the index). An existing sidecar is kept unless the file has `changed`.
+    pub async fn write_sha256(
+        &self,
+        path: &str,
+        digest: Option<[u8; 32]>,
+        changed: bool,
+    ) -> Result<()> {
+        let sidecar = format!("{}.sha256", path);
+        if !changed && self.exists(&sidecar) {
+            return Ok(());
+        }
+
+        let digest = match digest {
+            Some(digest) => digest,
+            None => self.hash(&self.local_path(path)).await?,
+        };
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let contents = format!(
+            "# Generated by squire, {} has no checksum upstream.\n{}  {}\n",
+            name,
+            hex::encode(digest),
+            name
+        );
+
+        log::debug!("Writing {}...", sidecar);
+        self.store(&sidecar, contents.into_bytes()).await
+    }
+
+    /// Copies a file of the mirror to the other destinations, if it was updated (`changed`) or does
+    /// not exist there yet.
+    async fn replicate(&self, path: &str, changed: bool) -> Result<()> {
+        for destination in &self.destinations {
+            let target = crate::paths::local_path(destination, path);
+            if !changed && target.exists() {
+                continue;
+            }
+
+            log::debug!("Copying {} to {}...", path, destination);
+
+            let source = self.local_path(path);
+            let permissions = self.permissions;
+            tokio::task::spawn_blocking(move || copy_file(&permissions, &source, &target))
+                .await
+                .map_err(std::io::Error::other)??;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the sync window to open. Downloads in progress when it closes are finished, new ones
+    /// wait until it opens again.
+    async fn wait_for_sync_window(&self) {
+        let window = match &self.sync_window {
+            Some(window) => window,
+            None => return,
+        };
+
+        while let Some(wait) = window.until_open() {
+            if !self.outside_sync_window.swap(true, Ordering::Relaxed) {
+                log::info!(
+                    "Outside of the sync window {}, pausing the downloads for {}s...",
+                    window,
+                    wait.as_secs()
+                );
+                crate::systemd::notify("STATUS=Paused, outside of the sync window");
+            }
+            tokio::time::sleep(wait).await;
+        }
+
+        if self.outside_sync_window.swap(false, Ordering::Relaxed) {
+            log::info!(
+                "The sync window {} is open, resuming the downloads.",
+                window
+            );
+            crate::systemd::notify("STATUS=Syncing");
+        }
+    }
+
+    /// Pauses all downloads after a 429 response for `url`, for as long as its Retry-After header asks
+    /// (`RATE_LIMIT_PAUSE` without one), so the upstream is not hammered while it throttles the sync.
+    fn pause_for_rate_limit(&self, url: &str, retry_after: Option<Duration>) {
+        let pause = retry_after
+            .unwrap_or(RATE_LIMIT_PAUSE)
+            .min(MAX_RATE_LIMIT_PAUSE);
+        let now = Instant::now();
+        let until = now + pause;
+        self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+
+        let mut paused = self.rate_limited_until.lock().unwrap();
+        let extended = match *paused {
+            Some(paused) if paused > now => until.saturating_duration_since(paused),
+            _ => pause,
+        };
+        if extended.is_zero() {
+            return;
+        }
+
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        log::warn!(
+            "Rate limited by {}, pausing the downloads for {}s...",
+            host,
+            pause.as_secs()
+        );
+        if self.events {
+            crate::events::emit(&crate::events::Event::RateLimited {
+                host: &host,
+                seconds: pause.as_secs(),
+            });
+        }
+        *paused = Some(until);
+        self.stats
+            .rate_limited_pause
+            .fetch_add(extended.as_secs(), Ordering::Relaxed);
+    }
+
+    /// With `content_addressed`, replaces the file at `path` with a hard link to the object of its
+    /// content in `OBJECTS_DIRECTORY`, so identical files are stored once. The digest is computed
+    /// unless it is known. Files that are linked already are left alone.
+    #[cfg(unix)]
+    async fn link_object(&self, path: &str, digest: Option<[u8; 32]>) -> Result<()> {
+        if !self.content_addressed {
+            return Ok(());
+        }
+
+        let path_buf = self.local_path(path);
+        if link_count(&tokio::fs::metadata(&path_buf).await?) > 1 {
+            return Ok(());
+        }
+
+        let digest = match digest {
+            Some(digest) => digest,
+            None => self.hash(&path_buf).await?,
+        };
+        let digest = hex::encode(digest);
+        let object = self.local_path(&format!(
+            "{}/{}/{}",
+            OBJECTS_DIRECTORY,
+            &digest[..2],
+            digest
+        ));
+        let permissions = self.permissions;
+
+        tokio::task::spawn_blocking(move || link_to_object(&permissions, &path_buf, &object))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    #[cfg(not(unix))]
+    async fn link_object(&self, _path: &str, _digest: Option<[u8; 32]>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Removes the objects in `OBJECTS_DIRECTORY` that no path of the mirror links to anymore, e.g.
+    /// after yanked crates or superseded releases were removed. Returns the number removed.
+    #[cfg(unix)]
+    pub async fn remove_unlinked_objects(&self) -> Result<usize> {
+        let objects = self.local_path(OBJECTS_DIRECTORY);
+
+        tokio::task::spawn_blocking(move || {
+            let mut removed = 0;
+            if !objects.is_dir() {
+                return Ok(removed);
+            }
+
+            for prefix in std::fs::read_dir(&objects)? {
+                for object in std::fs::read_dir(prefix?.path())? {
+                    let object = object?;
+                    if link_count(&object.metadata()?) == 1 {
+                        std::fs::remove_file(object.path())?;
+                        removed += 1;
+                    }
+                }
+            }
+
+            Ok(removed)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    #[cfg(not(unix))]
+    pub async fn remove_unlinked_objects(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Writes a file that is generated rather than downloaded to the mirror (and the other destinations).
+    pub async fn store(&self, path: &str, contents: Vec<u8>) -> Result<()> {
+        let target = self.local_path(path);
+        let permissions = self.permissions;
+
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = target.parent() {
+                permissions.create_dir_all(parent)?;
+            }
+
+            let part_path = part_path(&target);
+            std::fs::write(&part_path, contents)?;
+            permissions.apply_file(&part_path)?;
+            std::fs::rename(&part_path, &target)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        self.replicate(path, true).await
+    }
+
+    /// Copies a file of the mirror to another path in the mirror (and the other destinations).
+    pub async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let source = self.local_path(from);
+        let target = self.local_path(to);
+        let permissions = self.permissions;
+
+        tokio::task::spawn_blocking(move || copy_file(&permissions, &source, &target))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        self.link_object(to, None).await?;
+        self.replicate(to, true).await
+    }
+
+    /// Copies the files of a directory of the mirror (e.g. the index) that are missing or have changed
+    /// to the other destinations.
+    pub async fn replicate_tree(&self, path: &str) -> Result<()> {
+        for destination in &self.destinations {
+            log::info!("Copying {} to {}...", path, destination);
+
+            let source = self.local_path(path);
+            let target = crate::paths::local_path(destination, path);
+            let permissions = self.permissions;
+            tokio::task::spawn_blocking(move || copy_tree(&permissions, &source, &target))
+                .await
+                .map_err(std::io::Error::other)??;
+        }
+
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        url: String,
+        path: &str,
+        overwrite: Overwrite,
+        checksum: Option<[u8; 32]>,
+    ) -> Result<Outcome> {
+        let start = Instant::now();
+        let path_buf = self.local_path(path);
+
+        let download = match overwrite {
+            Overwrite::True => true,
+            _ if !self.exists(path) => true,
+            Overwrite::Checksum(checksum) => {
+                let valid = self.checksum_matches(path, &path_buf, checksum).await?;
+                if !valid {
+                    let reason = format!("SHA-256 digest does not match {}", hex::encode(checksum));
+                    self.quarantine(path, &path_buf, &reason).await?;
+                }
+                !valid
+            }
+            Overwrite::False if self.verify_size => {
+                let valid = self.size_matches(&url, path).await?;
+                if !valid {
+                    self.quarantine(path, &path_buf, "Size does not match upstream")
+                        .await?;
+                }
+                !valid
+            }
+            Overwrite::False => false,
+        };
+
+        if !download {
+            self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+            self.report(&url, path, "skipped", start, None);
+            return Ok(Outcome::Skipped);
+        }
+
+        // Crates can also be downloaded from the fallback hosts, which are tried in order before
+        // waiting to retry.
+        let sources: Vec<String> = std::iter::once(url.clone())
+            .chain(
+                url.strip_prefix(CRATES_ROOT_URL)
+                    .into_iter()
+                    .flat_map(|path| {
+                        self.crates_fallbacks
+                            .iter()
+                            .map(move |root| format!("{}{}", root.trim_end_matches('/'), path))
+                    }),
+            )
+            .collect();
+        let mut source = 0;
+
+        let mut attempt = 0;
+        let mut rate_limited = 0;
+        loop {
+            // Wait while the downloads are paused because the disk is full.
+            drop(self.disk_full.lock().await);
+            if self.stats.disk_full.load(Ordering::Relaxed) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "The disk is full",
+                ));
+            }
+
+            // Wait while the downloads are paused because upstream is rate limiting them.
+            let paused = *self.rate_limited_until.lock().unwrap();
+            if let Some(until) = paused {
+                tokio::time::sleep_until(until.into()).await;
+            }
+            self.wait_for_sync_window().await;
+
+            let source_url = &sources[source];
+            log::info!("Downloading {}...", source_url);
+
+            match self.attempt(source_url, &path_buf, checksum).await {
+                Err(Error::RateLimited(_, retry_after)) if rate_limited < MAX_RATE_LIMITED => {
+                    rate_limited += 1;
+                    self.pause_for_rate_limit(source_url, retry_after);
+                }
+                Ok(()) => {
+                    // Downloads with a checksum are verified while they are written.
+                    if let Some(checksum) = checksum.filter(|_| self.validate_checksums) {
+                        self.set_verified(path, &path_buf, checksum).await?;
+                    }
+                    self.stats.downloaded.fetch_add(1, Ordering::Relaxed);
+                    self.stats.consecutive_failures.store(0, Ordering::Relaxed);
+                    if let Ok(metadata) = tokio::fs::metadata(&path_buf).await {
+                        self.stats
+                            .bytes
+                            .fetch_add(metadata.len(), Ordering::Relaxed);
+                    }
+                    self.report(source_url, path, "downloaded", start, None);
+                    return Ok(Outcome::Downloaded);
+                }
+                Err(Error::NotFound) => {
+                    log::debug!("Not found upstream: {}", url);
+                    self.stats.consecutive_failures.store(0, Ordering::Relaxed);
+                    self.report(&url, path, "not_found", start, None);
+                    self.stats.not_found.lock().unwrap().push(url);
+                    return Ok(Outcome::NotFound);
+                }
+                Err(Error::Transient(error))
+                | Err(Error::Permanent(error))
+                | Err(Error::RateLimited(error, _))
+                    if source + 1 < sources.len() =>
+                {
+                    source += 1;
+                    log::warn!(
+                        "Error downloading file: {} ({}), trying {}...",
+                        source_url,
+                        error,
+                        sources[source]
+                    );
+                }
+                Err(Error::Transient(error)) if attempt < self.retries => {
+                    source = 0;
+                    attempt += 1;
+                    let delay = Duration::from_secs(1 << attempt.min(6));
+                    log::warn!(
+                        "Error downloading file: {} ({}), retrying in {}s [{}/{}]...",
+                        url,
+                        error,
+                        delay.as_secs(),
+                        attempt,
+                        self.retries
+                    );
+                    self.stats.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(Error::Transient(error))
+                | Err(Error::Permanent(error))
+                | Err(Error::RateLimited(error, _)) => {
+                    log::warn!("Error downloading file: {}", url);
+                    log::debug!("{}", error);
+                    self.report(&url, path, "failed", start, Some(error));
+                    self.stats.failed.lock().unwrap().push(url);
+                    self.count_failure();
+                    return Ok(Outcome::Failed);
+                }
+                // Truncated files are removed with the partial file, retry the same attempt once
+                // space has been freed.
+                Err(Error::Io(error))
+                    if error.kind() == std::io::ErrorKind::StorageFull
+                        && self.wait_for_space(&error).await => {}
+                Err(Error::Io(error)) => {
+                    self.report(&url, path, "error", start, Some(error.to_string()));
+                    self.count_failure();
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    async fn attempt(
+        &self,
+        url: &str,
+        path_buf: &Path,
+        checksum: Option<[u8; 32]>,
+    ) -> std::result::Result<(), Error> {
+        let permit = self.requests(url).acquire().await.unwrap();
+
+        // Write to a temporary file that is renamed into place once complete, so an interrupted
+        // download never leaves a truncated file at the final path.
+        let part_path = part_path(path_buf);
+
+        // Continue a partial file kept by an earlier attempt where it stopped.
+        let kept = match self.partial_files {
+            PartialFiles::Keep => tokio::fs::metadata(&part_path)
+                .await
+                .map_or(0, |metadata| metadata.len()),
+            _ => 0,
+        };
+
+        let mut request = self.client.get(url);
+        if kept > 0 {
+            request = request.header(RANGE, format!("bytes={}-", kept));
+        }
+        // A redirect that is not allowed fails again on a retry.
+        let res = match tokio::time::timeout(self.timeout, request.send()).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(error)) if error.is_redirect() => {
+                return Err(Error::Permanent(error.to_string()))
+            }
+            Ok(Err(error)) => return Err(Error::Transient(error.to_string())),
+            Err(_) => return Err(Error::Transient("timed out".to_string())),
+        };
+
+        // The partial file is as long as the file or longer, so it does not belong to it.
+        if kept > 0 && res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            log::debug!("Discarding partial download {}...", part_path.display());
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(Error::Transient(
+                "Partial download is not part of the file".to_string(),
+            ));
+        }
+
+        check_status(&res)?;
+
+        // Servers that ignore the range send the whole file, which replaces the partial file.
+        let offset = match res.status() {
+            StatusCode::PARTIAL_CONTENT if kept > 0 => {
+                let expected = format!("bytes {}-", kept);
+                let resumes = res
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.starts_with(&expected));
+                if !resumes {
+                    let _ = tokio::fs::remove_file(&part_path).await;
+                    return Err(Error::Transient(
+                        "Unexpected range in response to resume a download".to_string(),
+                    ));
+                }
+
+                log::info!("Resuming {} from {} bytes...", url, kept);
+                kept
+            }
+            _ => 0,
+        };
+
+        let transfer = self.stats.begin_transfer(url, content_length(&res));
+        let modified = last_modified(&res);
+
+        log::debug!("Writing file {}...", path_buf.display());
+
+        if let Some(path) = path_buf.parent() {
+            let permissions = self.permissions;
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || permissions.create_dir_all(&path))
+                .await
+                .map_err(std::io::Error::other)??;
+        }
+
+        let segmented = offset == 0
+            && self.segments > 1
+            && content_length(&res).is_some_and(|length| length >= self.segment_threshold)
+            && res
+                .headers()
+                .get(ACCEPT_RANGES)
+                .is_none_or(|value| value.as_bytes() != b"none");
+
+        let result = if segmented {
+            let length = content_length(&res).unwrap();
+            drop(res);
+            // Each segment request takes a permit of its own.
+            drop(permit);
+
+            match self
+                .write_segments(url, &part_path, length, &transfer)
+                .await
+            {
+                Ok(()) => match checksum {
+                    Some(checksum) => self.verify_checksum(&part_path, checksum).await,
+                    None => self.verify_sidecar_checksum(url, &part_path).await,
+                },
+                Err(Error::Permanent(error)) => {
+                    // Most likely range requests are not supported after all.
+                    log::debug!(
+                        "Segmented download of {} failed ({}), retrying as a single stream...",
+                        url,
+                        error
+                    );
+                    let _permit = self.requests(url).acquire().await.unwrap();
+                    match tokio::time::timeout(self.timeout, self.client.get(url).send()).await {
+                        Ok(Ok(res)) => match check_status(&res) {
+                            Ok(()) => {
+                                self.write_response(res, &part_path, 0, checksum, &transfer)
+                                    .await
+                            }
+                            Err(error) => Err(error),
+                        },
+                        Ok(Err(error)) => Err(Error::Transient(error.to_string())),
+                        Err(_) => Err(Error::Transient("timed out".to_string())),
+                    }
+                }
+                Err(error) => Err(error),
+            }
+        } else {
+            self.write_response(res, &part_path, offset, checksum, &transfer)
+                .await
+        };
+
+        match (result, self.partial_files) {
+            (Ok(()), _) => {
+                if let Some(modified) = modified {
+                    set_modified(&part_path, modified)?;
+                }
+                self.permissions.apply_file(&part_path)?;
+                tokio::fs::rename(&part_path, path_buf).await?;
+                Ok(())
+            }
+            (Err(error), PartialFiles::Keep) if !segmented => {
+                log::debug!("Keeping partial download {}.", part_path.display());
+                Err(error)
+            }
+            (Err(error), PartialFiles::InPlace) if part_path.exists() => {
+                log::warn!("Keeping the partial download of {} in place.", url);
+                self.permissions.apply_file(&part_path)?;
+                tokio::fs::rename(&part_path, path_buf).await?;
+                Err(error)
+            }
+            (Err(error), _) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                Err(error)
+            }
+        }
+    }
+
+    /// Writes the body of the response to `path`, after the first `offset` bytes of the file when a
+    /// download is resumed. With a `checksum`, the SHA-256 digest of the file is computed while it is
+    /// written and compared with it. A file that does not match is deleted, so it is not resumed.
+    async fn write_response(
+        &self,
+        res: reqwest::Response,
+        path: &Path,
+        offset: u64,
+        checksum: Option<[u8; 32]>,
+        transfer: &ActiveTransfer<'_>,
+    ) -> std::result::Result<(), Error> {
+        // reqwest removes the header from compressed responses, which it decodes.
+        let length: Option<u64> = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok());
+        let mut received = 0;
+        let mut stream = res.bytes_stream();
+        let mut hasher = checksum.map(|_| Sha256::new());
+
+        let file = if offset > 0 {
+            let mut file = tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .await?;
+            if let Some(hasher) = &mut hasher {
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    match file.read(&mut buffer).await? {
+                        0 => break,
+                        n => hasher.update(&buffer[..n]),
+                    }
+                }
+            }
+            file.set_len(offset).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            file
+        } else {
+            tokio::fs::File::create(path).await?
+        };
+        let mut file = BufWriter::new(file);
+
+        loop {
+            match tokio::time::timeout(self.timeout, stream.next()).await {
+                Ok(Some(Ok(bytes))) => {
+                    received += bytes.len() as u64;
+                    transfer.receive(bytes.len());
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&bytes);
+                    }
+                    file.write_all(&bytes).await?
+                }
+                // What has been received is written out, so a kept partial file can be resumed.
+                Ok(Some(Err(error))) => {
+                    file.flush().await?;
+                    return Err(Error::Transient(error.to_string()));
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    file.flush().await?;
+                    return Err(Error::Transient("timed out".to_string()));
+                }
+            }
+        }
+
+        file.flush().await?;
+
+        // A connection that is closed early can end the body without an error.
+        if let Some(length) = length.filter(|length| *length != received) {
+            if received > length {
+                drop(file);
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            return Err(Error::Transient(format!(
+                "Received {} of the {} bytes of the Content-Length",
+                received, length
+            )));
+        }
+
+        if let (Some(checksum), Some(hasher)) = (checksum, hasher) {
+            if hasher.finalize()[..] != checksum[..] {
+                drop(file);
+                let _ = tokio::fs::remove_file(path).await;
+                return Err(Error::Transient(
+                    "Checksum of downloaded file does not match the index".to_string(),
+                ));
+            }
+        }
+
+        if self.fsync {
+            file.get_ref().sync_all().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads a file of `length` bytes using `self.segments` parallel range requests, each writing
+    /// directly to its own region of the file.
+    async fn write_segments(
+        &self,
+        url: &str,
+        path: &Path,
+        length: u64,
+        transfer: &ActiveTransfer<'_>,
+    ) -> std::result::Result<(), Error> {
+        log::debug!(
+            "Downloading {} ({} bytes) in {} segments...",
+            url,
+            length,
+            self.segments
+        );
+
+        let file = tokio::fs::File::create(path).await?;
+        file.set_len(length).await?;
+        drop(file);
+
+        let segment_size = length.div_ceil(self.segments as u64);
+        let segments = (0..self.segments as u64)
+            .map(|i| (i * segment_size, ((i + 1) * segment_size).min(length)))
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| self.write_segment(url, path, start, end, transfer));
+
+        // A throttled segment pauses the downloads, rather than falling back to a single stream.
+        let mut results = futures_util::future::join_all(segments).await;
+        results.sort_by_key(|result| !matches!(result, Err(Error::RateLimited(..))));
+        for result in results {
+            result?;
+        }
+
+        if self.fsync {
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .await?
+                .sync_all()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the byte range `start..end` of `url` into the same range of the file at `path`.
+    async fn write_segment(
+        &self,
+        url: &str,
+        path: &Path,
+        start: u64,
+        end: u64,
+        transfer: &ActiveTransfer<'_>,
+    ) -> std::result::Result<(), Error> {
+        let _permit = self.requests(url).acquire().await.unwrap();
+        let request = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, end - 1));
+        let res = match tokio::time::timeout(self.timeout, request.send()).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(error)) => return Err(Error::Transient(error.to_string())),
+            Err(_) => return Err(Error::Transient("timed out".to_string())),
+        };
+
+        check_status(&res)?;
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::Permanent(format!(
+                "Expected a partial response for range request, got HTTP {}.",
+                res.status()
+            )));
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut file = BufWriter::new(file);
+
+        let mut stream = res.bytes_stream();
+        let mut written = 0;
+
+        loop {
+            match tokio::time::timeout(self.timeout, stream.next()).await {
+                Ok(Some(Ok(bytes))) => {
+                    written += bytes.len() as u64;
+                    transfer.receive(bytes.len());
+                    if written > end - start {
+                        return Err(Error::Transient("Segment is larger than requested.".into()));
+                    }
+                    file.write_all(&bytes).await?;
+                }
+                Ok(Some(Err(error))) => return Err(Error::Transient(error.to_string())),
+                Ok(None) => break,
+                Err(_) => return Err(Error::Transient("timed out".to_string())),
+            }
+        }
+
+        file.flush().await?;
+
+        if written != end - start {
+            return Err(Error::Transient(format!(
+                "Segment {}-{} is truncated ({} of {} bytes).",
+                start,
+                end,
+                written,
+                end - start
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Compares the SHA-256 digest of the file at `path` with the checksum from the index.
+    async fn verify_checksum(
+        &self,
+        path: &Path,
+        checksum: [u8; 32],
+    ) -> std::result::Result<(), Error> {
+        if self.hash(path).await? != checksum {
+            return Err(Error::Transient(
+                "Checksum of reassembled file does not match the index".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Compares the SHA-256 digest of the file at `path` with the `.sha256` sidecar of `url`, if
+    /// upstream has one. Used to validate files that were reassembled from segments.
+    async fn verify_sidecar_checksum(
+        &self,
+        url: &str,
+        path: &Path,
+    ) -> std::result::Result<(), Error> {
+        let permit = self.requests(url).acquire().await.unwrap();
+        let expected = match self.client.get(format!("{}.sha256", url)).send().await {
+            Ok(res) if res.status().is_success() => res.text().await.ok().and_then(|text| {
+                let hash = text.split_whitespace().next()?;
+                hex::decode(hash).ok()
+            }),
+            _ => None,
+        };
+        drop(permit);
+
+        let expected = match expected {
+            Some(expected) => expected,
+            None => {
+                log::debug!("No checksum available to verify {}", url);
+                return Ok(());
+            }
+        };
+
+        let digest = self.hash(path).await?;
+
+        if digest[..] != expected[..] {
+            return Err(Error::Transient(format!(
+                "Checksum of reassembled file does not match {}.sha256",
+                url
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Logs a summary of all downloads performed so far.
+    pub fn log_summary(&self) {
+        let not_found = self.stats.not_found.lock().unwrap();
+        let failed = self.stats.failed.lock().unwrap();
+
+        log::info!(
+            "Downloaded {} files ({}), skipped {} existing files, {} not found upstream, {} failed ({} retries).",
+            self.stats.downloaded.load(Ordering::Relaxed),
+            crate::format_size(self.stats.bytes.load(Ordering::Relaxed)),
+            self.stats.skipped.load(Ordering::Relaxed),
+            not_found.len(),
+            failed.len(),
+            self.stats.retries.load(Ordering::Relaxed)
+        );
+
+        for url in not_found.iter() {
+            log::debug!("Not found upstream: {}", url);
+        }
+
+        for url in failed.iter() {
+            log::warn!("Failed: {}", url);
+        }
+
+        let rate_limited = self.stats.rate_limited.load(Ordering::Relaxed);
+        if rate_limited > 0 {
+            log::warn!(
+                "Rate limited {} times by upstream, the downloads were paused for {}s in total.",
+                rate_limited,
+                self.stats.rate_limited_pause.load(Ordering::Relaxed)
+            );
+        }
+
+        for addition in self.stats.additions.lock().unwrap().iter() {
+            log::warn!("New since the last sync: {}", addition);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use clap::ArgMatches;
+use crates_index::BareIndex;
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::path::Path;
+
+use crate::crate_list;
+use crate::download::{CRATES_ROOT_URL, RUSTLANG_ROOT_URL};
+use crate::format_size;
+use crate::list::sizes;
+use crate::manifest::Manifest;
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+struct Size {
+    files: usize,
+    bytes: u64,
+}
+
+impl Size {
+    fn add(&mut self, files: usize, bytes: u64) {
+        self.files += files;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Channel {
+    total: Size,
+    targets: BTreeMap<String, Size>,
+    components: BTreeMap<String, Size>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Crates {
+    total: Size,
+    /// Whether `total.bytes` is extrapolated from a sample of crate sizes.
+    estimated: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Estimate {
+    total: Size,
+    rustup: Size,
+    toolchain: BTreeMap<String, Channel>,
+    crates: Option<Crates>,
+}
+
+async fn rustup(client: &Client, architectures: &[String]) -> Size {
+    let urls: Vec<String> = architectures
+        .iter()
+        .map(|arch| {
+            let ext = if arch.contains("windows") { ".exe" } else { "" };
+            format!(
+                "{}/rustup/dist/{}/rustup-init{}",
+                RUSTLANG_ROOT_URL, arch, ext
+            )
+        })
+        .collect();
+
+    let sizes = sizes(client, urls.clone()).await;
+
+    Size {
+        files: urls.len() + 1, // The rustup-init executables and release-stable.toml.
+        bytes: sizes.values().sum(),
+    }
+}
+
+async fn channel(client: &Client, channel: &str, architectures: &[String]) -> Result<Channel> {
+    let manifest = Manifest::fetch(client, channel).await?;
+
+    // Select the same archives as the sync, along with the component and target they belong to.
+    let selected: Vec<(String, String, String)> = manifest
+        .archives(architectures)
+        .into_iter()
+        .filter(|archive| archive.url.starts_with(RUSTLANG_ROOT_URL))
+        .map(|archive| (archive.pkg, archive.target, archive.url))
+        .collect();
+
+    let sizes = sizes(
+        client,
+        selected.iter().map(|(_, _, url)| url.clone()).collect(),
+    )
+    .await;
+
+    // Every archive is accompanied by a .asc and .sha256 file, which are counted but negligible in size.
+    let mut result = Channel::default();
+    for (name, target, url) in selected {
+        let bytes = sizes.get(&url).copied().unwrap_or_default();
+        result.total.add(3, bytes);
+        result.targets.entry(target).or_default().add(3, bytes);
+        result.components.entry(name).or_default().add(3, bytes);
+    }
+    result.total.add(3, 0); // The channel manifest and its sidecars.
+
+    Ok(result)
+}
+
+async fn crates(client: &Client, output_directory: &str, samples: usize) -> Result<Option<Crates>> {
+    let index_path = Path::new(output_directory).join("index");
+    if !index_path.exists() {
+        return Ok(None);
+    }
+
+    let bare_index = BareIndex::with_path(index_path.clone(), crate::CRATES_INDEX_URL);
+    let index = bare_index.open_or_clone().map_err(std::io::Error::other)?;
+    let commit = crate_list::index_commit(&index_path)?;
+
+    let entries: Box<dyn Iterator<Item = crate_list::Entry>> =
+        match crate_list::Cache::new(output_directory).load(&commit)? {
+            Some(entries) => Box::new(entries),
+            None => Box::new(crate_list::from_index(&index)),
+        };
+
+    // Keep every 1000th crate version as a candidate for sampling the average crate size.
+    let mut count = 0;
+    let mut candidates = Vec::new();
+    for (name, version, _) in entries {
+        if count % 1000 == 0 {
+            candidates.push(format!(
+                "{}/crates/{}/{}-{}.crate",
+                CRATES_ROOT_URL, name, name, version
+            ));
+        }
+        count += 1;
+    }
+
+    let stride = (candidates.len() / samples.max(1)).max(1);
+    let sample: Vec<String> = candidates
+        .into_iter()
+        .step_by(stride)
+        .take(samples)
+        .collect();
+    let sizes = sizes(client, sample).await;
+
+    let average = if sizes.is_empty() {
+        0
+    } else {
+        sizes.values().sum::<u64>() / sizes.len() as u64
+    };
+
+    Ok(Some(Crates {
+        total: Size {
+            files: count,
+            bytes: average * count as u64,
+        },
+        estimated: true,
+    }))
+}
+
+fn print_size(label: &str, size: &Size, indent: usize) {
+    println!(
+        "{:indent$}{:<width$} {:>9} files {:>12}",
+        "",
+        label,
+        size.files,
+        format_size(size.bytes),
+        indent = indent,
+        width = 48 - indent
+    );
+}
+
+fn print(estimate: &Estimate) {
+    print_size("rustup", &estimate.rustup, 0);
+
+    for (name, channel) in &estimate.toolchain {
+        print_size(&format!("toolchain [channel-{}]", name), &channel.total, 0);
+
+        for (target, size) in &channel.targets {
+            print_size(target, size, 2);
+        }
+
+        println!("  components:");
+        for (component, size) in &channel.components {
+            print_size(component, size, 4);
+        }
+    }
+
+    match &estimate.crates {
+        Some(crates) => print_size("crates (estimated)", &crates.total, 0),
+        None => println!("crates: unknown, the output directory does not contain an index yet."),
+    }
+
+    print_size("total", &estimate.total, 0);
+}
+
+pub async fn run(client: &Client, matches: &ArgMatches) -> Result<()> {
+    let target_filter = crate::parse_target_filter(matches, matches.value_of("targets").unwrap())?;
+    let samples: usize = crate::number_arg(matches, "crate-samples")?;
+
+    let stable = Manifest::fetch(client, "stable").await?;
+    let available: Vec<String> = stable.targets().into_iter().map(String::from).collect();
+    let architectures = target_filter
+        .select(&available)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let mut estimate = Estimate {
+        rustup: rustup(client, &architectures).await,
+        ..Default::default()
+    };
+    estimate.total = estimate.rustup;
+
+    for name in matches.values_of("channels").unwrap() {
+        let channel = channel(client, name, &architectures).await?;
+        estimate.total.add(channel.total.files, channel.total.bytes);
+        estimate.toolchain.insert(name.to_string(), channel);
+    }
+
+    if let Some(output_directory) = matches.value_of("OUTPUT-DIRECTORY") {
+        estimate.crates = crates(client, output_directory, samples).await?;
+        if let Some(crates) = &estimate.crates {
+            estimate.total.add(crates.total.files, crates.total.bytes);
+        }
+    }
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&estimate)?);
+    } else {
+        print(&estimate);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+use std::io::Write;
+
+use crate::download::Stats;
+use crate::report::Record;
+
+/// A change in the state of a sync, for wrapper tools and dashboards (`--progress-format ndjson`).
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A section (`rustup`, `dist:<channel>`, `crates`, ...) has started.
+    SectionStarted {
+        section: &'a str,
+    },
+    SectionCompleted {
+        section: &'a str,
+    },
+    /// A file has been processed.
+    File(&'a Record<'a>),
+    /// The sync has ended, early if it was `stopped` (e.g. by `--max-total-bytes`).
+    Finished {
+        downloaded: usize,
+        bytes: u64,
+        skipped: usize,
+        not_found: usize,
+        failed: usize,
+        stopped: Option<&'a str>,
+    },
+    /// The disk of the mirror is full, so the downloads are paused until space is freed.
+    DiskFull {
+        output_directory: &'a str,
+        available: u64,
+    },
+    /// Upstream answered 429 Too Many Requests, so the downloads are paused for `seconds`.
+    RateLimited {
+        host: &'a str,
+        seconds: u64,
+    },
+    /// The sync failed with an error.
+    Error {
+        error: &'a str,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn finished(stats: &Stats, stopped: Option<&'a str>) -> Self {
+        use std::sync::atomic::Ordering;
+
+        Event::Finished {
+            downloaded: stats.downloaded.load(Ordering::Relaxed),
+            bytes: stats.bytes.load(Ordering::Relaxed),
+            skipped: stats.skipped.load(Ordering::Relaxed),
+            not_found: stats.not_found.lock().unwrap().len(),
+            failed: stats.failed.lock().unwrap().len(),
+            stopped,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Timed<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Writes an event to stdout as a line of JSON.
+pub fn emit(event: &Event<'_>) {
+    let timed = Timed {
+        time: chrono::Local::now().to_rfc3339(),
+        event,
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    let result = serde_json::to_writer(&mut stdout, &timed)
+        .map_err(std::io::Error::from)
+        .and_then(|_| stdout.write_all(b"\n"))
+        .and_then(|_| stdout.flush());
+
+    if let Err(error) = result {
+        log::warn!("Unable to write progress event: {}", error);
+    }
+}
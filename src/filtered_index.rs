@@ -0,0 +1,164 @@
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::Result;
+use std::path::Path;
+
+use crate::paths;
+use crate::permissions::Permissions;
+
+/// A sparse registry index with only the crate versions in the mirror, served as
+/// `sparse+<url>/filtered-index/`.
+pub const FILTERED_INDEX_PATH: &str = "/filtered-index";
+
+/// A line of a crate file in the index. Only the fields needed to find the mirrored file are parsed,
+/// the lines themselves are copied as they are.
+#[derive(Debug, Deserialize)]
+struct IndexVersion {
+    name: String,
+    vers: String,
+}
+
+/// Writes an index to `target` with only the crate versions of the index at `commit` (in the clone at
+/// `index_path`) that are in the mirror, and with `config.json` pointing cargo at the crates of the mirror served at `url` (if any).
+/// Returns the (name, version) of the crate versions in it.
+pub fn write(
+    output_directory: &str,
+    index_path: &Path,
+    permissions: &Permissions,
+    commit: &str,
+    target: &Path,
+    url: Option<&str>,
+) -> Result<Vec<(String, String)>> {
+    let repo = Repository::open(index_path).map_err(std::io::Error::other)?;
+    let tree = Oid::from_str(commit)
+        .and_then(|oid| repo.find_commit(oid))
+        .and_then(|commit| commit.tree())
+        .map_err(std::io::Error::other)?;
+
+    // The crate files are in `1/`, `2/`, `3/a/` and `ab/cd/`, next to `config.json` and dot-directories.
+    let mut files = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if root.starts_with('.') {
+            return TreeWalkResult::Skip;
+        }
+        if !root.is_empty() && entry.kind() == Some(ObjectType::Blob) {
+            if let Some(name) = entry.name() {
+                files.push((format!("{}{}", root, name), entry.id()));
+            }
+        }
+        TreeWalkResult::Ok
+    })
+    .map_err(std::io::Error::other)?;
+
+    // Write the new index next to the old one, so it can be swapped in at once.
+    let staging = target.with_extension("tmp");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    permissions.create_dir_all(&staging)?;
+
+    let mut versions = Vec::new();
+    for (path, id) in files {
+        let blob = repo.find_blob(id).map_err(std::io::Error::other)?;
+        let mut contents = Vec::new();
+
+        for line in blob.content().split(|b| *b == b'\n') {
+            let mirrored = serde_json::from_slice::<IndexVersion>(line)
+                .ok()
+                .filter(|version| {
+                    let crate_path = format!(
+                        "/crates/{}/{}-{}.crate",
+                        version.name, version.name, version.vers
+                    );
+                    paths::local_path(output_directory, &crate_path).exists()
+                });
+
+            if let Some(version) = mirrored {
+                contents.extend_from_slice(line);
+                contents.push(b'\n');
+                versions.push((version.name, version.vers));
+            }
+        }
+
+        if !contents.is_empty() {
+            write_file(permissions, &staging.join(path), &contents)?;
+        }
+    }
+
+    if let Some(url) = url {
+        let config = serde_json::json!({
+            "dl": format!("{}/crates/{{crate}}/{{crate}}-{{version}}.crate", url.trim_end_matches('/')),
+        });
+        write_file(
+            permissions,
+            &staging.join("config.json"),
+            &serde_json::to_vec_pretty(&config)?,
+        )?;
+    }
+
+    if target.exists() {
+        std::fs::remove_dir_all(target)?;
+    }
+    std::fs::rename(staging, target)?;
+
+    Ok(versions)
+}
+
+/// Writes a directory for cargo's `local-registry` source replacement: the index of the mirrored crate
+/// versions in `index/`, next to the `.crate` files (hard links to the mirror where possible). Returns
+/// the number of crate versions in it.
+pub fn write_local_registry(
+    output_directory: &str,
+    index_path: &Path,
+    permissions: &Permissions,
+    commit: &str,
+    directory: &Path,
+) -> Result<usize> {
+    permissions.create_dir_all(directory)?;
+    let versions = write(
+        output_directory,
+        index_path,
+        permissions,
+        commit,
+        &directory.join("index"),
+        None,
+    )?;
+
+    let mut files = HashSet::new();
+    for (name, version) in &versions {
+        let file_name = format!("{}-{}.crate", name, version);
+        let target = directory.join(&file_name);
+
+        if !target.exists() {
+            let source =
+                paths::local_path(output_directory, &format!("/crates/{}/{}", name, file_name));
+            // Fall back to copying when the directory is on another file system.
+            if std::fs::hard_link(&source, &target).is_err() {
+                std::fs::copy(&source, &target)?;
+                permissions.apply_file(&target)?;
+            }
+        }
+
+        files.insert(file_name);
+    }
+
+    // Remove the crate versions that are no longer in the mirror.
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.ends_with(".crate") && !files.contains(&file_name) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(versions.len())
+}
+
+fn write_file(permissions: &Permissions, path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        permissions.create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents)?;
+    permissions.apply_file(path)
+}
@@ -0,0 +1,228 @@
+use clap::ArgMatches;
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use url::Url;
+
+use crate::download::{fingerprint, num_cpus, sha256_file};
+use crate::manifest::Manifest;
+use crate::paths::local_path;
+use crate::scan;
+use crate::state::{State, STATE_FILE};
+
+/// The directories whose files are listed; the index is metadata rather than mirrored contents.
+const DIRECTORIES: [&str; 4] = ["rustup", "dist", "crates", "quickinstall"];
+
+/// A file of the mirror. `name`, `version` and `target` are empty when they are not known, e.g. for
+/// the channel manifests.
+#[derive(Debug, Default, Serialize)]
+struct Item {
+    /// `toolchain`, `rustup`, `crate`, `tool` or `other`.
+    kind: &'static str,
+    name: String,
+    version: String,
+    target: String,
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// The component, version and target of each file referenced by a channel manifest in the mirror
+/// (including the archived manifests of earlier releases), by mirror path.
+fn toolchain_files(
+    output_directory: &str,
+    files: &HashMap<String, u64>,
+) -> HashMap<String, (String, String, String)> {
+    let mut toolchain = HashMap::new();
+
+    let manifests = files.keys().filter(|path| {
+        path.starts_with("/dist/")
+            && path
+                .rsplit('/')
+                .next()
+                .unwrap()
+                .starts_with("channel-rust-")
+            && path.ends_with(".toml")
+    });
+    for path in manifests {
+        let manifest = match Manifest::load(&local_path(output_directory, path)) {
+            Ok(manifest) => manifest,
+            Err(error) => {
+                log::warn!("Unable to read {}: {}", path, error);
+                continue;
+            }
+        };
+
+        let version = |name: &str| {
+            manifest
+                .pkg
+                .get(name)
+                .or_else(|| manifest.pkg.get("rust"))
+                .and_then(|pkg| pkg.version.split(' ').next())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let mut insert = |name: &str, target: &str, url: &str| {
+            if let Ok(url) = Url::parse(url) {
+                toolchain
+                    .entry(url.path().to_string())
+                    .or_insert_with(|| (name.to_string(), version(name), target.to_string()));
+            }
+        };
+
+        for (name, pkg) in &manifest.pkg {
+            for (target, t) in &pkg.target {
+                for url in t.url.iter().chain(t.xz_url.iter()) {
+                    insert(name, target, url);
+                }
+            }
+        }
+        for (name, artifact) in &manifest.artifacts {
+            for (target, files) in &artifact.target {
+                for file in files {
+                    insert(name, target, &file.url);
+                }
+            }
+        }
+    }
+
+    toolchain
+}
+
+/// Splits `<name>-<version>` at the dash that is followed by a semantic version.
+fn split_version(name_version: &str) -> Option<(&str, &str)> {
+    name_version
+        .match_indices('-')
+        .map(|(i, _)| (&name_version[..i], &name_version[i + 1..]))
+        .find(|(_, version)| semver::Version::parse(version).is_ok())
+}
+
+/// Tells what a file of the mirror is from its path, and the manifests for the toolchain.
+fn describe(
+    path: &str,
+    toolchain: &HashMap<String, (String, String, String)>,
+    rustup_version: &str,
+) -> Item {
+    let item = |kind, name: &str, version: &str, target: &str| Item {
+        kind,
+        name: name.to_string(),
+        version: version.to_string(),
+        target: target.to_string(),
+        path: path.to_string(),
+        ..Default::default()
+    };
+
+    if let Some((name, version, target)) = toolchain.get(path) {
+        return item("toolchain", name, version, target);
+    }
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["rustup", "dist", target, file] => item(
+            "rustup",
+            file.trim_end_matches(".exe"),
+            rustup_version,
+            target,
+        ),
+        ["rustup", "archive", version, target, file] => {
+            item("rustup", file.trim_end_matches(".exe"), version, target)
+        }
+        ["crates", name, file] => match file
+            .strip_prefix(&format!("{}-", name))
+            .and_then(|file| file.strip_suffix(".crate"))
+        {
+            Some(version) => item("crate", name, version, ""),
+            None => item("other", "", "", ""),
+        },
+        ["quickinstall", release, file] => match split_version(release) {
+            Some((name, version)) => {
+                let target = file
+                    .strip_prefix(&format!("{}-", release))
+                    .and_then(|file| file.strip_suffix(".tar.gz"))
+                    .unwrap_or_default();
+                item("tool", name, version, target)
+            }
+            None => item("other", "", "", ""),
+        },
+        _ => item("other", "", "", ""),
+    }
+}
+
+/// The SHA-256 digest of a file, from the state if it was verified and has not changed since.
+fn sha256(output_directory: &str, state: &State, path: &str) -> Result<String> {
+    let path_buf = local_path(output_directory, path);
+    let unchanged = state.verified(path).filter(|verified| {
+        std::fs::metadata(&path_buf)
+            .ok()
+            .and_then(|metadata| fingerprint(&metadata))
+            == Some((verified.size, verified.modified))
+    });
+
+    match unchanged {
+        Some(verified) => Ok(verified.sha256),
+        None => Ok(hex::encode(sha256_file(&path_buf)?)),
+    }
+}
+
+/// Lists every file of a mirror (toolchain archives, rustup executables, crates and prebuilt tools)
+/// with what it is, its size and its SHA-256 digest, as CSV or JSON. Files that were not verified by
+/// a sync are hashed.
+pub async fn run(matches: &ArgMatches) -> Result<()> {
+    let output_directory = matches.value_of("OUTPUT-DIRECTORY").unwrap();
+
+    if !Path::new(output_directory).join(STATE_FILE).exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} has not been synced yet.", output_directory),
+        ));
+    }
+
+    let state = std::sync::Arc::new(State::open(output_directory)?);
+    let files = scan::scan(output_directory, &DIRECTORIES).await?;
+    let toolchain = toolchain_files(output_directory, &files);
+    let rustup_version =
+        std::fs::read_to_string(local_path(output_directory, "/rustup/release-stable.toml"))
+            .ok()
+            .and_then(|release| crate::rustup_release_version(&release))
+            .unwrap_or_default();
+
+    let mut paths: Vec<(String, u64)> = files
+        .into_iter()
+        .filter(|(path, _)| !path.ends_with(".sha256") && !path.ends_with(".asc"))
+        .collect();
+    paths.sort();
+
+    let items: Vec<Item> = stream::iter(paths)
+        .map(|(path, size)| {
+            let mut item = describe(&path, &toolchain, &rustup_version);
+            item.size = size;
+
+            let output_directory = output_directory.to_string();
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || {
+                item.sha256 = sha256(&output_directory, &state, &item.path)?;
+                Ok::<_, Error>(item)
+            })
+        })
+        .buffered(num_cpus())
+        .map(|result| result.map_err(Error::other).and_then(|item| item))
+        .collect::<Vec<Result<Item>>>()
+        .await
+        .into_iter()
+        .collect::<Result<_>>()?;
+
+    match matches.value_of("format").unwrap() {
+        "json" => println!("{}", serde_json::to_string_pretty(&items)?),
+        _ => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for item in &items {
+                writer.serialize(item).map_err(Error::other)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const JOURNAL_FILE: &str = ".squire/journal.json";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Progress {
+    /// Sections (`rustup`, `dist:<channel>`, `crates`) that have been completed.
+    completed: Vec<String>,
+    /// Number of crate versions, in index order, that have been processed by the crates pass.
+    crates_position: usize,
+    /// Name of the crate at `crates_position`, used when the index has changed since.
+    #[serde(default)]
+    crates_name: Option<String>,
+    /// Whether the sync only fetched the manifests, in which case its progress does not apply to a
+    /// full sync (and the other way around).
+    #[serde(default)]
+    manifests_only: bool,
+}
+
+/// Records the progress of a sync so that an interrupted run (crash, reboot, ...) can be resumed by the
+/// next run. The journal is removed once a sync completes.
+pub struct Journal {
+    path: PathBuf,
+    progress: Mutex<Progress>,
+}
+
+impl Journal {
+    /// Opens the journal of the output directory. If `resume` is false, any previous progress is
+    /// discarded.
+    pub fn open(output_directory: &str, resume: bool, manifests_only: bool) -> Result<Self> {
+        let path = Path::new(output_directory).join(JOURNAL_FILE);
+
+        let previous = if resume && path.exists() {
+            let progress: Progress = serde_json::from_slice(&std::fs::read(&path)?)?;
+            Some(progress).filter(|progress| progress.manifests_only == manifests_only)
+        } else {
+            None
+        };
+
+        let progress = if let Some(progress) = previous {
+            log::info!(
+                "Resuming interrupted sync (completed: [{}], crates position: {})...",
+                progress.completed.join(", "),
+                progress.crates_position
+            );
+            progress
+        } else {
+            Progress {
+                manifests_only,
+                ..Default::default()
+            }
+        };
+
+        Ok(Self {
+            path,
+            progress: Mutex::new(progress),
+        })
+    }
+
+    pub fn is_completed(&self, section: &str) -> bool {
+        let progress = self.progress.lock().unwrap();
+        progress.completed.iter().any(|s| s == section)
+    }
+
+    pub fn complete(&self, section: &str) -> Result<()> {
+        let mut progress = self.progress.lock().unwrap();
+        progress.completed.push(section.to_string());
+        self.write(&progress)
+    }
+
+    pub fn crates_position(&self) -> usize {
+        self.progress.lock().unwrap().crates_position
+    }
+
+    pub fn crates_name(&self) -> Option<String> {
+        self.progress.lock().unwrap().crates_name.clone()
+    }
+
+    pub fn set_crates_position(&self, position: usize, name: Option<&str>) -> Result<()> {
+        let mut progress = self.progress.lock().unwrap();
+        progress.crates_position = position;
+        progress.crates_name = name.map(str::to_string);
+        self.write(&progress)
+    }
+
+    /// Removes the journal after a successful sync.
+    pub fn finish(self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&self, progress: &Progress) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Write to a temporary file and rename it, so a crash never leaves a half-written journal.
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(progress)?)?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
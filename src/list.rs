@@ -0,0 +1,131 @@
+use clap::ArgMatches;
+use futures_util::{stream, StreamExt};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::io::Result;
+
+use crate::format_size;
+use crate::manifest::Manifest;
+
+const CONCURRENCY: usize = 16;
+
+/// Fetches the sizes of `urls` using HEAD requests. URLs whose size is unknown are left out.
+pub async fn sizes(client: &Client, urls: Vec<String>) -> HashMap<String, u64> {
+    stream::iter(urls)
+        .map(|url| async move {
+            let size = client
+                .head(&url)
+                .send()
+                .await
+                .ok()
+                .filter(|res| res.status().is_success())
+                .and_then(|res| {
+                    res.headers()
+                        .get(CONTENT_LENGTH)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse::<u64>().ok())
+                });
+            (url, size)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .filter_map(|(url, size)| async move { size.map(|size| (url, size)) })
+        .collect()
+        .await
+}
+
+async fn targets(client: &Client, matches: &ArgMatches) -> Result<()> {
+    let channel = matches.value_of("channel").unwrap();
+    let manifest = Manifest::fetch(client, channel).await?;
+
+    println!("Targets available in {} ({}):", channel, manifest.date);
+
+    let targets = manifest.targets();
+    let sizes = if matches.is_present("sizes") {
+        let urls = targets
+            .iter()
+            .flat_map(|target| manifest.components(target))
+            .filter_map(|(_, _, t)| t.xz_url.clone().or_else(|| t.url.clone()))
+            .collect();
+        Some(sizes(client, urls).await)
+    } else {
+        None
+    };
+
+    for target in targets {
+        let components = manifest.components(target);
+
+        match &sizes {
+            Some(sizes) => {
+                let size: u64 = components
+                    .iter()
+                    .filter_map(|(_, _, t)| t.xz_url.as_ref().or(t.url.as_ref()))
+                    .filter_map(|url| sizes.get(url))
+                    .sum();
+                println!(
+                    "{:<40} {:>3} components {:>12}",
+                    target,
+                    components.len(),
+                    format_size(size)
+                );
+            }
+            None => println!("{:<40} {:>3} components", target, components.len()),
+        }
+    }
+
+    Ok(())
+}
+
+async fn components(client: &Client, matches: &ArgMatches) -> Result<()> {
+    let channel = matches.value_of("channel").unwrap();
+    let target = matches.value_of("target").unwrap();
+    let manifest = Manifest::fetch(client, channel).await?;
+
+    let components = manifest.components(target);
+    if components.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("No components available for {} in {}.", target, channel),
+        ));
+    }
+
+    let urls = components
+        .iter()
+        .filter_map(|(_, _, t)| t.xz_url.clone().or_else(|| t.url.clone()))
+        .collect();
+    let sizes = sizes(client, urls).await;
+
+    println!(
+        "Components available for {} in {} ({}):",
+        target, channel, manifest.date
+    );
+
+    for (name, pkg, t) in components {
+        let size = t
+            .xz_url
+            .as_ref()
+            .or(t.url.as_ref())
+            .and_then(|url| sizes.get(url))
+            .map_or_else(|| "-".to_string(), |size| format_size(*size));
+        let version = pkg.version.split(' ').next().unwrap_or_default();
+
+        let aliases = manifest.aliases(name);
+        let name = if aliases.is_empty() {
+            name.to_string()
+        } else {
+            format!("{} ({})", name, aliases.join(", "))
+        };
+
+        println!("{:<52} {:<12} {:>12}", name, version, size);
+    }
+
+    Ok(())
+}
+
+pub async fn run(client: &Client, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("targets", matches)) => targets(client, matches).await,
+        Some(("components", matches)) => components(client, matches).await,
+        _ => unreachable!(), // Covered by `SubcommandRequiredElseHelp`.
+    }
+}
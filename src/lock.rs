@@ -0,0 +1,69 @@
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const LOCK_FILE: &str = ".squire.lock";
+
+/// An advisory lock on the output directory, held until dropped. Prevents overlapping runs (e.g. from
+/// cron) from corrupting the index clone and racing on the same files.
+pub struct DirectoryLock {
+    file: File,
+}
+
+impl DirectoryLock {
+    /// Takes the lock, failing immediately if another process holds it unless `wait` is set.
+    pub async fn acquire(output_directory: &str, wait: bool) -> Result<Self> {
+        std::fs::create_dir_all(output_directory)?;
+
+        let path = Path::new(output_directory).join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if let Err(error) = file.try_lock_exclusive() {
+            let holder = std::fs::read_to_string(&path).unwrap_or_default();
+            let holder = holder.trim();
+
+            if !wait {
+                return Err(std::io::Error::new(
+                    error.kind(),
+                    format!(
+                        "Another sync (pid {}) is in progress in {}. Use --wait-lock to wait for it to finish.",
+                        if holder.is_empty() { "unknown" } else { holder },
+                        output_directory
+                    ),
+                ));
+            }
+
+            log::info!(
+                "Waiting for another sync (pid {}) to release {}...",
+                if holder.is_empty() { "unknown" } else { holder },
+                path.display()
+            );
+
+            let file = file.try_clone()?;
+            tokio::task::spawn_blocking(move || file.lock_exclusive())
+                .await
+                .map_err(std::io::Error::other)??;
+        }
+
+        let mut lock = Self { file };
+        lock.file.set_len(0)?;
+        lock.file.seek(SeekFrom::Start(0))?;
+        write!(lock.file, "{}", std::process::id())?;
+        lock.file.flush()?;
+
+        Ok(lock)
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = FileExt::unlock(&self.file);
+    }
+}
@@ -0,0 +1,262 @@
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use simple_logger::SimpleLogger;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Number of console log lines kept while they are captured.
+const CAPTURED_LINES: usize = 100;
+
+/// While the dashboard is shown, console log lines are kept for it instead of being printed.
+static CAPTURE: AtomicBool = AtomicBool::new(false);
+static CAPTURED: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// While progress bars are shown, console log lines are printed with this, above the bars.
+type Printer = Box<dyn Fn(&str) + Send>;
+static PRINTER: Mutex<Option<Printer>> = Mutex::new(None);
+
+/// Prints the console log lines with `printer` instead of to stdout, until it is reset with `None`.
+pub fn print_with(printer: Option<Printer>) {
+    *PRINTER.lock().unwrap() = printer;
+}
+
+/// Starts or stops capturing the console log lines.
+pub fn capture(enabled: bool) {
+    if enabled {
+        CAPTURED.lock().unwrap().clear();
+    }
+    CAPTURE.store(enabled, Ordering::Relaxed);
+}
+
+/// The most recent captured console log lines, oldest first.
+pub fn captured(lines: usize) -> Vec<String> {
+    let captured = CAPTURED.lock().unwrap();
+    captured
+        .iter()
+        .skip(captured.len().saturating_sub(lines))
+        .cloned()
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn period(&self) -> String {
+        match self {
+            Rotation::Never => String::new(),
+            Rotation::Hourly => Local::now().format("%Y-%m-%d %H").to_string(),
+            Rotation::Daily => Local::now().format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(format!("Unknown rotation: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub level: LevelFilter,
+    pub max_size: Option<u64>,
+    pub rotation: Rotation,
+    pub keep: usize,
+}
+
+/// A log file that is rotated to `<path>.1`, `<path>.2`, ... once it exceeds its maximum size or
+/// the current rotation period (hour/day) has passed. Only `keep` rotated files are retained.
+struct RotatingFile {
+    config: LogFile,
+    file: File,
+    size: u64,
+    period: String,
+}
+
+impl RotatingFile {
+    fn open(config: LogFile) -> Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+        let period = config.rotation.period();
+
+        Ok(Self {
+            config,
+            file,
+            size,
+            period,
+        })
+    }
+
+    fn rotated_path(path: &Path, n: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let path = &self.config.path;
+
+        if self.config.keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            let _ = std::fs::remove_file(Self::rotated_path(path, self.config.keep));
+            for n in (1..self.config.keep).rev() {
+                let from = Self::rotated_path(path, n);
+                if from.exists() {
+                    std::fs::rename(from, Self::rotated_path(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, Self::rotated_path(path, 1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        self.period = self.config.rotation.period();
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let exceeds_size = self
+            .config
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+
+        if exceeds_size || self.period != self.config.rotation.period() {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Logs to stdout (through `SimpleLogger`) and, optionally, to a rotating log file with its own level.
+struct Logger {
+    console: SimpleLogger,
+    console_level: LevelFilter,
+    /// Write the console log lines to stderr instead of stdout.
+    stderr: bool,
+    file: Option<(LevelFilter, Mutex<RotatingFile>)>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.console_level
+            || self
+                .file
+                .as_ref()
+                .is_some_and(|(level, _)| metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.console_level {
+            if CAPTURE.load(Ordering::Relaxed) {
+                let mut captured = CAPTURED.lock().unwrap();
+                if captured.len() == CAPTURED_LINES {
+                    captured.pop_front();
+                }
+                captured.push_back(format!(
+                    "{} {:<5} {}",
+                    Local::now().format("%H:%M:%S"),
+                    record.level(),
+                    record.args()
+                ));
+            } else if let Some(printer) = &*PRINTER.lock().unwrap() {
+                printer(&format!(
+                    "{} {:<5} [{}] {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ));
+            } else if self.stderr {
+                eprintln!(
+                    "{} {:<5} [{}] {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+            } else {
+                self.console.log(record);
+            }
+        }
+
+        if let Some((level, file)) = &self.file {
+            if record.level() <= *level {
+                let line = format!(
+                    "{} {:<5} [{}] {}\n",
+                    Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+
+                if let Ok(mut file) = file.lock() {
+                    if let Err(error) = file.write_line(&line) {
+                        eprintln!("Unable to write to log file: {}", error);
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, file)) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+pub fn init(console_level: LevelFilter, log_file: Option<LogFile>, stderr: bool) -> Result<()> {
+    let file = match log_file {
+        Some(config) => Some((config.level, Mutex::new(RotatingFile::open(config)?))),
+        None => None,
+    };
+
+    let max_level = file
+        .as_ref()
+        .map_or(console_level, |(level, _)| console_level.max(*level));
+
+    let logger = Logger {
+        console: SimpleLogger::new().with_level(console_level),
+        console_level,
+        stderr,
+        file,
+    };
+
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|err: SetLoggerError| std::io::Error::other(err.to_string()))?;
+    log::set_max_level(max_level);
+
+    Ok(())
+}
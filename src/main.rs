@@ -1,104 +1,395 @@
-use clap::{App, Arg};
-use crates_index::BareIndex;
+use clap::{App, AppSettings, Arg, ArgMatches, ArgSettings};
+use crates_index::{BareIndex, BareIndexRepo};
 use futures_util::{stream, StreamExt};
 use log::LevelFilter;
 use regex::Regex;
 use reqwest::Client;
-use sha2::{Digest, Sha256};
-use simple_logger::SimpleLogger;
-use std::collections::HashSet;
-use std::convert::TryInto;
-use std::io::{Result, Write};
-use std::path::PathBuf;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 use url::Url;
 
-const RUSTLANG_ROOT_URL: &'static str = "https://static.rust-lang.org";
-const CRATES_ROOT_URL: &'static str = "https://static.crates.io";
+mod binstall;
+mod changes;
+mod check_upstream;
+mod completions;
+mod components_history;
+mod config;
+mod crate_list;
+mod db_dump;
+mod dependencies;
+mod diff;
+mod doctor;
+mod download;
+mod estimate;
+mod events;
+mod filtered_index;
+mod inventory;
+mod journal;
+mod list;
+mod lock;
+mod logging;
+mod manifest;
+mod migrate;
+mod otel;
+mod paths;
+mod permissions;
+mod progress;
+mod projects;
+mod report;
+mod scan;
+mod state;
+mod status;
+mod systemd;
+mod targets;
+mod tui;
+mod window;
+
+use download::{Downloader, Outcome, Overwrite, CRATES_ROOT_URL, RUSTLANG_ROOT_URL};
+use journal::Journal;
+use manifest::Manifest;
+
 const CRATES_INDEX_URL: &str = "https://github.com/rust-lang/crates.io-index";
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Overwrite {
-    True,
-    False,
-    Checksum([u8; 32]),
-}
+/// The components left out by `--no-docs`.
+const DOC_COMPONENTS: [&str; 2] = ["rust-docs", "rust-docs-json"];
 
-async fn download(
-    http_client: &Client,
-    output_directory: &str,
-    path: &str,
-    overwrite: Overwrite,
-) -> Result<()> {
-    let url = if path.ends_with(".crate") {
-        format!("{}{}", CRATES_ROOT_URL, path)
+/// The concurrent requests to static.rust-lang.org and static.crates.io unless `--concurrency` (or
+/// their own option) is given. The toolchain archives are few and large, so fewer requests keep it
+/// busy than for the many small crates.
+const DIST_CONCURRENCY: usize = 4;
+const CRATES_CONCURRENCY: usize = 16;
+
+/// Exit status of a sync in which more downloads failed than `--fail-on-errors` allows. Other errors
+/// exit with 1.
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+
+/// Formats a number of bytes for humans, e.g. `1.5 GiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
     } else {
-        format!("{}{}", RUSTLANG_ROOT_URL, path)
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Parses a size such as `1024`, `512KiB`, `10MiB`, `200GiB` or `1TB` into a number of bytes.
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {}", s))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1 << 30,
+        "t" | "tb" => 1000 * 1000 * 1000 * 1000,
+        "tib" => 1 << 40,
+        _ => return Err(format!("Invalid size unit: {}", unit)),
     };
 
-    let path_buf = PathBuf::from(format!("{}{}", output_directory, path));
+    Ok((number * multiplier as f64) as u64)
+}
 
-    let download = overwrite == Overwrite::True
-        || !path_buf.exists()
-        || !(match overwrite {
-            Overwrite::Checksum(checksum) => {
-                let bytes = std::fs::read(&path_buf)?;
-                let digest: [u8; 32] = Sha256::digest(&bytes).as_slice().try_into().unwrap();
-                checksum == digest
-            }
-            Overwrite::False => true,
-            Overwrite::True => unreachable!(), // Convered by short-circuit in first clause.
-        });
+/// The value of a numeric option, e.g. `--log-file-keep`.
+fn number_arg<T>(matches: &ArgMatches, name: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    matches.value_of_t(name).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--{} must be a number.", name),
+        )
+    })
+}
 
-    if download {
-        log::info!("Downloading {}...", url);
-        match http_client.get(&url).send().await {
-            Ok(res) => {
-                log::debug!("Writing file {}...", path_buf.display());
+/// The value of a numeric option that must be at least 1, e.g. `--segments`.
+fn positive_arg(matches: &ArgMatches, name: &str) -> Result<usize> {
+    match number_arg(matches, name)? {
+        0 => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--{} must be at least 1.", name),
+        )),
+        value => Ok(value),
+    }
+}
 
-                if let Some(path) = path_buf.parent() {
-                    std::fs::create_dir_all(path)?;
-                }
+/// The `--timeout` of the requests.
+fn timeout_arg(matches: &ArgMatches) -> Result<Duration> {
+    Ok(Duration::from_secs(number_arg(matches, "timeout")?))
+}
 
-                let mut stream = res.bytes_stream();
-                let mut file = std::fs::File::create(path_buf)?;
+/// Parses a duration such as `90s`, `30m`, `6h` or `1d`. A plain number is a number of seconds.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
 
-                while let Some(Ok(bytes)) = stream.next().await {
-                    file.write(&bytes)?;
-                }
-            }
-            Err(error) => {
-                log::warn!("Error downloading file: {}", url);
-                log::debug!("{}", error);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", s))?;
+
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Invalid duration unit: {}", unit)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration too long: {}", s))
+}
+
+/// Adds the options given as `SQUIRE_*` environment variables to the command line arguments, e.g.
+/// `SQUIRE_MAX_FAILURES=100` for `--max-failures 100` or `SQUIRE_FSYNC=1` for `--fsync`. Options given
+/// on the command line take precedence.
+fn env_args(app: &App) -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let given = |arg: &Arg| {
+        args.iter().filter_map(|given| given.to_str()).any(|given| {
+            let long = arg.get_long().is_some_and(|long| {
+                given
+                    .strip_prefix("--")
+                    .and_then(|given| given.strip_prefix(long))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+            });
+            let short = arg.get_short().is_some_and(|short| {
+                !given.starts_with("--")
+                    && given
+                        .strip_prefix('-')
+                        .is_some_and(|given| given.starts_with(short))
+            });
+            long || short
+        })
+    };
+
+    let mut from_env = Vec::new();
+    for arg in app.get_arguments() {
+        let long = match arg.get_long() {
+            Some(long) => long,
+            None => continue,
+        };
+        let value = match std::env::var(format!(
+            "SQUIRE_{}",
+            long.to_ascii_uppercase().replace('-', "_")
+        )) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        if given(arg) {
+            continue;
+        }
+
+        if arg.is_set(ArgSettings::TakesValue) {
+            from_env.push(format!("--{}={}", long, value));
+        } else {
+            // Flags are set with 1 or true, and repeated ones (-vv) with a count.
+            let count = match value.to_ascii_lowercase().as_str() {
+                "" | "0" | "false" | "no" => 0,
+                "true" | "yes" => 1,
+                value => value.parse().unwrap_or(1),
+            };
+            from_env.extend(std::iter::repeat_n(format!("--{}", long), count));
+        }
+    }
+
+    args.splice(1..1, from_env.into_iter().map(OsString::from));
+    args
+}
+
+/// Keeps running, syncing the mirror every `interval` (measured from the start of each sync). A sync
+/// that fails is logged and retried at the next scheduled time, resuming where it stopped. With
+/// `--sync-window`, a sync that is due outside of the window waits for it to open. The config file is
+/// reloaded on SIGHUP, and used from the next sync on.
+async fn daemon(matches: &ArgMatches, http_client: Client, interval: Duration) -> Result<()> {
+    let mut profile = load_profile(matches)?;
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+
+    let window = sync_window(matches)?;
+
+    loop {
+        if let Some(wait) = window.as_ref().and_then(window::SyncWindow::until_open) {
+            let start = chrono::Local::now()
+                + chrono::Duration::from_std(wait).map_err(std::io::Error::other)?;
+            let start = start.format("%Y-%m-%d %H:%M:%S");
+            log::info!("Waiting for the sync window, next sync at {}.", start);
+            systemd::notify(&format!("STATUS=Idle, next sync at {}", start));
+            tokio::time::sleep(wait).await;
+        }
+
+        let next = tokio::time::Instant::now() + interval;
+        let next_time = chrono::Local::now()
+            + chrono::Duration::from_std(interval).map_err(std::io::Error::other)?;
+
+        if let Err(error) = sync(matches, http_client.clone(), profile.clone()).await {
+            log::error!("Sync failed: {}", error);
+        }
+
+        let next_time = next_time
+            .max(chrono::Local::now())
+            .format("%Y-%m-%d %H:%M:%S");
+        log::info!("Next sync at {}.", next_time);
+        systemd::notify(&format!("STATUS=Idle, next sync at {}", next_time));
+
+        // A SIGHUP received during the sync is handled here, before the next one.
+        #[cfg(unix)]
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => break,
+                _ = hangup.recv() => reload_profile(matches, &mut profile),
             }
         }
+        #[cfg(not(unix))]
+        tokio::time::sleep_until(next).await;
     }
+}
 
-    Ok(())
+/// Parses `--sync-window`.
+fn sync_window(matches: &ArgMatches) -> Result<Option<window::SyncWindow>> {
+    matches
+        .value_of("sync-window")
+        .map(window::SyncWindow::parse)
+        .transpose()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+}
+
+/// Reloads the config file for the next sync of the daemon. An invalid file is reported, and the
+/// previous config kept.
+#[cfg(unix)]
+fn reload_profile(matches: &ArgMatches, profile: &mut Option<config::Selection>) {
+    if !matches.is_present("config") {
+        return;
+    }
+
+    match load_profile(matches) {
+        Ok(reloaded) => {
+            log::info!("Reloaded the config file, it is used from the next sync.");
+            *profile = reloaded;
+        }
+        Err(error) => log::error!("Keeping the previous config: {}", error),
+    }
+}
+
+/// Loads the config file and selects the profiles given with `--profile`, if any. A config file
+/// without profiles to select would be silently ignored, so that is an error.
+fn load_profile(matches: &ArgMatches) -> Result<Option<config::Selection>> {
+    match matches.values_of("profile") {
+        Some(names) => {
+            let config = config::Config::load(Path::new(matches.value_of("config").unwrap()))?;
+            Ok(Some(config.select(&names.collect::<Vec<&str>>())?))
+        }
+        None if matches.is_present("config") => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--config requires --profile, e.g. --profile all.",
+        )),
+        None => Ok(None),
+    }
 }
 
+/// The version of rustup in a `release-stable.toml`.
+fn rustup_release_version(release: &str) -> Option<String> {
+    let release: toml::Value = release.parse().ok()?;
+    release.get("version")?.as_str().map(str::to_string)
+}
+
+/// Downloads the rustup executables. With a `version`, that release is mirrored instead of the latest
+/// one and `release-stable.toml` is pinned to it, so `rustup self update` does not go past it.
 async fn rustup(
-    http_client: &Client,
-    output_directory: &str,
+    downloader: &Downloader,
     concurrency: usize,
-    architectures: &Vec<String>,
+    architectures: &[String],
+    version: Option<&str>,
+    manifests_only: bool,
 ) -> Result<()> {
     log::info!("Downloading rustup executables...");
-    download(
-        http_client,
-        output_directory,
-        "/rustup/release-stable.toml",
-        Overwrite::True,
-    )
-    .await?;
+
+    let release_path = "/rustup/release-stable.toml";
+    let is_pinned = version.is_some();
+    let version = match version {
+        Some(version) => {
+            log::info!("Pinning rustup to version {}.", version);
+            let release = format!("schema-version = '1'\nversion = '{}'\n", version);
+            downloader.store(release_path, release.into_bytes()).await?;
+            version.to_string()
+        }
+        None => {
+            downloader.download(release_path, Overwrite::True).await?;
+            let release = std::fs::read_to_string(downloader.local_path(release_path))?;
+            rustup_release_version(&release).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Unable to read the rustup version from release-stable.toml.",
+                )
+            })?
+        }
+    };
+    let pinned = version.as_str();
+
+    if manifests_only {
+        log::info!(
+            "Skipping the rustup {} executables (--manifests-only).",
+            pinned
+        );
+        return Ok(());
+    }
 
     stream::iter(architectures.iter())
         .for_each_concurrent(concurrency, |arch| {
             let ext = if arch.contains("windows") { ".exe" } else { "" };
             let name = format!("rustup-init{}", ext);
-            let url = format!("/rustup/dist/{}/{}", arch, name);
+            let dist = format!("/rustup/dist/{}/{}", arch, name);
+            // Used by `rustup self update`.
+            let archive = format!("/rustup/archive/{}/{}/{}", pinned, arch, name);
 
             async move {
-                let _ = download(http_client, output_directory, &url, Overwrite::True).await;
+                let _ = downloader.download(&archive, Overwrite::False).await;
+
+                // The executables in dist are those of the latest (or pinned) release, so they are
+                // copied from its archive instead of being downloaded again.
+                let fingerprint = |path: &str| {
+                    std::fs::metadata(downloader.local_path(path))
+                        .ok()
+                        .and_then(|metadata| download::fingerprint(&metadata))
+                };
+                if !downloader.exists(&archive) && !is_pinned {
+                    let _ = downloader.download(&dist, Overwrite::True).await;
+                } else if fingerprint(&archive).is_none()
+                    || fingerprint(&archive) != fingerprint(&dist)
+                {
+                    if let Err(error) = downloader.copy(&archive, &dist).await {
+                        log::warn!("Unable to copy {} to {}: {}", archive, dist, error);
+                    }
+                }
             }
         })
         .await;
@@ -106,28 +397,16 @@ async fn rustup(
     Ok(())
 }
 
-async fn get_dist_archiectures(
-    http_client: &Client,
-    output_directory: &str,
-    channel: &str,
-) -> Result<Vec<String>> {
+async fn get_dist_archiectures(downloader: &Downloader, channel: &str) -> Result<Vec<String>> {
     log::info!(
         "Getting all available architectures for the Rust toolchain [channel-{}]...",
         channel
     );
 
-    download(
-        http_client,
-        output_directory,
-        &format!("/dist/channel-rust-{}.toml", channel),
-        Overwrite::True,
-    )
-    .await?;
+    let manifest_path = manifest::path(channel);
+    downloader.download(&manifest_path, Overwrite::True).await?;
 
-    let path = PathBuf::from(format!(
-        "{}/dist/channel-rust-{}.toml",
-        output_directory, channel
-    ));
+    let path = downloader.local_path(&manifest_path);
 
     let manifest = std::fs::read_to_string(path)?;
 
@@ -152,78 +431,171 @@ async fn get_dist_archiectures(
     Ok(architectures.into_iter().collect())
 }
 
+/// Downloads a file and its `.asc` and `.sha256` sidecars. When the `version` of the manifest that
+/// references the file is given, sidecars that were not found upstream for that version are not
+/// requested again. With `--local-sha256`, a missing `.sha256` sidecar is generated.
 async fn dist_download(
-    http_client: &Client,
-    output_directory: &str,
+    downloader: &Downloader,
     path: &str,
     overwrite: Overwrite,
+    version: Option<&str>,
 ) -> Result<()> {
-    download(http_client, output_directory, path, overwrite).await?;
-    download(
-        http_client,
-        output_directory,
-        &format!("{}.asc", path),
-        overwrite,
-    )
-    .await?;
-    download(
-        http_client,
-        output_directory,
-        &format!("{}.sha256", path),
-        overwrite,
-    )
-    .await
+    let file = downloader.download(path, overwrite).await?;
+
+    let state = downloader.state.as_ref().zip(version);
+
+    for extension in [".asc", ".sha256"] {
+        let sidecar = format!("{}{}", path, extension);
+
+        let outcome = match state {
+            Some((state, version)) if state.is_missing(&sidecar, version) => {
+                log::debug!("Skipping {}, it was not found upstream before.", sidecar);
+                Outcome::NotFound
+            }
+            _ => downloader.download(&sidecar, overwrite).await?,
+        };
+
+        if let Some((state, version)) = state {
+            if outcome == Outcome::NotFound {
+                state.set_missing(&sidecar, version);
+            }
+        }
+
+        if extension == ".sha256"
+            && outcome == Outcome::NotFound
+            && downloader.local_sha256
+            && matches!(file, Outcome::Downloaded | Outcome::Skipped)
+        {
+            downloader
+                .write_sha256(path, None, file == Outcome::Downloaded)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// How a channel is mirrored, besides the archives of its components for the selected targets.
+struct DistOptions<'a> {
+    /// Also mirror the files of the `[artifacts]` section.
+    artifacts: bool,
+    /// Also mirror the source tarball of the compiler.
+    rustc_source: bool,
+    /// Leave out the documentation components (`--no-docs`).
+    no_docs: bool,
+    /// Keep a copy of the manifest at the dated path it is published under.
+    archive_manifest: bool,
+    manifests_only: bool,
+    /// Components that are not built per target (e.g. rust-src) to mirror as well.
+    components: &'a [String],
+    /// Targets that only the standard library is mirrored for (`--std-targets`).
+    std_targets: &'a [String],
 }
 
 async fn dist(
-    http_client: &Client,
-    output_directory: &str,
+    downloader: &Downloader,
     concurrency: usize,
     channel: &str,
-    architectures: &Vec<String>,
+    architectures: &[String],
+    options: &DistOptions<'_>,
 ) -> Result<()> {
     log::info!("Downloading Rust toolchain [channel-{}]...", channel);
 
-    dist_download(
-        http_client,
-        output_directory,
-        &format!("/dist/channel-rust-{}.toml", channel),
-        Overwrite::True,
-    )
-    .await?;
-
-    let path = PathBuf::from(format!(
-        "{}/dist/channel-rust-{}.toml",
-        output_directory, channel
-    ));
-
-    let manifest = std::fs::read_to_string(path)?;
+    // Dated manifests never change once published.
+    let manifest_path = manifest::path(channel);
+    let overwrite = match manifest::dated(channel) {
+        Some(_) => Overwrite::False,
+        None => Overwrite::True,
+    };
+    dist_download(downloader, &manifest_path, overwrite, None).await?;
 
-    let pkg_urls: Vec<String> = manifest
-        .lines()
-        .filter_map(|line| {
-            let line = line.trim();
+    let manifest = Manifest::load(&downloader.local_path(&manifest_path))?;
 
-            if !line.starts_with("url") && !line.starts_with("xz_url") {
-                return None;
+    // Keep a copy of the manifest at the dated path it is published under, so the mirror retains
+    // every version of the channel and dated installs of it keep working.
+    if options.archive_manifest && manifest::dated(channel).is_none() {
+        let dated_path = manifest::path(&format!("{}-{}", channel, manifest.date));
+        for extension in ["", ".asc", ".sha256"] {
+            let from = format!("{}{}", manifest_path, extension);
+            let to = format!("{}{}", dated_path, extension);
+            if downloader.exists(&from) && !downloader.exists(&to) {
+                log::info!("Archiving {} as {}...", from, to);
+                downloader.copy(&from, &to).await?;
             }
+        }
+    }
 
-            let mut iter = line.chars();
+    let mut archives = manifest.archives(architectures);
+    if options.artifacts {
+        archives.extend(manifest.artifacts(architectures));
+    }
+    if options.rustc_source {
+        archives.extend(manifest.rustc_source());
+    }
+    archives.extend(manifest.std_archives(options.std_targets));
+    if options.no_docs {
+        // rust-docs-json is listed as rust-docs-json-preview.
+        archives.retain(|archive| {
+            !std::iter::once(archive.pkg.as_str())
+                .chain(manifest.aliases(&archive.pkg))
+                .any(|pkg| DOC_COMPONENTS.contains(&pkg))
+        });
+    }
+    archives.extend(manifest.target_independent(options.components));
 
-            iter.find(|c| *c == '"'); // Trim off characters from the front until the first double-quote.
-            let line = iter.as_str().trim_end_matches('"'); // Trim off double-quote at the end of the line.
+    // Channels recorded by earlier versions have no packages, which would all be reported as new.
+    let previous = downloader
+        .state
+        .as_ref()
+        .and_then(|state| state.channels().remove(channel))
+        .filter(|previous| previous.date != manifest.date && !previous.packages.is_empty());
+    if let Some(previous) = previous {
+        let mut additions = downloader.stats.additions.lock().unwrap();
+        for target in manifest.targets() {
+            if target == "*" || previous.available_targets.iter().any(|t| t == target) {
+                continue;
+            }
 
-            if !architectures.iter().any(|arch| line.contains(arch)) {
-                return None;
+            let mirrored = architectures
+                .iter()
+                .chain(options.std_targets)
+                .any(|selected| selected == target);
+            additions.push(format!(
+                "target {} in channel-{} ({})",
+                target,
+                channel,
+                if mirrored {
+                    "mirrored"
+                } else {
+                    "not selected by --targets"
+                }
+            ));
+        }
+        for package in manifest.pkg.keys() {
+            if previous.packages.contains(package) {
+                continue;
             }
 
-            let url = Url::parse(line).ok()?;
-            if &url.origin().ascii_serialization() == RUSTLANG_ROOT_URL {
+            let mirrored = archives.iter().any(|archive| &archive.pkg == package);
+            additions.push(format!(
+                "component {} in channel-{} ({})",
+                package,
+                channel,
+                if mirrored { "mirrored" } else { "not mirrored" }
+            ));
+        }
+    }
+
+    let mut pkg_urls: Vec<String> = archives
+        .into_iter()
+        .filter_map(|archive| {
+            let url = Url::parse(&archive.url).ok()?;
+            if url.origin().ascii_serialization() == RUSTLANG_ROOT_URL {
                 Some(url.path().to_string())
             } else {
                 log::warn!(
                     "Skipping URL ({}) in channel manifest that does not have this origin: {}",
-                    line,
+                    archive.url,
                     RUSTLANG_ROOT_URL
                 );
                 None
@@ -231,192 +603,2304 @@ async fn dist(
         })
         .collect();
 
+    // Some archives are shared by several targets (e.g. rust-docs), and concurrent downloads of the
+    // same file would write to the same temporary file.
+    let mut seen = HashSet::new();
+    pkg_urls.retain(|url| seen.insert(url.clone()));
+
+    // Components that are not built for a target (common on nightly) are marked as unavailable in
+    // the manifest, and are skipped rather than requested.
+    let unavailable = manifest.unavailable(architectures);
+    if !unavailable.is_empty() {
+        log::info!(
+            "Components unavailable in channel-{} ({}): {}",
+            channel,
+            manifest.date,
+            unavailable
+                .iter()
+                .map(|(pkg, target)| format!("{} [{}]", pkg, target))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+
+    if options.manifests_only {
+        log::info!(
+            "Skipping the {} archives of channel-{} (--manifests-only).",
+            pkg_urls.len(),
+            channel
+        );
+        return Ok(());
+    }
+
+    let date = manifest.date.as_str();
     let total = pkg_urls.len();
-    stream::iter(pkg_urls.iter().enumerate())
-        .for_each_concurrent(concurrency, |(i, url)| {
-            log::info!("Downloading – {}/{}", i + 1, total);
+    downloader.stats.total.store(total, Ordering::Relaxed);
+    stream::iter(
+        pkg_urls
+            .iter()
+            .enumerate()
+            .take_while(|_| !downloader.should_stop()),
+    )
+    .for_each_concurrent(concurrency, |(i, url)| {
+        log::info!("Downloading – {}/{}", i + 1, total);
 
-            let url = url.to_string();
-            async move {
-                let _ = dist_download(http_client, output_directory, &url, Overwrite::False).await;
-            }
-        })
-        .await;
+        let url = url.to_string();
+        async move {
+            let _ = dist_download(downloader, &url, Overwrite::False, Some(date)).await;
+            downloader.stats.done.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+    .await;
+
+    if let Some(state) = &downloader.state {
+        state.set_channel(
+            channel,
+            state::Channel {
+                date: manifest.date.clone(),
+                targets: architectures.to_vec(),
+                files: pkg_urls.clone(),
+                available_targets: manifest.targets().into_iter().map(str::to_string).collect(),
+                packages: manifest.pkg.keys().cloned().collect(),
+            },
+        );
+        if channel == "stable" {
+            state.set_stable_release(date, pkg_urls);
+        }
+        state.save()?;
+    }
 
     Ok(())
 }
 
+/// How the crates are mirrored, besides which crate versions are selected.
+struct CratesOptions<'a> {
+    /// Validate the checksums of existing crates.
+    validate_checksums: bool,
+    /// Skip the crates before this one.
+    start_from: Option<&'a str>,
+    /// Only mirror the crate versions added or changed since this index commit.
+    index_since: Option<&'a str>,
+    /// Download counts of the crates, to mirror the most downloaded crates first.
+    downloads: Option<HashMap<String, u64>>,
+    /// The database dump to read the crate versions from, instead of the index (`--db-dump-index`).
+    db_dump: Option<PathBuf>,
+}
+
 async fn crates(
-    http_client: &Client,
-    output_directory: &str,
+    downloader: &Downloader,
+    journal: &Journal,
     concurrency: usize,
-    validate_checksums: bool,
+    mut selection: crate_list::Selection,
+    options: CratesOptions<'_>,
 ) -> Result<()> {
-    let bare_index = BareIndex::with_path(
-        PathBuf::from(format!("{}/index", output_directory)),
-        CRATES_INDEX_URL,
-    );
+    let CratesOptions {
+        validate_checksums,
+        start_from,
+        index_since,
+        downloads,
+        db_dump,
+    } = options;
 
-    let mut index = bare_index
-    .open_or_clone()
-    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    // The crate versions come from the index, or from the database dump where git is not available.
+    let bare_index = downloader.bare_index();
+    let mut dump_entries = None;
+    let index = match db_dump {
+        Some(path) => {
+            log::info!("Reading the crate versions from the database dump...");
+            let explicit = selection.explicit.clone();
+            let entries = tokio::task::spawn_blocking(move || {
+                db_dump::crate_versions(&path, explicit.as_ref())
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+            dump_entries = Some(entries);
+            None
+        }
+        None => {
+            let index = retrieve_index(downloader, &bare_index)?;
+            let commit = crate_list::index_commit(&downloader.index_path())?;
+            Some((index, commit))
+        }
+    };
+    let cache = crate_list::Cache::new(&downloader.output_directory);
 
-    log::info!("Retrieving/updating crates.io-index...");
-    index
-        .retrieve()
-        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+    // Without a filter every crate is selected already, including all dependencies.
+    if let Some((index, _)) = index.as_ref().filter(|_| {
+        selection.with_dependencies
+            && (selection.filter.is_some()
+                || selection.names.is_some()
+                || selection.explicit.is_some())
+    }) {
+        selection = crate_list::Selection {
+            explicit: Some(dependencies::resolve(index, &selection)),
+            ..Default::default()
+        };
+    }
 
-    let crates = index
-        .crates()
-        .filter_map(|c| {
-            if c.versions().len() < 2 {
-                return None;
-            }
+    if let (Some(since), Some((_, commit))) = (index_since, &index) {
+        let changes = changes::Changes::between(&downloader.index_path(), since, commit)?;
+        log::info!(
+            "Only mirroring the {} crate versions added or changed since index commit {}.",
+            changes.added.len() + changes.unyanked.len() + changes.checksum_changed.len(),
+            since
+        );
 
-            Some(
-                c.versions()
-                    .iter()
-                    .filter(|v| !v.is_yanked())
-                    .map(|v| {
-                        (
-                            v.name().to_string(),
-                            v.version().to_string(),
-                            v.checksum().clone(),
-                        )
-                    })
-                    .collect::<Vec<(String, String, [u8; 32])>>(),
+        let changed: HashSet<(String, String)> = changes
+            .added
+            .into_iter()
+            .chain(changes.unyanked)
+            .map(|version| (version.name, version.version))
+            .chain(
+                changes
+                    .checksum_changed
+                    .into_iter()
+                    .map(|change| (change.name, change.version)),
             )
-        })
-        .flatten();
+            .collect();
+        selection.versions = Some(match selection.versions {
+            Some(versions) => versions.intersection(&changed).cloned().collect(),
+            None => changed,
+        });
+    }
 
-    stream::iter(crates.enumerate())
-        .for_each_concurrent(concurrency, |(i, (name, version, checksum))| async move {
-            let path = format!("/crates/{}/{}-{}.crate", name, name, version);
-            log::info!("Checking {}-{} – {}", name, version, i + 1);
+    // Reuse the flattened list of crate versions if the index has not changed since the last run,
+    // otherwise build it from the index while writing a new cache.
+    let mut writer = None;
+    let mut unchanged = false;
+    let crates: Box<dyn Iterator<Item = crate_list::Entry>> = match (&index, &selection.explicit) {
+        // The list leaves out yanked versions and crates with a single version, which the explicitly
+        // selected versions may be, so they are looked up in the index instead.
+        (Some((index, _)), Some(explicit)) => {
+            Box::new(crate_list::explicit_versions(index, explicit).into_iter())
+        }
+        (Some((index, commit)), None) => match cache.load(commit)? {
+            Some(entries) => {
+                log::info!("Using cached crate list for index commit {}.", commit);
+                unchanged = true;
+                Box::new(entries)
+            }
+            None => {
+                let cache_writer = writer.insert(cache.writer(commit)?);
+                Box::new(
+                    crate_list::from_index(index).inspect(move |entry| cache_writer.push(entry)),
+                )
+            }
+        },
+        (None, _) => Box::new(dump_entries.unwrap_or_default().into_iter()),
+    };
 
-            let overwrite = if validate_checksums {
-                Overwrite::Checksum(checksum)
-            } else {
-                Overwrite::False
-            };
+    // An interrupted pass is resumed at its position in the list. If the index (or the download
+    // counts the list is ordered by) may have changed since, positions may have shifted, so it is
+    // resumed from the crate at that position instead.
+    let start_from = match start_from {
+        Some(name) => Some(name.to_string()),
+        None if unchanged && downloads.is_none() => None,
+        None => journal.crates_name(),
+    };
+    let start = match start_from {
+        Some(_) => 0,
+        None => journal.crates_position(),
+    };
 
-            let _ = download(http_client, output_directory, &path, overwrite).await;
-        })
-        .await;
+    if let Some(name) = &start_from {
+        log::info!("Skipping crate versions until crate {}...", name);
+    } else if start > 0 {
+        log::info!(
+            "Skipping the first {} crate versions already processed...",
+            start
+        );
+    }
 
-    Ok(())
-}
+    // Hashing runs on its own pool, so allow enough tasks to keep both the hashers and the HTTP
+    // requests busy.
+    let tasks = if validate_checksums {
+        concurrency + downloader.hashers.available_permits()
+    } else {
+        concurrency
+    };
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let matches = App::new(env!("CARGO_PKG_NAME"))
-        .version(env!("CARGO_PKG_VERSION"))
-        .author(env!("CARGO_PKG_AUTHORS"))
-        .about(
-            "Downloads the Rust toolchain, the Crates package registry, and rustup for offline use.",
-        )
-        .arg(
-            Arg::new("channels")
-            .long("channels")
-            .short('d')
-            .default_values(&["stable"])
-            .about("Specify toolchain channels, versions or dates (possible values: stable|beta|nightly|<major.minor>|<major.minor.patch>|<YYYY-MM-DD>)."),
-        )
-        .arg(
-            Arg::new("verbose")
-            .long("verbose")
-            .short('v')
-            .about("Enable verbose mode."),
-        )
-        .arg(
-            Arg::new("targets")
-            .long("targets")
-            .short('t')
-            .default_value("x86_64")
-            .about("Include only toolchain distributions and rustup executables that match this regular expression. Use \"*\" to include rust-src."),
-        )
-        .arg(
-            Arg::new("concurrency")
-            .long("concurrency")
-            .short('c')
-            .default_value("5")
-            .about("Maximum number of concurrent HTTP-requests."),
-        )
-        .arg(
-            Arg::new("validate-checksums")
-            .long("validate-checksums")
-            .about("Enable checksum (SHA-256) validation of existing crate files.")
-        )
-        .arg(
-            Arg::new("user-agent")
-            .long("user-agent")
-            .default_value("squire (https://github.com/oskarbraten/squire)")
-        )
-        .arg(
-            Arg::new("OUTPUT-DIRECTORY")
-            .about("Specifies the output directory for the mirror.")
-            .required(true)
-            .index(1),
-        )
-        .get_matches();
+    // Filter after the crate list has been cached, so the cache always contains every crate.
+    let crates = crates.filter(|(name, version, _)| selection.contains(name, version));
 
-    SimpleLogger::new()
-        .with_level(if matches.is_present("verbose") {
-            LevelFilter::Debug
-        } else {
-            LevelFilter::Info
-        })
-        .init()
-        .unwrap();
+    // The most downloaded crates first, so a partial mirror is useful for most builds early. This
+    // holds the whole list in memory.
+    let crates: Box<dyn Iterator<Item = crate_list::Entry>> = match &downloads {
+        Some(downloads) => {
+            let mut entries: Vec<crate_list::Entry> = crates.collect();
+            entries.sort_by_key(|(name, _, _)| Reverse(downloads.get(name).copied().unwrap_or(0)));
+            log::info!("Ordered {} crate versions by downloads.", entries.len());
+            Box::new(entries.into_iter())
+        }
+        None => Box::new(crates),
+    };
 
-    let output_directory = matches.value_of("OUTPUT-DIRECTORY").unwrap();
-    let channels = matches.values_of("channels").unwrap();
-    let targets_regex = Regex::new(matches.value_of("targets").unwrap()).unwrap();
-    let concurrency: usize = matches.value_of_t("concurrency").unwrap();
-    let user_agent = matches.value_of("user-agent").unwrap();
-    let validate_checksums = matches.value_of("validate-checksums").is_some();
+    let mut found = start_from.is_none();
+    let crates = crates
+        .enumerate()
+        .skip(start)
+        .skip_while(|(_, (name, _, _))| start_from.as_ref().is_some_and(|start| start != name))
+        .inspect(|_| found = true);
 
-    let http_client = Client::builder()
-        .user_agent(user_agent)
-        .build()
-        .expect("Unable to build reqwest Client!");
+    // Stop starting downloads once the budget is exhausted or too many have failed, remembering the
+    // first crate version that was not started so the next run resumes there.
+    let mut stopped_at = None;
+    let crates = crates.take_while(|(i, (name, _, _))| {
+        let stop = downloader.should_stop();
+        if stop {
+            stopped_at = Some((*i, name.clone()));
+        }
+        !stop
+    });
 
-    // Filter architectures based on regex:
-    let architectures: Vec<String> =
-        get_dist_archiectures(&http_client, output_directory, "stable")
-            .await?
-            .into_iter()
-            .filter(|arch| targets_regex.is_match(arch))
-            .collect();
+    // The names of the last `tasks + 1` started crate versions, used to record the name of the crate
+    // at the position in the journal.
+    let recent = Mutex::new(VecDeque::with_capacity(tasks + 1));
 
-    log::info!(
-        "Selected architectures [channel-stable]: {}",
-        architectures.join(", ")
-    );
+    // The list is pulled lazily, so no more than `tasks` crate versions are held in memory at a time.
+    stream::iter(crates)
+        .for_each_concurrent(tasks, |(i, (name, version, checksum))| {
+            let checkpoint = {
+                let mut recent = recent.lock().unwrap();
+                recent.push_back(name.clone());
+                if recent.len() > tasks + 1 {
+                    recent.pop_front();
+                }
+                (recent.len() == tasks + 1)
+                    .then(|| recent.front().cloned())
+                    .flatten()
+            };
 
-    // Download rustup executables and manifest:
-    rustup(&http_client, output_directory, concurrency, &architectures).await?;
+            async move {
+                let path = format!("/crates/{}/{}-{}.crate", name, name, version);
+                log::debug!("Checking {}-{} – {}", name, version, i + 1);
 
-    // Download Rust toolchain(s) and channel manifest:
-    for channel in channels {
-        dist(
-            &http_client,
-            output_directory,
-            concurrency,
-            channel,
-            &architectures,
-        )
-        .await?;
+                // Everything before the last `tasks` started downloads is known to be finished.
+                if i % 1000 == 0 {
+                    if let Err(error) =
+                        journal.set_crates_position(i.saturating_sub(tasks), checkpoint.as_deref())
+                    {
+                        log::warn!("Unable to update journal: {}", error);
+                    }
+                }
+
+                // The digests should not all be lost if the run is interrupted.
+                if i % 100_000 == 0 {
+                    let saved = downloader
+                        .state
+                        .as_ref()
+                        .map(|state| tokio::task::block_in_place(|| state.save()));
+                    if let Some(Err(error)) = saved {
+                        log::warn!("Unable to save state: {}", error);
+                    }
+                }
+
+                let overwrite = if validate_checksums {
+                    Overwrite::Checksum(checksum)
+                } else {
+                    Overwrite::False
+                };
+
+                let _ = downloader.download_crate(&path, overwrite, checksum).await;
+                downloader.stats.done.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .await;
+
+    if !found {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "Crate {} is not in the index, use another crate or --restart.",
+                start_from.unwrap_or_default()
+            ),
+        ));
     }
 
-    // Download crate.io-index and crates:
-    crates(
-        &http_client,
-        output_directory,
-        concurrency,
-        validate_checksums,
-    )
-    .await?;
+    if let Some((position, name)) = &stopped_at {
+        log::info!("Stopped at crate version {} ({}).", position, name);
+        journal.set_crates_position(*position, Some(name))?;
+    }
+
+    // The rest of the list was never read from the index, so the cache would be incomplete.
+    if let Some(writer) = writer.filter(|_| stopped_at.is_none()) {
+        writer.finish()?;
+    }
+
+    if let Some(state) = &downloader.state {
+        state.save()?;
+    }
 
     Ok(())
 }
+
+/// Deletes the crate files of versions that have been yanked upstream, or moves them to `quarantine`
+/// (in the same layout as the mirror).
+async fn remove_yanked(downloader: &Downloader, quarantine: Option<&str>) -> Result<()> {
+    let bare_index = downloader.bare_index();
+    let index = bare_index.open_or_clone().map_err(std::io::Error::other)?;
+
+    log::info!("Removing yanked crate versions...");
+
+    let mut removed = 0;
+    for (name, version) in crate_list::yanked(&index) {
+        let path = format!("/crates/{}/{}-{}.crate", name, name, version);
+        let path_buf = downloader.local_path(&path);
+
+        if !path_buf.exists() {
+            continue;
+        }
+
+        match quarantine {
+            Some(quarantine) => {
+                let destination = paths::local_path(quarantine, &path);
+                log::info!("Quarantining yanked {}-{}...", name, version);
+                downloader
+                    .permissions
+                    .create_dir_all(destination.parent().unwrap())?;
+                // Fall back to copying when the quarantine is on another file system.
+                if std::fs::rename(&path_buf, &destination).is_err() {
+                    std::fs::copy(&path_buf, &destination)?;
+                    std::fs::remove_file(&path_buf)?;
+                }
+            }
+            None => {
+                log::info!("Removing yanked {}-{}...", name, version);
+                std::fs::remove_file(&path_buf)?;
+            }
+        }
+
+        // The sidecar generated by --local-sha256 goes with the crate.
+        let sidecar = format!("{}.sha256", path);
+        let sidecar_buf = downloader.local_path(&sidecar);
+        if sidecar_buf.exists() {
+            std::fs::remove_file(sidecar_buf)?;
+        }
+
+        for destination in &downloader.destinations {
+            for path in [&path, &sidecar] {
+                let copy = paths::local_path(destination, path);
+                if copy.exists() {
+                    std::fs::remove_file(copy)?;
+                }
+            }
+        }
+
+        removed += 1;
+    }
+
+    log::info!("Removed {} yanked crate versions.", removed);
+
+    Ok(())
+}
+
+/// Clones or updates the crates.io-index, unless it is maintained by another tool (`--index-path`).
+fn retrieve_index<'a>(
+    downloader: &Downloader,
+    bare_index: &'a BareIndex,
+) -> Result<BareIndexRepo<'a>> {
+    if let Some(path) = &downloader.index_path {
+        if !path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No crates.io-index clone at {}.", path.display()),
+            ));
+        }
+
+        log::info!("Using the crates.io-index at {}...", path.display());
+        return bare_index.open_or_clone().map_err(std::io::Error::other);
+    }
+
+    let mut index = bare_index.open_or_clone().map_err(std::io::Error::other)?;
+
+    log::info!(
+        "Retrieving/updating crates.io-index from {}...",
+        downloader.index_url
+    );
+    index
+        .retrieve()
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+    Ok(index)
+}
+
+/// Stops the sync once `--max-total-bytes` have been downloaded, or aborts it once too many downloads
+/// have failed. Completed sections and the position of the crates pass are kept in the journal, so the
+/// next run resumes where this one stopped.
+async fn stop_early(downloader: &Downloader) -> Option<Result<usize>> {
+    let failures = downloader.failure_limit();
+    match &failures {
+        Some(reason) => log::error!("Aborting: {}", reason),
+        None if downloader.budget_exhausted() => log::warn!(
+            "Download budget of {} exhausted, stopping. The next run resumes from here.",
+            format_size(downloader.max_bytes.unwrap_or_default())
+        ),
+        None => return None,
+    }
+
+    downloader.log_summary();
+    if let Some(tracer) = &downloader.tracer {
+        tracer.finish(&downloader.stats, failures.as_deref()).await;
+    }
+    if downloader.events && failures.is_none() {
+        events::emit(&events::Event::finished(
+            &downloader.stats,
+            Some("max-total-bytes"),
+        ));
+    }
+    systemd::notify(match failures {
+        Some(_) if downloader.stats.disk_full.load(Ordering::Relaxed) => {
+            "STATUS=Aborted, the disk is full"
+        }
+        Some(_) => "STATUS=Aborted after too many failures",
+        None => "STATUS=Download budget exhausted",
+    });
+
+    let saved = match &downloader.report {
+        Some(report) => report.flush(),
+        None => Ok(()),
+    }
+    .and_then(|_| match &downloader.state {
+        Some(state) => {
+            state.set_failed(downloader.stats.failed.lock().unwrap().clone());
+            state.save()
+        }
+        None => Ok(()),
+    });
+
+    Some(saved.and_then(|_| match failures {
+        Some(reason) => Err(std::io::Error::other(reason)),
+        None => Ok(downloader.stats.failed.lock().unwrap().len()),
+    }))
+}
+
+/// Removes the archives of all but the `keep` most recent stable releases, unless they are still
+/// referenced by a mirrored channel (e.g. a pinned version).
+async fn prune_stable(downloader: &Downloader, keep: usize) -> Result<()> {
+    let state = downloader.state.as_ref().unwrap();
+    let releases = state.stable_releases();
+
+    if releases.len() <= keep {
+        return Ok(());
+    }
+
+    // Releases are ordered by date, so the superseded ones come first.
+    let superseded = releases.len() - keep;
+    let channels = state.channels();
+    let referenced: HashSet<&String> = releases
+        .values()
+        .skip(superseded)
+        .flatten()
+        .chain(channels.values().flat_map(|channel| channel.files.iter()))
+        .collect();
+
+    let mut removed = 0;
+    for (date, files) in releases.iter().take(superseded) {
+        log::info!("Pruning superseded stable release of {}...", date);
+
+        for file in files.iter().filter(|file| !referenced.contains(file)) {
+            for path in [
+                file.to_string(),
+                format!("{}.asc", file),
+                format!("{}.sha256", file),
+            ] {
+                let copies = std::iter::once(downloader.local_path(&path)).chain(
+                    downloader
+                        .destinations
+                        .iter()
+                        .map(|destination| paths::local_path(destination, &path)),
+                );
+
+                for copy in copies {
+                    match std::fs::remove_file(&copy) {
+                        Ok(()) => {
+                            removed += 1;
+                            // Remove the dated directory once it is empty.
+                            if let Some(parent) = copy.parent() {
+                                let _ = std::fs::remove_dir(parent);
+                            }
+                        }
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+
+        state.remove_stable_release(date);
+        state.save()?;
+    }
+
+    log::info!("Pruned {} files of superseded stable releases.", removed);
+
+    Ok(())
+}
+
+/// Follows at most `max` redirects. Redirects to another origin are logged, and with `origins` only
+/// followed to one of them.
+fn redirect_policy(max: usize, origins: Option<Vec<String>>) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max {
+            return attempt.error(format!("More than {} redirects", max));
+        }
+
+        let previous = attempt.previous().last().cloned();
+        let origin = attempt.url().origin().ascii_serialization();
+        if previous
+            .as_ref()
+            .is_some_and(|previous| previous.origin().ascii_serialization() == origin)
+        {
+            return attempt.follow();
+        }
+
+        let from = previous.map_or(String::new(), |previous| previous.to_string());
+        match &origins {
+            Some(origins) if !origins.contains(&origin) => {
+                log::warn!(
+                    "Not following the redirect from {} to {}, which is not an allowed origin.",
+                    from,
+                    attempt.url()
+                );
+                attempt.error(format!("Redirect to {} is not allowed", origin))
+            }
+            _ => {
+                log::info!("Following the redirect from {} to {}.", from, attempt.url());
+                attempt.follow()
+            }
+        }
+    })
+}
+
+/// Parses a curl-style `host:port:address` entry of `--resolve`.
+fn parse_resolve(entry: &str) -> std::result::Result<(String, SocketAddr), String> {
+    let invalid = || {
+        format!(
+            "Invalid --resolve entry (expected host:port:address): {}",
+            entry
+        )
+    };
+
+    let mut parts = entry.splitn(3, ':');
+    let (host, port, address) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(port), Some(address)) if !host.is_empty() => (host, port, address),
+        _ => return Err(invalid()),
+    };
+
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    let address = address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|_| invalid())?;
+
+    Ok((host.to_string(), SocketAddr::new(address, port)))
+}
+
+/// Reads a list of channels, versions or dates, one per line. Everything after a `#` is a comment.
+fn read_channels_file(path: &str) -> Result<Vec<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Reads the certificates of a PEM file, which may contain several.
+fn read_certificates(path: &str) -> Result<Vec<reqwest::Certificate>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+
+    let contents = std::fs::read_to_string(path)?;
+    let certificates = contents
+        .match_indices(BEGIN)
+        .map(|(start, _)| {
+            let block = &contents[start..];
+            let end = block[BEGIN.len()..]
+                .find(BEGIN)
+                .map_or(block.len(), |end| end + BEGIN.len());
+            reqwest::Certificate::from_pem(&block.as_bytes()[..end]).map_err(std::io::Error::other)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if certificates.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("No PEM certificates found in {}.", path),
+        ));
+    }
+
+    Ok(certificates)
+}
+
+/// Parses a target filter (`--targets`, `--std-targets` or `--rustup-targets`) and leaves out the
+/// targets of `--exclude-targets`.
+fn parse_target_filter(matches: &ArgMatches, targets: &str) -> Result<targets::TargetFilter> {
+    let filter = targets::TargetFilter::parse(targets);
+    match matches.value_of("exclude-targets") {
+        Some(exclusions) => filter.and_then(|filter| filter.exclude(exclusions)),
+        None => filter,
+    }
+    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+}
+
+/// The URL of the proxy given with `--proxy`, including the credentials from `--proxy-user`.
+fn proxy_url(matches: &ArgMatches) -> Result<Option<Url>> {
+    let invalid_input = |err: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, err);
+
+    let mut url = match matches.value_of("proxy") {
+        Some(proxy) => Url::parse(proxy).map_err(|err| invalid_input(err.to_string()))?,
+        None => return Ok(None),
+    };
+
+    if let Some(credentials) = matches.value_of("proxy-user") {
+        let (user, password) = match credentials.split_once(':') {
+            Some((user, password)) => (user, Some(password.to_string())),
+            None => (credentials, std::env::var("SQUIRE_PROXY_PASSWORD").ok()),
+        };
+
+        url.set_username(user)
+            .and_then(|_| url.set_password(password.as_deref()))
+            .map_err(|_| invalid_input(format!("Invalid proxy URL: {}", url)))?;
+    }
+
+    Ok(Some(url))
+}
+
+fn main() -> Result<()> {
+    let app = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(
+            "Downloads the Rust toolchain, the Crates package registry, and rustup for offline use.",
+        )
+        .arg(
+            Arg::new("channels")
+            .long("channels")
+            .short('d')
+            .default_values(&["stable"])
+            .multiple(true)
+            .use_delimiter(true)
+            .global(true)
+            .about("Specify toolchain channels, versions or dates (possible values: stable|beta|nightly|<major.minor>|<major.minor.patch>|<YYYY-MM-DD>|<channel>-<YYYY-MM-DD>)."),
+        )
+        .arg(
+            Arg::new("channels-file")
+            .long("channels-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .about("Read channels, versions or dates from a file, one per line (# starts a comment). They are added to the channels given with --channels, or replace the default.")
+        )
+        .arg(
+            Arg::new("config")
+            .long("config")
+            .takes_value(true)
+            .about("Configuration file (TOML) with named profiles, e.g. [profiles.ci-linux] with channels, targets, components and crates-filter. The profiles to sync are given with --profile.")
+        )
+        .arg(
+            Arg::new("profile")
+            .long("profile")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .requires("config")
+            .about("Profiles of the config file to sync (or all). Each channel is mirrored for the targets and components of the profiles that list it, and the crates of all of them are mirrored.")
+        )
+        .arg(
+            Arg::new("verbose")
+            .long("verbose")
+            .short('v')
+            .multiple_occurrences(true)
+            .about("Log more: -v includes debug messages (such as every crate checked), -vv trace messages."),
+        )
+        .arg(
+            Arg::new("quiet")
+            .long("quiet")
+            .short('q')
+            .conflicts_with("verbose")
+            .about("Only log warnings and errors."),
+        )
+        .arg(
+            Arg::new("targets")
+            .long("targets")
+            .short('t')
+            .default_value("x86_64")
+            .global(true)
+            .about("Include only toolchain distributions and rustup executables (unless --rustup-targets is given) for these targets. Accepts a comma-separated list of exact target triples (x86_64-unknown-linux-gnu), globs (*-linux-gnu) and regular expressions (prefix with \"regex:\" to force). Use \"*\" to include rust-src."),
+        )
+        .arg(
+            Arg::new("rustup-targets")
+            .long("rustup-targets")
+            .takes_value(true)
+            .about("Download the rustup executables for these targets instead of those of --targets, in the same syntax, e.g. for developer machines that install toolchains for other targets than they run on."),
+        )
+        .arg(
+            Arg::new("std-targets")
+            .long("std-targets")
+            .value_name("TARGETS")
+            .takes_value(true)
+            .about("Also mirror the standard library (rust-std) of these targets, in the same syntax as --targets, without the rest of their toolchains. This is what `rustup target add` installs to cross-compile, e.g. for embedded (thumbv7em-none-eabihf) or wasm (wasm32-unknown-unknown) targets."),
+        )
+        .arg(
+            Arg::new("exclude-targets")
+            .long("exclude-targets")
+            .value_name("TARGETS")
+            .takes_value(true)
+            .global(true)
+            .about("Leave out these targets from those selected by --targets, --std-targets and --rustup-targets, in the same syntax, e.g. \"*-linux-*\" with --exclude-targets \"*-musl*,*-gnueabi\"."),
+        )
+        .arg(
+            Arg::new("concurrency")
+            .long("concurrency")
+            .short('c')
+            .default_value("5")
+            .about("Maximum number of concurrent HTTP-requests. Unless it is given, static.rust-lang.org and static.crates.io have their own defaults (see --dist-concurrency and --crates-concurrency)."),
+        )
+        .arg(
+            Arg::new("dist-concurrency")
+            .long("dist-concurrency")
+            .takes_value(true)
+            .about("Maximum number of concurrent HTTP-requests to static.rust-lang.org (rustup and toolchains). Defaults to --concurrency if given, otherwise 4.")
+        )
+        .arg(
+            Arg::new("crates-concurrency")
+            .long("crates-concurrency")
+            .takes_value(true)
+            .about("Maximum number of concurrent HTTP-requests to static.crates.io (crates). Defaults to --concurrency if given, otherwise 16.")
+        )
+        .arg(
+            Arg::new("validate-checksums")
+            .long("validate-checksums")
+            .about("Enable checksum (SHA-256) validation of existing crate files. Downloaded crates are always validated before they are written.")
+        )
+        .arg(
+            Arg::new("local-sha256")
+            .long("local-sha256")
+            .about("Write a .sha256 sidecar (like those of static.rust-lang.org, after a comment marking it as generated locally) for every mirrored file that has none upstream, such as the crates, so verification tools cover the whole mirror.")
+        )
+        .arg(
+            Arg::new("content-addressed")
+            .long("content-addressed")
+            .about("Store each file once by its SHA-256 digest in .squire/objects, with hard links at its paths, so identical files (e.g. archives referenced by several manifests) take up space once. Existing files are linked on the next sync. Only on Unix.")
+        )
+        .arg(
+            Arg::new("local-digests")
+            .long("local-digests")
+            .requires("validate-checksums")
+            .about("Record BLAKE3 digests of crates whose checksum has been validated (in .squire/state.json), and validate against those on later runs, which is considerably faster than SHA-256. Crates are still compared with SHA-256 if their BLAKE3 digest is unknown or does not match.")
+        )
+        .arg(
+            Arg::new("force-revalidate")
+            .long("force-revalidate")
+            .requires("validate-checksums")
+            .about("Hash all existing crates again. By default, crates whose size and modification time have not changed since their checksum was last verified are not hashed again.")
+        )
+        .arg(
+            Arg::new("quarantine")
+            .long("quarantine")
+            .value_name("DIRECTORY")
+            .takes_value(true)
+            .about("Move files that fail validation (--validate-checksums, --verify-size) to this directory, with a record of why, instead of overwriting them when they are downloaded again.")
+        )
+        .arg(
+            Arg::new("hash-workers")
+            .long("hash-workers")
+            .takes_value(true)
+            .about("Maximum number of files hashed in parallel by --validate-checksums. Defaults to the number of CPUs.")
+        )
+        .arg(
+            Arg::new("user-agent")
+            .long("user-agent")
+            .default_value("squire (https://github.com/oskarbraten/squire)")
+        )
+        .arg(
+            Arg::new("ip-version")
+            .long("ip-version")
+            .possible_values(&["4", "6", "auto"])
+            .default_value("auto")
+            .about("Only connect over IPv4 or IPv6, e.g. when the routing of the other is broken. Applies to downloads; the crates.io-index is fetched by libgit2, which uses the system resolver.")
+        )
+        .arg(
+            Arg::new("resolve")
+            .long("resolve")
+            .value_name("HOST:PORT:ADDRESS")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Connect to ADDRESS for HOST instead of resolving it through DNS, like curl's --resolve (e.g. static.crates.io:443:10.0.0.5). Can be given multiple times. Applies to downloads, not to fetching the crates.io-index.")
+        )
+        .arg(
+            Arg::new("pin-certificates")
+            .long("pin-certificates")
+            .value_name("PEM-FILE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Only trust the CA certificates in this PEM file for downloads, instead of the system's certificate store, e.g. the roots that issued the certificates of static.rust-lang.org and static.crates.io. Can be given multiple times. Does not apply to fetching the crates.io-index.")
+        )
+        .arg(
+            Arg::new("proxy")
+            .long("proxy")
+            .takes_value(true)
+            .about("Proxy to use for all downloads and fetching the index (e.g. http://proxy.example:8080), instead of the system proxy settings. Credentials can be included in the URL.")
+        )
+        .arg(
+            Arg::new("proxy-user")
+            .long("proxy-user")
+            .takes_value(true)
+            .requires("proxy")
+            .about("Credentials for basic authentication with the proxy, as user:password. The password can also be given with the SQUIRE_PROXY_PASSWORD environment variable.")
+        )
+        .arg(
+            Arg::new("worker-threads")
+            .long("worker-threads")
+            .takes_value(true)
+            .about("Number of threads of the async runtime (defaults to the number of CPUs).")
+        )
+        .arg(
+            Arg::new("blocking-threads")
+            .long("blocking-threads")
+            .takes_value(true)
+            .about("Maximum number of threads for blocking work such as hashing, scanning and reading the index (defaults to 512). Hashing is further limited by --hash-workers.")
+        )
+        .arg(
+            Arg::new("crates-fallback")
+            .long("crates-fallback")
+            .value_name("URL")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Root URL of a host that serves the same crates as https://static.crates.io (e.g. a CDN alias), tried when a download from it fails. Can be given multiple times.")
+        )
+        .arg(
+            Arg::new("max-redirects")
+            .long("max-redirects")
+            .value_name("N")
+            .default_value("10")
+            .about("Maximum number of redirects followed for a download.")
+        )
+        .arg(
+            Arg::new("redirect-origins")
+            .long("redirect-origins")
+            .value_name("ORIGINS")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .about("Only follow redirects to these origins (e.g. https://objects.githubusercontent.com), besides static.rust-lang.org, static.crates.io and the --crates-fallback hosts. Redirects to other origins fail the download. Without it, every redirect is followed, and those to another origin are logged.")
+        )
+        .arg(
+            Arg::new("retries")
+            .long("retries")
+            .default_value("3")
+            .about("Number of times to retry a download that failed with a server error or timeout. Files that are not found (HTTP 404) are not retried.")
+        )
+        .arg(
+            Arg::new("timeout")
+            .long("timeout")
+            .default_value("60")
+            .about("Seconds to wait for a connection, the response headers or the next chunk of a response before retrying.")
+        )
+        .arg(
+            Arg::new("progress-format")
+            .long("progress-format")
+            .takes_value(true)
+            .possible_values(&["text", "bars", "ndjson"])
+            .default_value("text")
+            .conflicts_with("tui")
+            .about("With bars, show progress bars of the files of the current section and of large downloads below the log lines (if stdout is a terminal). With ndjson, write an event per section and processed file to stdout as newline-delimited JSON for other tools to consume, and the log lines to stderr.")
+        )
+        .arg(
+            Arg::new("otel-endpoint")
+            .long("otel-endpoint")
+            .value_name("URL")
+            .takes_value(true)
+            .about("Export traces of the sync, its sections and downloads, and metrics of the files, to an OpenTelemetry collector with OTLP over HTTP (e.g. http://localhost:4318).")
+        )
+        .arg(
+            Arg::new("tui")
+            .long("tui")
+            .about("Show a live dashboard with the progress of the current section, the active downloads and their speeds, failure counts and an ETA, instead of the log lines. Press q to interrupt the sync.")
+        )
+        .arg(
+            Arg::new("daemon")
+            .long("daemon")
+            .about("Keep running and sync the mirror again every --interval, instead of exiting after one sync. On SIGHUP, the --config file is reloaded and used from the next sync.")
+        )
+        .arg(
+            Arg::new("interval")
+            .long("interval")
+            .value_name("DURATION")
+            .takes_value(true)
+            .default_value("6h")
+            .about("Time between the starts of syncs in --daemon mode, e.g. 30m, 6h or 1d. A sync that takes longer is followed immediately by the next one.")
+        )
+        .arg(
+            Arg::new("sync-window")
+            .long("sync-window")
+            .value_name("WINDOW")
+            .takes_value(true)
+            .about("Only download files between these times of the day, e.g. \"01:00-06:00\" in local time (following daylight saving time) or \"22:00-04:00 +02:00\" at a fixed UTC offset (which does not). Named time zones are not supported. Outside of it, downloads in progress are finished and new ones wait for it to open again. In --daemon mode, syncs also wait for it to start.")
+        )
+        .arg(
+            Arg::new("wait-lock")
+            .long("wait-lock")
+            .about("Wait for another sync of the same output directory to finish instead of exiting with an error.")
+        )
+        .arg(
+            Arg::new("prescan")
+            .long("prescan")
+            .about("Scan the existing crates and dist directories once at startup, instead of checking each file individually. Speeds up runs against large, mostly up-to-date mirrors.")
+        )
+        .arg(
+            Arg::new("verify-size")
+            .long("verify-size")
+            .about("Compare the size of existing files with upstream (using HEAD requests) and re-download files that differ, e.g. because they are truncated.")
+        )
+        .arg(
+            Arg::new("fsync")
+            .long("fsync")
+            .about("Flush every downloaded file to disk before moving it into place. Slower, but guarantees that files in the mirror are complete after a power loss.")
+        )
+        .arg(
+            Arg::new("partial-files")
+            .long("partial-files")
+            .value_name("POLICY")
+            .default_value("delete")
+            .possible_values(&["delete", "keep", "in-place"])
+            .about("What to do with the partial file of a download that fails: delete it, keep it as <file>.part and resume it on the next attempt or run, or keep it in place at the final path (where later runs skip it unless --verify-size or --validate-checksums is given). Segmented downloads are always deleted, as they cannot be resumed.")
+        )
+        .arg(
+            Arg::new("segments")
+            .long("segments")
+            .default_value("1")
+            .about("Download large files using this many parallel range requests.")
+        )
+        .arg(
+            Arg::new("max-failures")
+            .long("max-failures")
+            .value_name("N")
+            .takes_value(true)
+            .about("Abort the sync with an error once N downloads have failed, e.g. because upstream is down or the disk is broken. The next run resumes where it stopped.")
+        )
+        .arg(
+            Arg::new("fail-on-errors")
+            .long("fail-on-errors")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("0")
+            .about("Exit with status 2 when more than N downloads failed, so wrappers can tell a partial failure from success (0) and a fatal error (1). Files not found upstream do not count.")
+        )
+        .arg(
+            Arg::new("max-consecutive-failures")
+            .long("max-consecutive-failures")
+            .value_name("N")
+            .takes_value(true)
+            .about("Abort the sync with an error once N downloads in a row have failed.")
+        )
+        .arg(
+            Arg::new("disk-full-wait")
+            .long("disk-full-wait")
+            .value_name("DURATION")
+            .takes_value(true)
+            .default_value("1h")
+            .about("When the disk of the mirror is full, pause the downloads for up to this long (e.g. 30m or 6h) and resume them once 1 GiB is free. Longer, the sync is aborted with an error; 0 aborts it immediately.")
+        )
+        .arg(
+            Arg::new("on-disk-full")
+            .long("on-disk-full")
+            .value_name("COMMAND")
+            .takes_value(true)
+            .about("Shell command run once the disk is full and the downloads are paused, e.g. to send an alert. SQUIRE_OUTPUT_DIRECTORY and SQUIRE_AVAILABLE (free bytes) are set in its environment.")
+        )
+        .arg(
+            Arg::new("max-total-bytes")
+            .long("max-total-bytes")
+            .value_name("SIZE")
+            .takes_value(true)
+            .about("Stop starting new downloads once this much has been downloaded (e.g. 200GiB). Downloads in progress are finished, and the next run resumes where this one stopped, so seeding a full mirror can be spread over several runs.")
+        )
+        .arg(
+            Arg::new("segment-threshold")
+            .long("segment-threshold")
+            .default_value("64MiB")
+            .about("Minimum size of files downloaded in segments (e.g. 64MiB).")
+        )
+        .arg(
+            Arg::new("rustup-version")
+            .long("rustup-version")
+            .takes_value(true)
+            .about("Mirror this rustup release (e.g. 1.27.1) instead of the latest one, and pin release-stable.toml to it so rustup self update does not upgrade past it.")
+        )
+        .arg(
+            Arg::new("archive-manifests")
+            .long("archive-manifests")
+            .about("Keep a copy of every fetched channel manifest under its date (dist/<date>/channel-rust-<channel>.toml), so the mirror retains the history of each channel and dated installs keep working.")
+        )
+        .arg(
+            Arg::new("keep-stable")
+            .long("keep-stable")
+            .value_name("N")
+            .takes_value(true)
+            .about("Keep the archives of only the N most recent stable releases, removing older ones after a sync without failed downloads. Archives still referenced by another mirrored channel are kept.")
+        )
+        .arg(
+            Arg::new("manifests-only")
+            .long("manifests-only")
+            .about("Only fetch the channel manifests, release-stable.toml and the crates.io-index, without any archives, executables or crates. Useful to review what a full sync would download, e.g. with estimate or --changes-report.")
+        )
+        .arg(
+            Arg::new("no-docs")
+            .long("no-docs")
+            .about("Leave out the documentation components (rust-docs and rust-docs-json) of every channel and target, which make up a large part of the size of a mirror. Toolchains are then installed from the mirror with `rustup toolchain install --profile minimal`, as the default profile includes rust-docs.")
+        )
+        .arg(
+            Arg::new("rustc-src")
+            .long("rustc-src")
+            .about("Also download the source tarball of the compiler (rustc-<version>-src.tar.xz, with its signature and checksum) of every mirrored release, e.g. to build or patch the compiler offline.")
+        )
+        .arg(
+            Arg::new("artifacts")
+            .long("artifacts")
+            .about("Also download the files in the [artifacts] section of channel manifests (e.g. source tarballs and installers) for the selected targets.")
+        )
+        .arg(
+            Arg::new("toolchain-file")
+            .long("toolchain-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Add the channel, targets and components pinned by a rust-toolchain.toml (or rust-toolchain) file. Can be given multiple times.")
+        )
+        .arg(
+            Arg::new("scan-projects")
+            .long("scan-projects")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Add the channels and versions required by the projects in a directory (through rust-toolchain.toml, rust-toolchain and rust-version in Cargo.toml) to the channels.")
+        )
+        .arg(
+            Arg::new("binstall-tools")
+            .long("binstall-tools")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .about("Mirror the cargo-quickinstall artifacts of tools (e.g. cargo-nextest,ripgrep@14.1.0) for the selected targets, so cargo-binstall can be pointed at the mirror. Without a version, the latest release in the index is used.")
+        )
+        .arg(
+            Arg::new("components-history")
+            .long("components-history")
+            .about("Mirror the rustup-components-history of the selected targets, which tells the last nightly each component (e.g. clippy or rustfmt) was available in, so a nightly with the components can be picked offline.")
+        )
+        .arg(
+            Arg::new("mirror-to")
+            .long("mirror-to")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Additional output directory that the mirror is copied to, e.g. a redundant disk. Each file is downloaded once and copied to every destination. Can be given multiple times.")
+        )
+        .arg(
+            Arg::new("file-mode")
+            .long("file-mode")
+            .takes_value(true)
+            .about("Mode of downloaded files, in octal (e.g. 0644). Defaults to the umask.")
+        )
+        .arg(
+            Arg::new("dir-mode")
+            .long("dir-mode")
+            .takes_value(true)
+            .about("Mode of the directories created for downloaded files, in octal (e.g. 0755). Defaults to the umask.")
+        )
+        .arg(
+            Arg::new("owner")
+            .long("owner")
+            .takes_value(true)
+            .about("Owner of downloaded files and created directories, as user, user:group or :group (requires running as root).")
+        )
+        .arg(
+            Arg::new("crates-filter")
+            .long("crates-filter")
+            .takes_value(true)
+            .about("Only download crates whose name matches this regex (e.g. '^(tokio|serde|async-).*').")
+        )
+        .arg(
+            Arg::new("lockfile-dir")
+            .long("lockfile-dir")
+            .value_name("DIRECTORY")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .about("Only mirror the crate versions locked by the Cargo.lock files found in a directory and its subdirectories, e.g. a monorepo or CI checkout area. Can be given multiple times.")
+        )
+        .arg(
+            Arg::new("popular-first")
+            .long("popular-first")
+            .about("Mirror the most downloaded crates first (according to the crates.io database dump), so a partial or interrupted mirror is useful for most builds early.")
+        )
+        .arg(
+            Arg::new("with-dependencies")
+            .long("with-dependencies")
+            .about("Also mirror the transitive dependencies of the selected crates (the newest version matching each requirement, as cargo resolves without a lockfile), so they can be built offline.")
+        )
+        .arg(
+            Arg::new("crates-category")
+            .long("crates-category")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .about("Only download crates in these crates.io categories (by slug, including subcategories), according to the crates.io database dump.")
+        )
+        .arg(
+            Arg::new("crates-keyword")
+            .long("crates-keyword")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .about("Only download crates with these crates.io keywords, according to the crates.io database dump. Crates are downloaded if they match any of the categories or keywords.")
+        )
+        .arg(
+            Arg::new("remove-yanked")
+            .long("remove-yanked")
+            .about("Delete the crate files of versions that have been yanked upstream.")
+        )
+        .arg(
+            Arg::new("yanked-quarantine")
+            .long("yanked-quarantine")
+            .takes_value(true)
+            .requires("remove-yanked")
+            .about("Move yanked crate files to this directory instead of deleting them.")
+        )
+        .arg(
+            Arg::new("changes-report")
+            .long("changes-report")
+            .takes_value(true)
+            .about("Write a report of the crate versions added, removed, yanked and unyanked, and checksums changed in the index since the last sync (as text and JSON) to this directory.")
+        )
+        .arg(
+            Arg::new("start-from")
+            .long("start-from")
+            .takes_value(true)
+            .about("Skip the crate versions before the first version of this crate in the crates pass.")
+        )
+        .arg(
+            Arg::new("filtered-index")
+            .long("filtered-index")
+            .value_name("URL")
+            .takes_value(true)
+            .about("Also write a sparse registry index with only the mirrored crate versions to filtered-index/, for a mirror served at URL (use it with sparse+URL/filtered-index/).")
+        )
+        .arg(
+            Arg::new("local-registry")
+            .long("local-registry")
+            .value_name("DIRECTORY")
+            .takes_value(true)
+            .about("Also write the mirrored crates, with an index of them, to a directory that cargo can use as a local-registry source replacement.")
+        )
+        .arg(
+            Arg::new("index-url")
+            .long("index-url")
+            .value_name("URL")
+            .takes_value(true)
+            .about("Clone and update the crates.io-index from this git URL instead of GitHub, e.g. an internal mirror of it.")
+        )
+        .arg(
+            Arg::new("index-path")
+            .long("index-path")
+            .value_name("DIRECTORY")
+            .takes_value(true)
+            .conflicts_with("index-url")
+            .about("Read the crates from an existing crates.io-index clone, e.g. maintained by another tool, instead of the index in the mirror. It is not updated, nor copied into the mirror.")
+        )
+        .arg(
+            Arg::new("db-dump-index")
+            .long("db-dump-index")
+            .conflicts_with_all(&["index-url", "index-path", "index-since", "with-dependencies", "filtered-index", "local-registry", "remove-yanked", "changes-report"])
+            .about("Read the crate versions and their checksums from the crates.io database dump instead of the crates.io-index, for hosts where git is blocked. The index is not mirrored then, and the dump lags behind it by up to a day.")
+        )
+        .arg(
+            Arg::new("index-since")
+            .long("index-since")
+            .value_name("COMMIT")
+            .takes_value(true)
+            .about("Only mirror the crate versions added or changed in the crates.io-index since this commit, e.g. to catch up manually or to build a delta deterministically.")
+        )
+        .arg(
+            Arg::new("restart")
+            .long("restart")
+            .about("Ignore the journal of an interrupted sync and start from the beginning.")
+        )
+        .arg(
+            Arg::new("file-report")
+            .long("file-report")
+            .value_name("PATH")
+            .takes_value(true)
+            .about("Write a newline-delimited JSON report with a record (path, url, action, bytes, duration_ms, error) for every file processed by the sync.")
+        )
+        .arg(
+            Arg::new("log-file")
+            .long("log-file")
+            .takes_value(true)
+            .about("Also write log messages to this file, rotated according to --log-file-max-size and --log-file-rotation.")
+        )
+        .arg(
+            Arg::new("log-file-level")
+            .long("log-file-level")
+            .default_value("info")
+            .possible_values(&["error", "warn", "info", "debug", "trace"])
+            .about("Log level of the log file, independent of --verbose.")
+        )
+        .arg(
+            Arg::new("log-file-max-size")
+            .long("log-file-max-size")
+            .default_value("10MiB")
+            .about("Rotate the log file once it exceeds this size (e.g. 512KiB, 10MiB, 1GiB). Use 0 to disable size-based rotation.")
+        )
+        .arg(
+            Arg::new("log-file-rotation")
+            .long("log-file-rotation")
+            .default_value("never")
+            .possible_values(&["never", "hourly", "daily"])
+            .about("Rotate the log file at the start of every hour or day.")
+        )
+        .arg(
+            Arg::new("log-file-keep")
+            .long("log-file-keep")
+            .default_value("5")
+            .about("Number of rotated log files to retain.")
+        )
+        .arg(
+            Arg::new("OUTPUT-DIRECTORY")
+            .about("Specifies the output directory for the mirror.")
+            .env("SQUIRE_OUTPUT_DIRECTORY")
+            .required(true)
+            .index(1),
+        )
+        .subcommand(
+            App::new("list")
+            .about("Lists the targets or components available in a channel.")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                App::new("targets")
+                .about("Lists the targets available in a channel.")
+                .arg(Arg::new("channel").long("channel").default_value("stable").about("The channel, version or date to list."))
+                .arg(Arg::new("sizes").long("sizes").about("Also show the total size of each target (requires a HEAD request per file)."))
+            )
+            .subcommand(
+                App::new("components")
+                .about("Lists the components available in a channel, with their sizes.")
+                .arg(Arg::new("channel").long("channel").default_value("stable").about("The channel, version or date to list."))
+                .arg(Arg::new("target").long("target").default_value("x86_64-unknown-linux-gnu").about("The target to list components for."))
+            )
+        )
+        .subcommand(
+            App::new("estimate")
+            .about("Estimates the size of a sync with the given --channels and --targets, per section, target and component.")
+            .arg(Arg::new("json").long("json").about("Print the estimate as JSON."))
+            .arg(Arg::new("crate-samples").long("crate-samples").default_value("100").about("Number of crate sizes to sample (using HEAD requests) when estimating the size of the crates section."))
+            .arg(Arg::new("OUTPUT-DIRECTORY").about("An existing mirror, whose index is used to estimate the crates section.").index(1))
+        )
+        .subcommand(
+            App::new("status")
+            .about("Shows when each section of a mirror was last synced, the mirrored channels and targets, the size of the mirror and the downloads that failed.")
+            .arg(Arg::new("OUTPUT-DIRECTORY").about("The mirror.").required(true).index(1))
+        )
+        .subcommand(
+            App::new("diff")
+            .about("Compares the files of two mirrors and lists the files that are missing from either or differ. Exits with status 1 if the mirrors differ.")
+            .arg(Arg::new("checksums").long("checksums").about("Also compare the SHA-256 digests of files that have the same size."))
+            .arg(Arg::new("json").long("json").about("Print the differences as JSON."))
+            .arg(Arg::new("MIRROR-A").required(true).index(1))
+            .arg(Arg::new("MIRROR-B").required(true).index(2))
+        )
+        .subcommand(
+            App::new("inventory")
+            .about("Lists every file of a mirror (toolchain archives, rustup executables, crates and prebuilt tools) with its name, version, target, size and SHA-256 digest. Files that were not verified by a sync are hashed.")
+            .arg(Arg::new("format").long("format").possible_values(&["csv", "json"]).default_value("csv").about("The format of the list."))
+            .arg(Arg::new("OUTPUT-DIRECTORY").about("The mirror.").required(true).index(1))
+        )
+        .subcommand(
+            App::new("migrate")
+            .about("Upgrades a mirror made by an earlier version to the current layout in place: recomputes the state of the mirror from its files, removes leftovers and rewrites the metadata, so it does not need to be seeded again.")
+            .arg(Arg::new("wait-lock").long("wait-lock").about("Wait for a running sync to finish instead of failing."))
+            .arg(Arg::new("OUTPUT-DIRECTORY").about("The mirror.").required(true).index(1))
+        )
+        .subcommand(
+            App::new("doctor")
+            .about("Checks the environment before a long sync: connectivity to the upstreams (directly and via the proxy), fetching the crates.io-index with git, free space and write permissions of the output directory, and the clock. Exits with status 1 if a check fails.")
+            .arg(Arg::new("OUTPUT-DIRECTORY").about("The mirror, which does not need to exist yet.").index(1))
+        )
+        .subcommand(
+            App::new("check-upstream")
+            .about("Reports how far a mirror is behind upstream (new channel releases, a new rustup and the crate versions published since the last sync), fetching only the manifests and the crates.io-index. Exits with status 1 if the mirror is behind.")
+            .arg(Arg::new("OUTPUT-DIRECTORY").about("The mirror.").required(true).index(1))
+        )
+        .subcommand(
+            App::new("completions")
+            .about("Writes the shell completions of squire to stdout.")
+            .setting(AppSettings::Hidden)
+            .arg(Arg::new("SHELL").possible_values(&completions::SHELLS).required(true).index(1))
+        )
+        .setting(AppSettings::SubcommandsNegateReqs);
+    let args = env_args(&app);
+    let matches = app.clone().get_matches_from(args);
+
+    if let Some(("completions", matches)) = matches.subcommand() {
+        return completions::run(app, matches.value_of("SHELL").unwrap());
+    }
+
+    let log_file = match matches.value_of("log-file") {
+        Some(path) => Some(logging::LogFile {
+            path: PathBuf::from(path),
+            level: matches.value_of_t("log-file-level").unwrap(),
+            max_size: match parse_size(matches.value_of("log-file-max-size").unwrap()) {
+                Ok(0) => None,
+                Ok(size) => Some(size),
+                Err(error) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid --log-file-max-size: {}", error),
+                    ))
+                }
+            },
+            rotation: matches.value_of_t("log-file-rotation").unwrap(),
+            keep: number_arg(&matches, "log-file-keep")?,
+        }),
+        None => None,
+    };
+
+    // Progress events are written to stdout, so the log lines go to stderr.
+    let ndjson = matches.value_of("progress-format") == Some("ndjson");
+    let console_level = match matches.occurrences_of("verbose") {
+        _ if matches.is_present("quiet") => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    logging::init(console_level, log_file, ndjson)?;
+
+    let user_agent = matches.value_of("user-agent").unwrap();
+    let timeout = timeout_arg(&matches)?;
+
+    let max_redirects = matches.value_of_t("max-redirects").map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--max-redirects must be a number.",
+        )
+    })?;
+    // Redirects between the upstreams (and the fallbacks for static.crates.io) are always allowed.
+    let redirect_origins = matches.values_of("redirect-origins").map(|origins| {
+        origins
+            .chain([RUSTLANG_ROOT_URL, CRATES_ROOT_URL])
+            .chain(matches.values_of("crates-fallback").into_iter().flatten())
+            .filter_map(|origin| Url::parse(origin).ok())
+            .map(|url| url.origin().ascii_serialization())
+            .collect()
+    });
+
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .connect_timeout(timeout)
+        .redirect(redirect_policy(max_redirects, redirect_origins));
+
+    // Binding to the unspecified address of a family means only addresses of that family can be
+    // connected to.
+    match matches.value_of("ip-version") {
+        Some("4") => builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        Some("6") => builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        _ => {}
+    }
+
+    for entry in matches.values_of("resolve").into_iter().flatten() {
+        let (host, address) = parse_resolve(entry)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        builder = builder.resolve(&host, address);
+    }
+
+    // Only trust the pinned certificates, so a compromised CA or an intercepting proxy cannot serve
+    // tampered downloads.
+    if let Some(files) = matches.values_of("pin-certificates") {
+        builder = builder.tls_built_in_root_certs(false);
+        for file in files {
+            for certificate in read_certificates(file)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+    }
+
+    if let Some(proxy) = proxy_url(&matches)? {
+        // The index is fetched by libgit2, which picks up the proxy from the environment.
+        std::env::set_var("http_proxy", proxy.as_str());
+        std::env::set_var("https_proxy", proxy.as_str());
+
+        builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(std::io::Error::other)?);
+    }
+
+    let http_client = builder.build();
+
+    let threads = |name: &str| -> Result<Option<usize>> {
+        match matches.value_of(name).map(str::parse::<usize>) {
+            Some(Ok(0)) | Some(Err(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("--{} must be a positive number.", name),
+            )),
+            Some(Ok(threads)) => Ok(Some(threads)),
+            None => Ok(None),
+        }
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = threads("worker-threads")? {
+        runtime.worker_threads(threads);
+    }
+    if let Some(threads) = threads("blocking-threads")? {
+        runtime.max_blocking_threads(threads);
+    }
+
+    let runtime = runtime.build()?;
+    let http_client = match http_client {
+        Ok(client) => client,
+        // The doctor reports why the client cannot be created, along with the other checks.
+        Err(error) => {
+            return match matches.subcommand() {
+                Some(("doctor", sub_matches)) => {
+                    runtime.block_on(doctor::run(Err(error), &matches, sub_matches))
+                }
+                _ => Err(std::io::Error::other(format!(
+                    "Unable to create the HTTP client: {}",
+                    error
+                ))),
+            }
+        }
+    };
+
+    runtime.block_on(async {
+        match matches.subcommand() {
+            Some(("list", matches)) => list::run(&http_client, matches).await,
+            Some(("estimate", matches)) => estimate::run(&http_client, matches).await,
+            Some(("status", matches)) => status::run(matches).await,
+            Some(("diff", matches)) => diff::run(matches).await,
+            Some(("inventory", matches)) => inventory::run(matches).await,
+            Some(("migrate", matches)) => migrate::run(matches).await,
+            Some(("doctor", sub_matches)) => {
+                doctor::run(Ok(&http_client), &matches, sub_matches).await
+            }
+            Some(("check-upstream", sub_matches)) => {
+                check_upstream::run(&http_client, &matches, sub_matches).await
+            }
+            _ => {
+                systemd::spawn_watchdog();
+
+                let result = if matches.is_present("daemon") {
+                    let interval = parse_duration(matches.value_of("interval").unwrap())
+                        .ok()
+                        .filter(|interval| !interval.is_zero())
+                        // The time of the next sync has to be a date.
+                        .filter(|interval| {
+                            chrono::Duration::from_std(*interval)
+                                .ok()
+                                .and_then(|interval| {
+                                    chrono::Local::now().checked_add_signed(interval)
+                                })
+                                .is_some()
+                        })
+                        .ok_or_else(|| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                "--interval must be a positive duration, e.g. 6h.",
+                            )
+                        })?;
+                    daemon(&matches, http_client, interval).await
+                } else {
+                    let allowed: usize = matches
+                        .value_of("fail-on-errors")
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                "--fail-on-errors must be a number.",
+                            )
+                        })?;
+
+                    match sync(&matches, http_client, load_profile(&matches)?).await {
+                        Ok(failed) if failed > allowed => {
+                            log::error!(
+                                "{} downloads failed, more than --fail-on-errors allows ({}).",
+                                failed,
+                                allowed
+                            );
+                            std::process::exit(EXIT_PARTIAL_FAILURE);
+                        }
+                        result => result.map(drop),
+                    }
+                };
+
+                if let (true, Err(error)) = (ndjson, &result) {
+                    events::emit(&events::Event::Error {
+                        error: &error.to_string(),
+                    });
+                }
+                result
+            }
+        }
+    })
+}
+
+/// Synchronizes the mirror in the output directory with upstream. The channels and crate filters of
+/// the profiles selected from the config file are combined with those given explicitly, and each
+/// channel is mirrored for the targets and components of its profiles. Returns the number of
+/// downloads that failed.
+async fn sync(
+    matches: &ArgMatches,
+    http_client: Client,
+    profile: Option<config::Selection>,
+) -> Result<usize> {
+    let output_directory = matches.value_of("OUTPUT-DIRECTORY").unwrap();
+
+    let explicit = |name: &str| matches.occurrences_of(name) > 0;
+
+    let mut channels: Vec<String> = match &profile {
+        Some(profile) if !explicit("channels") && !profile.channels.is_empty() => {
+            profile.channels.clone()
+        }
+        Some(profile) => matches
+            .values_of("channels")
+            .unwrap()
+            .map(str::to_string)
+            .chain(profile.channels.iter().cloned())
+            .collect(),
+        None => matches
+            .values_of("channels")
+            .unwrap()
+            .map(str::to_string)
+            .collect(),
+    };
+    if let Some(path) = matches.value_of("channels-file") {
+        // The listed channels replace the default channel, but not channels given explicitly or by
+        // a profile.
+        let defaulted = !explicit("channels")
+            && profile
+                .as_ref()
+                .is_none_or(|profile| profile.channels.is_empty());
+        if defaulted {
+            channels.clear();
+        }
+        channels.extend(read_channels_file(path)?);
+    }
+    let mut seen = HashSet::new();
+    channels.retain(|channel| seen.insert(channel.clone()));
+
+    // The targets of a channel are those of its profiles, and --targets if it is given explicitly
+    // or no profile lists the channel (e.g. a channel of a toolchain file).
+    let default_targets = matches.value_of("targets").unwrap();
+    let channel_targets = |channel: &str| -> String {
+        let mut filters: Vec<&str> = profile.as_ref().map_or_else(Vec::new, |profile| {
+            profile
+                .targets(channel)
+                .into_iter()
+                .map(|targets| targets.unwrap_or(default_targets))
+                .collect()
+        });
+        if filters.is_empty() || explicit("targets") {
+            filters.insert(0, default_targets);
+        }
+        let mut seen = HashSet::new();
+        filters.retain(|filter| seen.insert(*filter));
+        filters.join(",")
+    };
+    let concurrency = positive_arg(matches, "concurrency")?;
+    let host_concurrency = |name: &str, default: usize| -> Result<usize> {
+        match matches.value_of(name) {
+            Some(_) => positive_arg(matches, name),
+            None if matches.occurrences_of("concurrency") > 0 => Ok(concurrency),
+            None => Ok(default),
+        }
+    };
+    let dist_concurrency = host_concurrency("dist-concurrency", DIST_CONCURRENCY)?;
+    let crates_concurrency = host_concurrency("crates-concurrency", CRATES_CONCURRENCY)?;
+    let validate_checksums = matches.is_present("validate-checksums");
+    let retries: u32 = number_arg(matches, "retries")?;
+    let timeout = timeout_arg(matches)?;
+
+    let _lock =
+        lock::DirectoryLock::acquire(output_directory, matches.is_present("wait-lock")).await?;
+
+    // When running as a systemd service, report progress and let the watchdog restart a hung sync.
+    systemd::notify(&format!("READY=1\nSTATUS=Syncing {}", output_directory));
+
+    let mut downloader =
+        Downloader::new(http_client, output_directory, concurrency, retries, timeout);
+    for (root_url, concurrency) in [
+        (RUSTLANG_ROOT_URL, dist_concurrency),
+        (CRATES_ROOT_URL, crates_concurrency),
+    ] {
+        let host = Url::parse(root_url)
+            .unwrap()
+            .host_str()
+            .unwrap()
+            .to_string();
+        downloader.limit_host(&host, concurrency);
+    }
+
+    if matches.is_present("hash-workers") {
+        downloader.hashers = Semaphore::new(positive_arg(matches, "hash-workers")?);
+    }
+
+    let count = |name: &str| -> Result<Option<usize>> {
+        matches
+            .value_of(name)
+            .map(str::parse)
+            .transpose()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+    };
+    downloader.max_failures = count("max-failures")?;
+    downloader.max_consecutive_failures = count("max-consecutive-failures")?;
+    downloader.disk_full_wait = parse_duration(matches.value_of("disk-full-wait").unwrap())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    downloader.on_disk_full = matches.value_of("on-disk-full").map(str::to_string);
+    downloader.sync_window = sync_window(matches)?;
+    downloader.max_bytes = matches
+        .value_of("max-total-bytes")
+        .map(parse_size)
+        .transpose()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    downloader.verify_size = matches.is_present("verify-size");
+    downloader.fsync = matches.is_present("fsync");
+    downloader.partial_files = matches.value_of_t("partial-files").unwrap();
+    downloader.segments = positive_arg(matches, "segments")?;
+    downloader.segment_threshold = parse_size(matches.value_of("segment-threshold").unwrap())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let invalid_input = |err: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, err);
+    downloader.permissions = permissions::Permissions {
+        file_mode: matches
+            .value_of("file-mode")
+            .map(permissions::parse_mode)
+            .transpose()
+            .map_err(invalid_input)?,
+        dir_mode: matches
+            .value_of("dir-mode")
+            .map(permissions::parse_mode)
+            .transpose()
+            .map_err(invalid_input)?,
+        owner: matches
+            .value_of("owner")
+            .map(permissions::parse_owner)
+            .transpose()
+            .map_err(invalid_input)?,
+    };
+
+    let state = state::State::open(output_directory)?;
+    if state.layout() < state::LAYOUT_VERSION {
+        log::warn!(
+            "{} has the layout of an earlier version, upgrade it with `squire migrate {}`.",
+            output_directory,
+            output_directory
+        );
+    } else {
+        // Record the layout of a new mirror right away, so it is not taken for one of an earlier
+        // version when the first sync fails.
+        state.save()?;
+    }
+    downloader.state = Some(state);
+    downloader.local_digests = matches.is_present("local-digests");
+    downloader.local_sha256 = matches.is_present("local-sha256");
+    downloader.content_addressed = matches.is_present("content-addressed");
+    if downloader.content_addressed && cfg!(not(unix)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--content-addressed is only supported on Unix.",
+        ));
+    }
+    downloader.force_revalidate = matches.is_present("force-revalidate");
+    downloader.validate_checksums = validate_checksums;
+    downloader.index_path = matches.value_of("index-path").map(PathBuf::from);
+    if let Some(url) = matches.value_of("index-url") {
+        downloader.index_url = url.to_string();
+    }
+    downloader.events = matches.value_of("progress-format") == Some("ndjson");
+    downloader.tracer = matches
+        .value_of("otel-endpoint")
+        .map(|endpoint| otel::Tracer::new(downloader.client.clone(), endpoint));
+    if let Some(tracer) = &downloader.tracer {
+        tracer.spawn_exporter();
+    }
+    downloader.quarantine = matches.value_of("quarantine").map(str::to_string);
+    downloader.crates_fallbacks = matches
+        .values_of("crates-fallback")
+        .map(|urls| urls.map(str::to_string).collect())
+        .unwrap_or_default();
+    downloader.report = matches
+        .value_of("file-report")
+        .map(|path| report::Report::create(Path::new(path)))
+        .transpose()?;
+
+    // Shows the progress instead of the log lines until the sync returns.
+    let _dashboard = if matches.is_present("tui") {
+        Some(tui::Dashboard::start(downloader.stats.clone())?)
+    } else {
+        None
+    };
+    let _bars = match matches.value_of("progress-format") {
+        Some("bars") => progress::Bars::start(downloader.stats.clone()),
+        _ => None,
+    };
+
+    if matches.is_present("prescan") {
+        log::info!("Scanning existing mirror...");
+        let existing = scan::scan(output_directory, &["crates", "dist"]).await?;
+        log::info!("Found {} existing files.", existing.len());
+        downloader.existing = Some(existing);
+    }
+
+    // Toolchain files pin a channel, and the components and targets installed with it:
+    let mut toolchain_components = Vec::new();
+    let mut toolchain_targets = Vec::new();
+    for path in matches.values_of("toolchain-file").into_iter().flatten() {
+        let toolchain = projects::toolchain(path)?;
+        if !channels.contains(&toolchain.channel) {
+            log::info!("Adding channel {} pinned by {}", toolchain.channel, path);
+            channels.push(toolchain.channel);
+        }
+        toolchain_components.extend(toolchain.components);
+        toolchain_targets.extend(toolchain.targets);
+    }
+
+    if let Some(directories) = matches.values_of("scan-projects") {
+        for directory in directories {
+            for channel in projects::scan(directory)? {
+                if !channels.contains(&channel) {
+                    log::info!(
+                        "Adding channel {} required by projects in {}",
+                        channel,
+                        directory
+                    );
+                    channels.push(channel);
+                }
+            }
+        }
+    }
+
+    let crates_filter = matches
+        .value_of("crates-filter")
+        .or_else(|| profile.as_ref()?.crates_filter.as_deref())
+        .map(Regex::new)
+        .transpose()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let values = |name: &str| -> Vec<String> {
+        matches
+            .values_of(name)
+            .map(|values| values.map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let locked = match matches.values_of("lockfile-dir") {
+        Some(directories) => {
+            let mut locked = HashSet::new();
+            for directory in directories {
+                locked.extend(projects::lockfiles(directory)?);
+            }
+            Some(locked)
+        }
+        None => None,
+    };
+    let categories = values("crates-category");
+    let keywords = values("crates-keyword");
+
+    let binstall_tools: Vec<binstall::Tool> = match matches.values_of("binstall-tools") {
+        Some(tools) => tools
+            .map(str::parse)
+            .collect::<std::result::Result<_, String>>()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+        None => Vec::new(),
+    };
+
+    let keep_stable = matches
+        .value_of("keep-stable")
+        .map(str::parse::<usize>)
+        .transpose()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    downloader.destinations = matches
+        .values_of("mirror-to")
+        .map(|destinations| destinations.map(str::to_string).collect())
+        .unwrap_or_default();
+
+    let manifests_only = matches.is_present("manifests-only");
+    let journal = Journal::open(
+        output_directory,
+        !matches.is_present("restart"),
+        manifests_only,
+    )?;
+    let state = downloader.state.as_ref().unwrap();
+    let begin = |section: &str| {
+        downloader.stats.begin_section(section);
+        if let Some(tracer) = &downloader.tracer {
+            tracer.begin_section(section);
+        }
+        if downloader.events {
+            events::emit(&events::Event::SectionStarted { section });
+        }
+        systemd::notify(&format!("STATUS=Syncing {}", section));
+    };
+    let complete = |section: &str| -> Result<()> {
+        // Fetching only the manifests does not bring a section up to date.
+        if !manifests_only {
+            state.set_completed(section);
+            state.save()?;
+        }
+        if downloader.events {
+            events::emit(&events::Event::SectionCompleted { section });
+        }
+        if let Some(tracer) = &downloader.tracer {
+            tracer.complete_section(section);
+        }
+        journal.complete(section)
+    };
+
+    // Filter architectures based on the target filter of each channel:
+    let available = get_dist_archiectures(&downloader, "stable").await?;
+    for target in &toolchain_targets {
+        if !available.contains(target) {
+            log::warn!(
+                "Target {} of a toolchain file is not available in channel-stable.",
+                target
+            );
+        }
+    }
+    let select_architectures = |targets: &str| -> Result<Vec<String>> {
+        let mut selected = parse_target_filter(matches, targets)?
+            .select(&available)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        for target in &toolchain_targets {
+            if !selected.contains(target) {
+                selected.push(target.clone());
+            }
+        }
+        Ok(selected)
+    };
+
+    let mut channel_architectures: HashMap<&str, Vec<String>> = HashMap::new();
+    for channel in &channels {
+        channel_architectures.insert(channel, select_architectures(&channel_targets(channel))?);
+    }
+
+    // All the architectures of the channels, for rustup and the other sections.
+    let architectures: Vec<String> = if channels.is_empty() {
+        select_architectures(&channel_targets(""))?
+    } else {
+        let mut architectures = Vec::new();
+        for channel in &channels {
+            for target in &channel_architectures[channel.as_str()] {
+                if !architectures.contains(target) {
+                    architectures.push(target.clone());
+                }
+            }
+        }
+        architectures
+    };
+
+    if channels
+        .iter()
+        .all(|channel| channel_architectures[channel.as_str()] == architectures)
+    {
+        log::info!(
+            "Selected architectures [channel-stable]: {}",
+            architectures.join(", ")
+        );
+    } else {
+        for channel in &channels {
+            log::info!(
+                "Selected architectures for channel-{}: {}",
+                channel,
+                channel_architectures[channel.as_str()].join(", ")
+            );
+        }
+    }
+
+    // Cross-compilation targets only need the standard library, not a toolchain of their own.
+    let std_architectures: Vec<String> = match matches.value_of("std-targets") {
+        Some(targets) => {
+            let selected: Vec<String> =
+                parse_target_filter(matches, targets)?
+                    .select(&available)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+            log::info!("Selected std-only architectures: {}", selected.join(", "));
+            selected
+        }
+        None => Vec::new(),
+    };
+
+    // The rustup executables can be mirrored for other machines than the toolchains are, e.g. for
+    // developer laptops next to Linux toolchains for CI.
+    let rustup_architectures = match matches.value_of("rustup-targets") {
+        Some(targets) => {
+            let selected = parse_target_filter(matches, targets)?
+                .select(&available)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+            log::info!("Selected rustup architectures: {}", selected.join(", "));
+            selected
+        }
+        None => architectures.clone(),
+    };
+
+    // Download rustup executables and manifest:
+    if !journal.is_completed("rustup") {
+        begin("rustup");
+        rustup(
+            &downloader,
+            dist_concurrency,
+            &rustup_architectures,
+            matches.value_of("rustup-version"),
+            manifests_only,
+        )
+        .await?;
+        if let Some(result) = stop_early(&downloader).await {
+            return result;
+        }
+        complete("rustup")?;
+    }
+
+    // Download Rust toolchain(s) and channel manifest:
+    for channel in &channels {
+        let section = format!("dist:{}", channel);
+        if !journal.is_completed(&section) {
+            begin(&section);
+            let architectures = &channel_architectures[channel.as_str()];
+            let std_architectures: Vec<String> = std_architectures
+                .iter()
+                .filter(|target| !architectures.contains(target))
+                .cloned()
+                .collect();
+            let mut components = toolchain_components.clone();
+            for component in profile
+                .iter()
+                .flat_map(|profile| profile.components(channel))
+            {
+                if !components.contains(&component) {
+                    components.push(component);
+                }
+            }
+            dist(
+                &downloader,
+                dist_concurrency,
+                channel,
+                architectures,
+                &DistOptions {
+                    artifacts: matches.is_present("artifacts"),
+                    rustc_source: matches.is_present("rustc-src"),
+                    no_docs: matches.is_present("no-docs"),
+                    archive_manifest: matches.is_present("archive-manifests"),
+                    manifests_only,
+                    components: &components,
+                    std_targets: &std_architectures,
+                },
+            )
+            .await?;
+            if let Some(result) = stop_early(&downloader).await {
+                return result;
+            }
+            complete(&section)?;
+        }
+    }
+
+    // Download crate.io-index and crates:
+    if !journal.is_completed("crates") {
+        begin("crates");
+        let index_path = downloader.index_path();
+        let previous_commit = crate_list::index_commit(&index_path).ok();
+
+        let db_dump_index = matches.is_present("db-dump-index");
+
+        if manifests_only {
+            log::info!("Skipping the crates (--manifests-only).");
+            if !db_dump_index {
+                retrieve_index(&downloader, &downloader.bare_index())?;
+            }
+        } else {
+            let names = if categories.is_empty() && keywords.is_empty() {
+                None
+            } else {
+                let path = db_dump::download(&downloader).await?;
+                let allowed = tokio::task::spawn_blocking(move || {
+                    db_dump::crates_matching(&path, &categories, &keywords)
+                })
+                .await
+                .map_err(std::io::Error::other)??;
+
+                log::info!(
+                    "Found {} crates in the selected categories and keywords.",
+                    allowed.len()
+                );
+                Some(allowed)
+            };
+
+            let downloads = if matches.is_present("popular-first") {
+                let path = db_dump::download(&downloader).await?;
+                let counts = tokio::task::spawn_blocking(move || db_dump::download_counts(&path))
+                    .await
+                    .map_err(std::io::Error::other)??;
+                Some(counts)
+            } else {
+                None
+            };
+
+            let db_dump = if db_dump_index {
+                Some(db_dump::download(&downloader).await?)
+            } else {
+                None
+            };
+
+            crates(
+                &downloader,
+                &journal,
+                crates_concurrency,
+                crate_list::Selection {
+                    filter: crates_filter,
+                    names,
+                    versions: None,
+                    with_dependencies: matches.is_present("with-dependencies"),
+                    explicit: locked,
+                },
+                CratesOptions {
+                    validate_checksums,
+                    start_from: matches.value_of("start-from"),
+                    index_since: matches.value_of("index-since"),
+                    downloads,
+                    db_dump,
+                },
+            )
+            .await?;
+        }
+
+        // An index maintained by another tool (or none at all) is not part of the mirror.
+        if downloader.index_path.is_none() && !db_dump_index {
+            downloader.replicate_tree("/index").await?;
+        }
+
+        if let (Some(directory), Some(previous_commit)) =
+            (matches.value_of("changes-report"), previous_commit)
+        {
+            let commit = crate_list::index_commit(&index_path)?;
+            if commit != previous_commit {
+                let changes = changes::Changes::between(&index_path, &previous_commit, &commit)?;
+                log::info!(
+                    "Index changes: {} added, {} removed, {} yanked, {} unyanked, {} checksum changes.",
+                    changes.added.len(),
+                    changes.removed.len(),
+                    changes.yanked.len(),
+                    changes.unyanked.len(),
+                    changes.checksum_changed.len()
+                );
+                if !changes.checksum_changed.is_empty() {
+                    log::warn!(
+                        "The checksums of published crate versions have changed in the index!"
+                    );
+                }
+
+                let name = format!(
+                    "index-changes-{}",
+                    chrono::Local::now().format("%Y-%m-%dT%H%M%S")
+                );
+                changes.write(directory, &name)?;
+            }
+        }
+
+        if let Some(url) = matches
+            .value_of("filtered-index")
+            .filter(|_| !manifests_only)
+        {
+            log::info!("Writing the filtered index...");
+            let output_directory = downloader.output_directory.clone();
+            let permissions = downloader.permissions;
+            let commit = crate_list::index_commit(&index_path)?;
+            let index_path = index_path.clone();
+            let url = url.to_string();
+            let versions = tokio::task::spawn_blocking(move || {
+                let target =
+                    paths::local_path(&output_directory, filtered_index::FILTERED_INDEX_PATH);
+                filtered_index::write(
+                    &output_directory,
+                    &index_path,
+                    &permissions,
+                    &commit,
+                    &target,
+                    Some(&url),
+                )
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+            log::info!(
+                "Wrote the filtered index with {} crate versions.",
+                versions.len()
+            );
+
+            downloader
+                .replicate_tree(filtered_index::FILTERED_INDEX_PATH)
+                .await?;
+        }
+
+        if let Some(directory) = matches
+            .value_of("local-registry")
+            .filter(|_| !manifests_only)
+        {
+            log::info!("Writing the local registry to {}...", directory);
+            let output_directory = downloader.output_directory.clone();
+            let permissions = downloader.permissions;
+            let commit = crate_list::index_commit(&index_path)?;
+            let index_path = index_path.clone();
+            let directory = PathBuf::from(directory);
+            let versions = tokio::task::spawn_blocking(move || {
+                filtered_index::write_local_registry(
+                    &output_directory,
+                    &index_path,
+                    &permissions,
+                    &commit,
+                    &directory,
+                )
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+            log::info!("Wrote the local registry with {} crate versions.", versions);
+        }
+        if let Some(result) = stop_early(&downloader).await {
+            return result;
+        }
+        complete("crates")?;
+    }
+
+    // Remove crate versions that have been yanked since they were downloaded:
+    if matches.is_present("remove-yanked") && !manifests_only && !journal.is_completed("yanked") {
+        begin("yanked");
+        remove_yanked(&downloader, matches.value_of("yanked-quarantine")).await?;
+        complete("yanked")?;
+    }
+
+    // Download prebuilt binaries for cargo-binstall (requires the index for version lookups):
+    if !binstall_tools.is_empty() && !manifests_only && !journal.is_completed("binstall") {
+        begin("binstall");
+        binstall::mirror(&downloader, concurrency, &binstall_tools, &architectures).await?;
+        if let Some(result) = stop_early(&downloader).await {
+            return result;
+        }
+        complete("binstall")?;
+    }
+
+    if matches.is_present("components-history") && !journal.is_completed("components-history") {
+        begin("components-history");
+        components_history::mirror(&downloader, concurrency, &architectures).await?;
+        if let Some(result) = stop_early(&downloader).await {
+            return result;
+        }
+        complete("components-history")?;
+    }
+
+    // Prune superseded stable releases, but only once everything else has been mirrored:
+    if let Some(keep) = keep_stable {
+        if !downloader.stats.failed.lock().unwrap().is_empty() {
+            log::warn!("Not pruning stable releases, as some downloads failed.");
+        } else if !manifests_only && !journal.is_completed("prune") {
+            begin("prune");
+            prune_stable(&downloader, keep).await?;
+            complete("prune")?;
+        }
+    }
+
+    if downloader.content_addressed {
+        let removed = downloader.remove_unlinked_objects().await?;
+        if removed > 0 {
+            log::info!("Removed {} objects that are no longer linked.", removed);
+        }
+    }
+
+    downloader.log_summary();
+    if let Some(report) = &downloader.report {
+        report.flush()?;
+    }
+
+    state.set_failed(downloader.stats.failed.lock().unwrap().clone());
+    state.save()?;
+    journal.finish()?;
+
+    if downloader.events {
+        events::emit(&events::Event::finished(&downloader.stats, None));
+    }
+    if let Some(tracer) = &downloader.tracer {
+        tracer.finish(&downloader.stats, None).await;
+    }
+    systemd::notify("STATUS=Sync finished");
+
+    let failed = downloader.stats.failed.lock().unwrap().len();
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("100b"), Ok(100));
+        assert_eq!(parse_size("10k"), Ok(10_000));
+        assert_eq!(parse_size("10 KiB"), Ok(10 * 1024));
+        assert_eq!(parse_size("1.5GB"), Ok(1_500_000_000));
+        assert_eq!(parse_size("2tib"), Ok(2 << 40));
+        assert_eq!(parse_size("0.5MiB"), Ok(512 * 1024));
+    }
+
+    #[test]
+    fn size_errors() {
+        assert!(parse_size("10x").unwrap_err().contains("unit"));
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("1.2.3GB").is_err());
+        assert!(parse_size("-1GB").is_err());
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration(" 6 h "), Ok(Duration::from_secs(6 * 3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+    }
+
+    #[test]
+    fn duration_errors() {
+        assert!(parse_duration("10w").unwrap_err().contains("unit"));
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("18446744073709551615d")
+            .unwrap_err()
+            .contains("too long"));
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(
+            parse_resolve("static.rust-lang.org:443:1.2.3.4"),
+            Ok((
+                "static.rust-lang.org".to_string(),
+                "1.2.3.4:443".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_resolve("static.rust-lang.org:443:[::1]"),
+            Ok((
+                "static.rust-lang.org".to_string(),
+                "[::1]:443".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_resolve("localhost:80:2001:db8::1").unwrap().1,
+            "[2001:db8::1]:80".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn resolve_errors() {
+        assert!(parse_resolve("static.rust-lang.org:443").is_err());
+        assert!(parse_resolve(":443:1.2.3.4").is_err());
+        assert!(parse_resolve("static.rust-lang.org:https:1.2.3.4").is_err());
+        assert!(parse_resolve("static.rust-lang.org:70000:1.2.3.4").is_err());
+        assert!(parse_resolve("static.rust-lang.org:443:1.2.3").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use chrono::NaiveDate;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Result;
+use std::path::Path;
+
+use crate::download::RUSTLANG_ROOT_URL;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Target {
+    #[serde(default)]
+    pub available: bool,
+    pub url: Option<String>,
+    pub xz_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Package {
+    pub version: String,
+    #[serde(default)]
+    pub target: BTreeMap<String, Target>,
+}
+
+/// Splits a dated channel (`nightly-2021-06-01`) into the channel and the date.
+pub fn dated(channel: &str) -> Option<(&str, &str)> {
+    let split = channel.len().checked_sub(11)?;
+    let (name, date) = (channel.get(..split)?, channel.get(split + 1..)?);
+
+    if channel[split..].starts_with('-') && NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok() {
+        Some((name, date))
+    } else {
+        None
+    }
+}
+
+/// The path of the manifest of a channel. Dated channels refer to the manifest published on that
+/// date, as with `rustup toolchain install nightly-2021-06-01`.
+pub fn path(channel: &str) -> String {
+    match dated(channel) {
+        Some((name, date)) => format!("/dist/{}/channel-rust-{}.toml", date, name),
+        None => format!("/dist/channel-rust-{}.toml", channel),
+    }
+}
+
+/// A package that has been renamed; the manifest lists the package under its new name.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rename {
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArtifactFile {
+    pub url: String,
+}
+
+/// Files that are not rustup components, such as source tarballs and installers.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Artifact {
+    #[serde(default)]
+    pub target: BTreeMap<String, Vec<ArtifactFile>>,
+}
+
+/// An archive of a package for a target, selected for mirroring.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub pkg: String,
+    pub target: String,
+    pub url: String,
+}
+
+/// A channel manifest (`channel-rust-<channel>.toml`, manifest version 2).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub date: String,
+    #[serde(default)]
+    pub pkg: BTreeMap<String, Package>,
+    #[serde(default)]
+    pub renames: BTreeMap<String, Rename>,
+    #[serde(default)]
+    pub artifacts: BTreeMap<String, Artifact>,
+}
+
+impl Manifest {
+    pub fn parse(manifest: &str) -> Result<Self> {
+        toml::from_str(manifest)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Fetches the manifest of `channel` directly from upstream, without storing it in the mirror.
+    pub async fn fetch(client: &Client, channel: &str) -> Result<Self> {
+        let url = format!("{}{}", RUSTLANG_ROOT_URL, path(channel));
+        let res = client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(std::io::Error::other)?;
+        let text = res.text().await.map_err(std::io::Error::other)?;
+
+        Self::parse(&text)
+    }
+
+    /// The archives (tar.gz and tar.xz) whose URL mentions one of the architectures. This includes the
+    /// packages of other targets that are built for one of the architectures (e.g. a rust-std of a
+    /// cross-compilation target hosted on x86_64), and packages for all targets (`*`, rust-src) when
+    /// `*` is one of the architectures.
+    pub fn archives(&self, architectures: &[String]) -> Vec<Archive> {
+        let mut archives = Vec::new();
+
+        for (name, pkg) in &self.pkg {
+            for (target, t) in &pkg.target {
+                if !t.available {
+                    continue;
+                }
+
+                for url in t.url.iter().chain(t.xz_url.iter()) {
+                    if architectures.iter().any(|arch| url.contains(arch.as_str())) {
+                        archives.push(Archive {
+                            pkg: name.clone(),
+                            target: target.clone(),
+                            url: url.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        archives
+    }
+
+    /// The archives of the standard library (rust-std) for the targets, which is what `rustup target
+    /// add` installs for cross-compilation.
+    pub fn std_archives(&self, targets: &[String]) -> Vec<Archive> {
+        self.pkg
+            .get("rust-std")
+            .into_iter()
+            .flat_map(|pkg| pkg.target.iter())
+            .filter(|(target, t)| t.available && targets.contains(target))
+            .flat_map(|(target, t)| {
+                t.url.iter().chain(t.xz_url.iter()).map(move |url| Archive {
+                    pkg: "rust-std".to_string(),
+                    target: target.clone(),
+                    url: url.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The files of the `[artifacts]` section for one of the architectures, or for all targets (`*`).
+    pub fn artifacts(&self, architectures: &[String]) -> Vec<Archive> {
+        self.artifacts
+            .iter()
+            .flat_map(|(name, artifact)| {
+                artifact
+                    .target
+                    .iter()
+                    .filter(|(target, _)| *target == "*" || architectures.contains(target))
+                    .flat_map(move |(target, files)| {
+                        files.iter().map(move |file| Archive {
+                            pkg: name.clone(),
+                            target: target.clone(),
+                            url: file.url.clone(),
+                        })
+                    })
+            })
+            .collect()
+    }
+
+    /// The source tarball of the compiler of this release, from the `source-code` artifact if the
+    /// manifest lists it. Older manifests do not, but the tarball is published next to the archives as
+    /// `rustc-<version>-src.tar.xz` (`rustc-beta-src.tar.xz` and `rustc-nightly-src.tar.xz` for those
+    /// channels).
+    pub fn rustc_source(&self) -> Option<Archive> {
+        let listed = self
+            .artifacts
+            .get("source-code")
+            .and_then(|artifact| artifact.target.get("*"))
+            .and_then(|files| files.iter().find(|file| file.url.ends_with(".tar.xz")))
+            .map(|file| file.url.clone());
+
+        let url = match listed {
+            Some(url) => url,
+            None => {
+                let version = self.pkg.get("rust")?.version.split(' ').next()?;
+                let release = if version.contains("-beta") {
+                    "beta"
+                } else if version.contains("-nightly") {
+                    "nightly"
+                } else {
+                    version
+                };
+                format!(
+                    "{}/dist/{}/rustc-{}-src.tar.xz",
+                    RUSTLANG_ROOT_URL, self.date, release
+                )
+            }
+        };
+
+        Some(Archive {
+            pkg: "rustc-src".to_string(),
+            target: "*".to_string(),
+            url,
+        })
+    }
+
+    /// The archives of the components that are not built per target (target `*`, e.g. rust-src),
+    /// which are not among the archives of any architecture.
+    pub fn target_independent(&self, components: &[String]) -> Vec<Archive> {
+        components
+            .iter()
+            .map(|name| self.renames.get(name).map_or(name, |rename| &rename.to))
+            .filter_map(|name| Some((name, self.pkg.get(name)?.target.get("*")?)))
+            .filter(|(_, target)| target.available)
+            .flat_map(|(name, target)| {
+                target
+                    .url
+                    .iter()
+                    .chain(target.xz_url.iter())
+                    .map(move |url| Archive {
+                        pkg: name.clone(),
+                        target: "*".to_string(),
+                        url: url.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    /// The old names of a package that has been renamed.
+    pub fn aliases(&self, pkg: &str) -> Vec<&str> {
+        self.renames
+            .iter()
+            .filter(|(_, rename)| rename.to == pkg)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// The packages marked as unavailable (`available = false`) for one of the architectures.
+    pub fn unavailable(&self, architectures: &[String]) -> Vec<(&str, &str)> {
+        self.pkg
+            .iter()
+            .flat_map(|(name, pkg)| {
+                pkg.target
+                    .iter()
+                    .filter(|(target, t)| !t.available && architectures.contains(target))
+                    .map(move |(target, _)| (name.as_str(), target.as_str()))
+            })
+            .collect()
+    }
+
+    /// All targets that at least one package is available for.
+    pub fn targets(&self) -> BTreeSet<&str> {
+        self.pkg
+            .values()
+            .flat_map(|pkg| pkg.target.iter())
+            .filter(|(_, target)| target.available)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// The packages available for `target` (packages for target `*`, such as rust-src, included).
+    pub fn components(&self, target: &str) -> Vec<(&str, &Package, &Target)> {
+        self.pkg
+            .iter()
+            .filter_map(|(name, pkg)| {
+                let t = pkg.target.get(target).or_else(|| pkg.target.get("*"))?;
+                if t.available {
+                    Some((name.as_str(), pkg, t))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
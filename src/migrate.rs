@@ -0,0 +1,146 @@
+use clap::ArgMatches;
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::download::RUSTLANG_ROOT_URL;
+use crate::lock::DirectoryLock;
+use crate::manifest::Manifest;
+use crate::paths;
+use crate::scan::{self, MIRROR_DIRECTORIES};
+use crate::state::{self, State, LAYOUT_VERSION};
+
+/// Upgrades a mirror in place to the layout written by this version, so long-lived mirrors do not
+/// need to be seeded again after an upgrade. Does nothing if the mirror is up to date.
+pub async fn run(matches: &ArgMatches) -> Result<()> {
+    let output_directory = matches.value_of("OUTPUT-DIRECTORY").unwrap();
+
+    if !MIRROR_DIRECTORIES
+        .iter()
+        .any(|directory| Path::new(output_directory).join(directory).is_dir())
+    {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} is not a mirror.", output_directory),
+        ));
+    }
+
+    let _lock = DirectoryLock::acquire(output_directory, matches.is_present("wait-lock")).await?;
+    let state = State::open(output_directory)?;
+    let layout = state.layout();
+
+    if layout >= LAYOUT_VERSION {
+        log::info!(
+            "{} already has the current layout (version {}).",
+            output_directory,
+            layout
+        );
+        return Ok(());
+    }
+
+    log::info!("Scanning {}...", output_directory);
+    let files = scan::scan(output_directory, &MIRROR_DIRECTORIES).await?;
+
+    if layout < 1 {
+        upgrade_to_1(output_directory, &state, &files)?;
+    }
+
+    state.set_layout(LAYOUT_VERSION);
+    state.save()?;
+
+    log::info!(
+        "Migrated {} from layout version {} to {}.",
+        output_directory,
+        layout,
+        LAYOUT_VERSION
+    );
+
+    Ok(())
+}
+
+/// Version 1 records the mirrored channels and stable releases in the state, which mirrors seeded
+/// before the state existed lack (they are needed by e.g. `--prune-stable`). They are recomputed from
+/// the manifests and archives in the mirror. Leftovers of interrupted downloads are removed, along
+/// with the digests of files that no longer exist.
+fn upgrade_to_1(output_directory: &str, state: &State, files: &HashMap<String, u64>) -> Result<()> {
+    let mut removed = 0;
+    for path in files.keys().filter(|path| path.ends_with(".part")) {
+        std::fs::remove_file(paths::local_path(output_directory, path))?;
+        removed += 1;
+    }
+    log::info!("Removed {} partial downloads.", removed);
+
+    // `/dist/channel-rust-<channel>.toml`, or a dated copy in `/dist/<date>/`.
+    let manifest_path =
+        Regex::new(r"^/dist/(\d{4}-\d{2}-\d{2}/)?channel-rust-([^/]+)\.toml$").unwrap();
+    let channels = state.channels();
+    let stable_releases = state.stable_releases();
+
+    for path in files.keys() {
+        let captures = match manifest_path.captures(path) {
+            Some(captures) => captures,
+            None => continue,
+        };
+        let (dated, channel) = (captures.get(1).is_some(), &captures[2]);
+
+        let manifest = match Manifest::load(&paths::local_path(output_directory, path)) {
+            Ok(manifest) => manifest,
+            Err(error) => {
+                log::warn!("Skipping unreadable manifest {}: {}", path, error);
+                continue;
+            }
+        };
+
+        // The mirrored targets are those with archives in the mirror.
+        let mut architectures: Vec<String> =
+            manifest.targets().into_iter().map(String::from).collect();
+        architectures.push("*".to_string());
+
+        let mut targets = BTreeSet::new();
+        let mut mirrored = BTreeSet::new();
+        for archive in manifest.archives(&architectures) {
+            if let Some(file) = archive.url.strip_prefix(RUSTLANG_ROOT_URL) {
+                if files.contains_key(file) {
+                    mirrored.insert(file.to_string());
+                    if archive.target != "*" {
+                        targets.insert(archive.target);
+                    }
+                }
+            }
+        }
+
+        if mirrored.is_empty() {
+            continue;
+        }
+
+        if !dated && !channels.contains_key(channel) {
+            log::info!(
+                "Recording channel {} ({}) with {} archives.",
+                channel,
+                manifest.date,
+                mirrored.len()
+            );
+            state.set_channel(
+                channel,
+                state::Channel {
+                    date: manifest.date.clone(),
+                    targets: targets.into_iter().collect(),
+                    files: mirrored.iter().cloned().collect(),
+                    available_targets: manifest.targets().into_iter().map(str::to_string).collect(),
+                    packages: manifest.pkg.keys().cloned().collect(),
+                },
+            );
+        }
+
+        if channel == "stable" && !stable_releases.contains_key(&manifest.date) {
+            log::info!("Recording stable release of {}.", manifest.date);
+            state.set_stable_release(&manifest.date, mirrored.into_iter().collect());
+        }
+    }
+
+    let forgotten = state.forget_removed(|path| files.contains_key(path));
+    log::info!("Forgot the digests of {} removed files.", forgotten);
+
+    Ok(())
+}
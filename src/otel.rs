@@ -0,0 +1,305 @@
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::download::Stats;
+
+/// How often the finished spans are exported while the sync runs.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A finished span, in the OTLP/JSON encoding.
+type Span = Value;
+
+/// Exports spans of the sync, its sections and its downloads (but not the skipped files) to an
+/// OpenTelemetry collector with OTLP over HTTP (JSON encoding). Counters of the files are exported
+/// as metrics when the sync ends.
+pub struct Tracer {
+    client: Client,
+    endpoint: String,
+    trace_id: String,
+    root: (String, u64),
+    /// The span of the section being synced, and when it started.
+    section: Mutex<Option<(String, String, u64)>>,
+    spans: Mutex<Vec<Span>>,
+    ids: RandomState,
+    counter: AtomicU64,
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::Number(number) => json!({ "intValue": number.to_string() }),
+        Value::String(string) => json!({ "stringValue": string }),
+        value => json!({ "stringValue": value.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn resource() -> Value {
+    json!({
+        "attributes": [
+            attribute("service.name", json!("squire")),
+            attribute("service.version", json!(env!("CARGO_PKG_VERSION"))),
+        ],
+    })
+}
+
+impl Tracer {
+    pub fn new(client: Client, endpoint: &str) -> Arc<Self> {
+        let ids = RandomState::new();
+        let mut tracer = Self {
+            client,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            trace_id: String::new(),
+            root: (String::new(), unix_nanos(SystemTime::now())),
+            section: Mutex::new(None),
+            spans: Mutex::new(Vec::new()),
+            ids,
+            counter: AtomicU64::new(0),
+        };
+        tracer.trace_id = format!("{}{}", tracer.id(), tracer.id());
+        tracer.root.0 = tracer.id();
+
+        Arc::new(tracer)
+    }
+
+    /// A random 64-bit id, hex-encoded.
+    fn id(&self) -> String {
+        let mut hasher = self.ids.build_hasher();
+        hasher.write_u64(self.counter.fetch_add(1, Ordering::Relaxed));
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn span(
+        &self,
+        id: &str,
+        parent: Option<&str>,
+        name: &str,
+        times: (u64, u64),
+        attributes: Vec<Value>,
+        error: Option<&str>,
+    ) -> Span {
+        json!({
+            "traceId": self.trace_id,
+            "spanId": id,
+            "parentSpanId": parent.unwrap_or_default(),
+            "name": name,
+            "kind": 1,
+            "startTimeUnixNano": times.0.to_string(),
+            "endTimeUnixNano": times.1.to_string(),
+            "attributes": attributes,
+            "status": match error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({ "code": 1 }),
+            },
+        })
+    }
+
+    /// Starts the span of a section, ending the previous one if it was not completed.
+    pub fn begin_section(&self, section: &str) {
+        let previous = self.section.lock().unwrap().replace((
+            section.to_string(),
+            self.id(),
+            unix_nanos(SystemTime::now()),
+        ));
+
+        if let Some((name, id, start)) = previous {
+            self.end_section_span(&name, &id, start);
+        }
+    }
+
+    pub fn complete_section(&self, section: &str) {
+        let mut current = self.section.lock().unwrap();
+        if current.as_ref().is_some_and(|(name, _, _)| name == section) {
+            let (name, id, start) = current.take().unwrap();
+            drop(current);
+            self.end_section_span(&name, &id, start);
+        }
+    }
+
+    fn end_section_span(&self, section: &str, id: &str, start: u64) {
+        let span = self.span(
+            id,
+            Some(&self.root.0),
+            section,
+            (start, unix_nanos(SystemTime::now())),
+            vec![attribute("squire.section", json!(section))],
+            None,
+        );
+        self.spans.lock().unwrap().push(span);
+    }
+
+    /// Records the span of a file that was downloaded (or failed to), as a child of the section.
+    pub fn record_file(
+        &self,
+        url: &str,
+        path: &str,
+        action: &str,
+        start: Instant,
+        bytes: Option<u64>,
+        error: Option<&str>,
+    ) {
+        let end = SystemTime::now();
+        let start = unix_nanos(end - start.elapsed());
+        let parent = self
+            .section
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, id, _)| id.clone())
+            .unwrap_or_else(|| self.root.0.clone());
+
+        let mut attributes = vec![
+            attribute("url.full", json!(url)),
+            attribute("squire.path", json!(path)),
+            attribute("squire.action", json!(action)),
+        ];
+        if let Some(bytes) = bytes {
+            attributes.push(attribute("squire.bytes", json!(bytes)));
+        }
+
+        let span = self.span(
+            &self.id(),
+            Some(&parent),
+            "download",
+            (start, unix_nanos(end)),
+            attributes,
+            error,
+        );
+        self.spans.lock().unwrap().push(span);
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> Result<()> {
+        self.client
+            .post(format!("{}{}", self.endpoint, path))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(std::io::Error::other)?;
+
+        Ok(())
+    }
+
+    /// Sends the spans that have finished since the last export.
+    async fn export(&self) {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        if spans.is_empty() {
+            return;
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": resource(),
+                "scopeSpans": [{ "scope": { "name": "squire" }, "spans": spans }],
+            }],
+        });
+
+        if let Err(error) = self.post("/v1/traces", &body).await {
+            log::warn!("Unable to export traces to {}: {}", self.endpoint, error);
+        }
+    }
+
+    /// Exports the finished spans every `EXPORT_INTERVAL`, for as long as the tracer is in use.
+    pub fn spawn_exporter(self: &Arc<Self>) {
+        let tracer = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(EXPORT_INTERVAL).await;
+                match tracer.upgrade() {
+                    Some(tracer) => tracer.export().await,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    /// Ends the span of the sync (and of the section, if it did not complete), and exports the
+    /// remaining spans and the counters of the sync.
+    pub async fn finish(&self, stats: &Stats, error: Option<&str>) {
+        let section = self.section.lock().unwrap().take();
+        if let Some((name, id, start)) = section {
+            self.end_section_span(&name, &id, start);
+        }
+
+        let end = unix_nanos(SystemTime::now());
+        let root = self.span(
+            &self.root.0,
+            None,
+            "sync",
+            (self.root.1, end),
+            vec![],
+            error,
+        );
+        self.spans.lock().unwrap().push(root);
+        self.export().await;
+
+        let counters = [
+            (
+                "squire.files.downloaded",
+                stats.downloaded.load(Ordering::Relaxed) as u64,
+                "1",
+            ),
+            (
+                "squire.files.skipped",
+                stats.skipped.load(Ordering::Relaxed) as u64,
+                "1",
+            ),
+            (
+                "squire.files.failed",
+                stats.failures.load(Ordering::Relaxed) as u64,
+                "1",
+            ),
+            (
+                "squire.retries",
+                stats.retries.load(Ordering::Relaxed) as u64,
+                "1",
+            ),
+            (
+                "squire.downloaded",
+                stats.bytes.load(Ordering::Relaxed),
+                "By",
+            ),
+        ];
+        let metrics: Vec<Value> = counters
+            .iter()
+            .map(|(name, value, unit)| {
+                json!({
+                    "name": name,
+                    "unit": unit,
+                    "sum": {
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                        "dataPoints": [{
+                            "asInt": value.to_string(),
+                            "startTimeUnixNano": self.root.1.to_string(),
+                            "timeUnixNano": end.to_string(),
+                        }],
+                    },
+                })
+            })
+            .collect();
+
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": resource(),
+                "scopeMetrics": [{ "scope": { "name": "squire" }, "metrics": metrics }],
+            }],
+        });
+
+        if let Err(error) = self.post("/v1/metrics", &body).await {
+            log::warn!("Unable to export metrics to {}: {}", self.endpoint, error);
+        }
+    }
+}
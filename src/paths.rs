@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Paths of directories longer than this (MAX_PATH minus room for an 8.3 file name) cannot be used on
+/// Windows without the `\\?\` prefix.
+const MAX_DIRECTORY_PATH: usize = 248;
+
+/// File names that refer to devices on Windows, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes a single path component safe to use on NTFS: characters that are not allowed in file names
+/// (and trailing dots and spaces, which are silently dropped) are percent-encoded, and device names
+/// get a trailing underscore (`con` becomes `con_`).
+fn sanitize_component(component: &str) -> Cow<'_, str> {
+    let is_invalid = |c: char| c.is_control() || "<>:\"\\|?*".contains(c);
+    let stem = component.split('.').next().unwrap_or_default();
+    let is_reserved = RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem.trim_end()));
+
+    if !component.contains(is_invalid) && !component.ends_with(['.', ' ']) && !is_reserved {
+        return Cow::Borrowed(component);
+    }
+
+    let mut sanitized = String::with_capacity(component.len() + 3);
+    for (i, c) in component.char_indices() {
+        let is_trailing = component[i..].chars().all(|c| c == '.' || c == ' ');
+        if is_invalid(c) || is_trailing {
+            sanitized.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            sanitized.push(c);
+        }
+
+        if is_reserved && i + c.len_utf8() == stem.len() {
+            sanitized.push('_');
+        }
+    }
+
+    Cow::Owned(sanitized)
+}
+
+/// Adds the `\\?\` prefix to a path, which lifts the MAX_PATH limit on Windows. Does nothing on other
+/// platforms, or if the path already has the prefix.
+pub fn extended(path: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return path.to_path_buf();
+    }
+
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+
+    PathBuf::from(prefixed(&absolute.to_string_lossy()))
+}
+
+/// An absolute Windows path with the `\\?\` prefix, `\\?\UNC\` for network paths.
+fn prefixed(absolute: &str) -> String {
+    if absolute.starts_with(r"\\?\") {
+        absolute.to_string()
+    } else if let Some(unc) = absolute.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{}", unc)
+    } else {
+        format!(r"\\?\{}", absolute)
+    }
+}
+
+/// The local path of a mirror path (e.g. `/crates/a/a-0.1.0.crate`) in the output directory. On
+/// Windows, the components are sanitized and long paths get the `\\?\` prefix.
+pub fn local_path(output_directory: &str, path: &str) -> PathBuf {
+    if !cfg!(windows) {
+        return PathBuf::from(format!("{}{}", output_directory, path));
+    }
+
+    let mut local = PathBuf::from(output_directory);
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        local.push(&*sanitize_component(component));
+    }
+
+    if local.as_os_str().len() > MAX_DIRECTORY_PATH {
+        extended(&local)
+    } else {
+        local
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_names() {
+        for name in [
+            "serde",
+            "serde-1.0.130.crate",
+            "config.toml",
+            ".squire",
+            "console",
+        ] {
+            assert!(
+                matches!(sanitize_component(name), Cow::Borrowed(_)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert_eq!(sanitize_component("CON"), "CON_");
+        assert_eq!(sanitize_component("con"), "con_");
+        assert_eq!(sanitize_component("nul.txt"), "nul_.txt");
+        assert_eq!(sanitize_component("COM1"), "COM1_");
+        assert_eq!(sanitize_component("lpt9.tar.gz"), "lpt9_.tar.gz");
+        assert_eq!(sanitize_component("aux .txt"), "aux _.txt");
+    }
+
+    #[test]
+    fn invalid_characters() {
+        assert_eq!(sanitize_component("a<b>c"), "a%3Cb%3Ec");
+        assert_eq!(sanitize_component("host:port"), "host%3Aport");
+        assert_eq!(sanitize_component("\"quoted\""), "%22quoted%22");
+        assert_eq!(sanitize_component("a|b?c*"), "a%7Cb%3Fc%2A");
+        assert_eq!(sanitize_component("back\\slash"), "back%5Cslash");
+        assert_eq!(sanitize_component("tab\t"), "tab%09");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        assert_eq!(sanitize_component("name."), "name%2E");
+        assert_eq!(sanitize_component("name "), "name%20");
+        assert_eq!(sanitize_component("name. ."), "name%2E%20%2E");
+        assert_eq!(sanitize_component("a.b"), "a.b");
+    }
+
+    #[test]
+    fn long_paths() {
+        let long = format!(r"C:\mirror\{}", "a".repeat(300));
+        assert!(long.len() > 260);
+        assert_eq!(prefixed(&long), format!(r"\\?\{}", long));
+        assert_eq!(
+            prefixed(r"\\server\share\mirror"),
+            r"\\?\UNC\server\share\mirror"
+        );
+        assert_eq!(prefixed(r"\\?\C:\mirror"), r"\\?\C:\mirror");
+    }
+
+    #[test]
+    fn local_paths() {
+        let crate_path = format!("/crates/{0}/{0}-0.1.0.crate", "a".repeat(300));
+        let local = local_path("mirror", &crate_path);
+
+        if cfg!(windows) {
+            assert!(local.to_string_lossy().starts_with(r"\\?\"));
+            assert_eq!(
+                local_path("mirror", "/crates/con/con-0.1.0.crate"),
+                Path::new(r"mirror\crates\con_\con-0.1.0.crate")
+            );
+        } else {
+            assert_eq!(local, PathBuf::from(format!("mirror{}", crate_path)));
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Modes and ownership applied to the files and directories written to the mirror, instead of the
+/// defaults from the umask and the user running the sync.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Permissions {
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    /// User and group ids.
+    pub owner: Option<(Option<u32>, Option<u32>)>,
+}
+
+pub fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("Invalid mode (expected octal, e.g. 0644): {}", s))
+}
+
+#[cfg(unix)]
+fn lookup(name: &str, user: bool) -> std::result::Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let c_name = std::ffi::CString::new(name).map_err(|err| err.to_string())?;
+
+    // SAFETY: The name is a valid C string, and the returned entry is only read before any other
+    // lookup could overwrite it (the options are parsed before anything else runs).
+    let id = unsafe {
+        if user {
+            let entry = libc::getpwnam(c_name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).pw_uid)
+        } else {
+            let entry = libc::getgrnam(c_name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).gr_gid)
+        }
+    };
+
+    id.ok_or_else(|| format!("Unknown {}: {}", if user { "user" } else { "group" }, name))
+}
+
+/// Parses `user`, `user:group` or `:group`, where users and groups are names or numeric ids.
+#[cfg(unix)]
+pub fn parse_owner(s: &str) -> std::result::Result<(Option<u32>, Option<u32>), String> {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return Err("Changing the owner of files requires running as root.".to_string());
+    }
+
+    let (user, group) = s.split_once(':').unwrap_or((s, ""));
+
+    let user = Some(user)
+        .filter(|user| !user.is_empty())
+        .map(|user| lookup(user, true))
+        .transpose()?;
+    let group = Some(group)
+        .filter(|group| !group.is_empty())
+        .map(|group| lookup(group, false))
+        .transpose()?;
+
+    Ok((user, group))
+}
+
+#[cfg(not(unix))]
+pub fn parse_owner(_: &str) -> std::result::Result<(Option<u32>, Option<u32>), String> {
+    Err("Changing the owner of files is not supported on this platform.".to_string())
+}
+
+impl Permissions {
+    pub fn apply_file(&self, path: &Path) -> Result<()> {
+        self.apply(path, self.file_mode)
+    }
+
+    pub fn apply_dir(&self, path: &Path) -> Result<()> {
+        self.apply(path, self.dir_mode)
+    }
+
+    #[cfg(unix)]
+    fn apply(&self, path: &Path, mode: Option<u32>) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+
+        if let Some((user, group)) = self.owner {
+            std::os::unix::fs::chown(path, user, group)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _: &Path, mode: Option<u32>) -> Result<()> {
+        match mode {
+            Some(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "File modes are not supported on this platform.",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates a directory and all of its missing parents, applying the directory mode and owner to
+    /// the directories that are created.
+    pub fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if path.as_os_str().is_empty() || path.is_dir() {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        match std::fs::create_dir(path) {
+            Ok(()) => self.apply_dir(path),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+            Err(error) => Err(Error::new(
+                error.kind(),
+                format!("Unable to create {}: {}", path.display(), error),
+            )),
+        }
+    }
+}
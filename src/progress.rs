@@ -0,0 +1,150 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::download::Stats;
+use crate::{format_size, logging};
+
+/// How often the progress bars are updated.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Downloads of at least this size get a progress bar of their own.
+const LARGE_DOWNLOAD: u64 = 10 * 1024 * 1024;
+
+const SECTION_TEMPLATE: &str =
+    "{prefix:>14.cyan.bold} [{bar:40}] {pos}/{len} files · {msg} · ETA {eta}";
+const SECTION_UNKNOWN_TEMPLATE: &str = "{prefix:>14.cyan.bold} {spinner} {pos} files · {msg}";
+const DOWNLOAD_TEMPLATE: &str = "{msg:>40.dim} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}";
+
+/// Progress bars of a sync in the terminal: one for the files of the section being synced, and one
+/// for every large download in progress. The log lines are printed above them until they are
+/// dropped.
+pub struct Bars {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars("=> ")
+}
+
+impl Bars {
+    /// Shows the progress bars, unless stdout is not a terminal (e.g. a log file), which only gets
+    /// the log lines.
+    pub fn start(stats: Arc<Stats>) -> Option<Self> {
+        if !std::io::stdout().is_terminal() {
+            return None;
+        }
+
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+        logging::print_with(Some(Box::new({
+            let multi = multi.clone();
+            move |line| {
+                let _ = multi.println(line);
+            }
+        })));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || run(&multi, &stats, &stop))
+        };
+
+        Some(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Bars {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        logging::print_with(None);
+    }
+}
+
+fn run(multi: &MultiProgress, stats: &Stats, stop: &AtomicBool) {
+    let section = multi.add(ProgressBar::new(0));
+    let mut current = None;
+    let mut downloads: HashMap<usize, ProgressBar> = HashMap::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        let name = stats
+            .section
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(name, _)| name.clone());
+        let total = stats.total.load(Ordering::Relaxed) as u64;
+
+        // The ETA is estimated from the progress of the section only.
+        if name != current {
+            section.reset();
+            section.set_style(style(if total == 0 {
+                SECTION_UNKNOWN_TEMPLATE
+            } else {
+                SECTION_TEMPLATE
+            }));
+            section.set_prefix(name.clone().unwrap_or_else(|| "starting".to_string()));
+            current = name;
+        }
+        section.set_length(total);
+        section.set_position(stats.done.load(Ordering::Relaxed) as u64);
+        section.set_message(format!(
+            "{} downloaded · {} failed",
+            format_size(stats.bytes.load(Ordering::Relaxed)),
+            stats.failed.lock().unwrap().len()
+        ));
+        section.tick();
+
+        let transfers: Vec<_> = stats
+            .transfers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, transfer)| {
+                transfer
+                    .length
+                    .is_some_and(|length| length >= LARGE_DOWNLOAD)
+            })
+            .map(|(id, transfer)| (*id, transfer.clone()))
+            .collect();
+
+        downloads.retain(|id, bar| {
+            let active = transfers.iter().any(|(active, _)| active == id);
+            if !active {
+                bar.finish_and_clear();
+            }
+            active
+        });
+        for (id, transfer) in transfers {
+            let bar = downloads.entry(id).or_insert_with(|| {
+                let name = transfer.url.rsplit('/').next().unwrap_or_default();
+                multi.add(
+                    ProgressBar::new(transfer.length.unwrap_or_default())
+                        .with_style(style(DOWNLOAD_TEMPLATE))
+                        .with_message(name.to_string()),
+                )
+            });
+            bar.set_position(transfer.received.load(Ordering::Relaxed));
+        }
+
+        std::thread::sleep(TICK);
+    }
+
+    for bar in downloads.values() {
+        bar.finish_and_clear();
+    }
+    section.finish_and_clear();
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::manifest;
+
+/// Directories that never contain toolchain declarations of their own, and can be very large.
+const SKIPPED_DIRECTORIES: [&str; 2] = ["target", "node_modules"];
+
+/// Normalizes a toolchain name to a channel that can be mirrored, e.g. `stable-x86_64-pc-windows-msvc`
+/// to `stable`. Returns `None` for toolchains that are not published channels (custom toolchains, paths).
+fn channel(toolchain: &str) -> Option<String> {
+    let toolchain = toolchain.trim();
+
+    for name in ["stable", "beta", "nightly"] {
+        if let Some(rest) = toolchain.strip_prefix(name) {
+            // Dated toolchains, optionally followed by a host triple (`nightly-2021-06-01-x86_64-...`).
+            if let Some(dated) = toolchain.get(..name.len() + 11) {
+                if manifest::dated(dated).is_some() {
+                    return Some(dated.to_string());
+                }
+            }
+
+            if rest.is_empty() || rest.starts_with('-') {
+                return Some(name.to_string());
+            }
+        }
+    }
+
+    // Versions (`1.56`, `1.56.1`), optionally followed by a host triple.
+    let version = toolchain.split('-').next()?;
+    let is_version = version.split('.').count() >= 2
+        && version
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+
+    if is_version {
+        Some(version.to_string())
+    } else {
+        None
+    }
+}
+
+/// The toolchain of a `rust-toolchain.toml` or `rust-toolchain` file, which is either TOML with a
+/// `[toolchain]` table or (the legacy format) just the name of the toolchain.
+fn toolchain_file(contents: &str) -> Option<String> {
+    match contents.parse::<toml::Value>() {
+        Ok(value) => value
+            .get("toolchain")?
+            .get("channel")?
+            .as_str()
+            .map(str::to_string),
+        Err(_) => contents.lines().next().map(str::to_string),
+    }
+}
+
+/// A toolchain pinned by a `rust-toolchain.toml` file.
+#[derive(Debug, Clone)]
+pub struct Toolchain {
+    pub channel: String,
+    pub components: Vec<String>,
+    pub targets: Vec<String>,
+}
+
+/// Reads the channel, components and targets of a `rust-toolchain.toml` or `rust-toolchain` file.
+pub fn toolchain(path: &str) -> Result<Toolchain> {
+    let contents = std::fs::read_to_string(path)?;
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+
+    let declared = toolchain_file(&contents)
+        .ok_or_else(|| invalid(format!("No toolchain channel in {}.", path)))?;
+    let channel = channel(&declared).ok_or_else(|| {
+        invalid(format!(
+            "The toolchain \"{}\" in {} is not a channel that can be mirrored.",
+            declared, path
+        ))
+    })?;
+
+    let list = |key: &str| -> Vec<String> {
+        contents
+            .parse::<toml::Value>()
+            .ok()
+            .and_then(|value| value.get("toolchain")?.get(key)?.as_array().cloned())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|item| item.as_str().map(str::to_string))
+            .collect()
+    };
+
+    Ok(Toolchain {
+        channel,
+        components: list("components"),
+        targets: list("targets"),
+    })
+}
+
+/// The minimum supported Rust version of a `Cargo.toml`, of either the package or the workspace.
+fn rust_version(contents: &str) -> Option<String> {
+    let value = contents.parse::<toml::Value>().ok()?;
+
+    value
+        .get("package")
+        .and_then(|package| package.get("rust-version"))
+        .or_else(|| value.get("workspace")?.get("package")?.get("rust-version"))?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Calls `visit` with the path and name of every file in the directory and its subdirectories.
+fn walk(directory: &Path, visit: &mut impl FnMut(&Path, &str) -> Result<()>) -> Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if entry.file_type()?.is_dir() {
+            if !name.starts_with('.') && !SKIPPED_DIRECTORIES.contains(&name.as_str()) {
+                walk(&path, visit)?;
+            }
+        } else {
+            visit(&path, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the channel declared by a toolchain file or `Cargo.toml`.
+fn visit(path: &Path, name: &str, channels: &mut BTreeSet<String>) -> Result<()> {
+    let declared = match name {
+        "rust-toolchain.toml" | "rust-toolchain" => toolchain_file(&std::fs::read_to_string(path)?),
+        "Cargo.toml" => rust_version(&std::fs::read_to_string(path)?),
+        _ => return Ok(()),
+    };
+
+    if let Some(declared) = declared {
+        match channel(&declared) {
+            Some(channel) => {
+                log::debug!("{} requires channel {}", path.display(), channel);
+                channels.insert(channel);
+            }
+            None => log::warn!(
+                "Ignoring toolchain \"{}\" in {}, it is not a channel that can be mirrored.",
+                declared,
+                path.display()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the channels and versions required by the projects in a directory, through their
+/// `rust-toolchain.toml`/`rust-toolchain` files and `rust-version` (MSRV) declarations.
+pub fn scan(directory: &str) -> Result<BTreeSet<String>> {
+    let mut channels = BTreeSet::new();
+    walk(Path::new(directory), &mut |path, name| {
+        visit(path, name, &mut channels)
+    })?;
+
+    Ok(channels)
+}
+
+/// The crates.io packages of a `Cargo.lock`: (name, version). Path and git dependencies, and packages
+/// from other registries, are skipped.
+fn lockfile_packages(contents: &str) -> Vec<(String, String)> {
+    let value = match contents.parse::<toml::Value>() {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+
+    value
+        .get("package")
+        .and_then(|packages| packages.as_array())
+        .map(|packages| {
+            packages
+                .iter()
+                .filter(|package| {
+                    package
+                        .get("source")
+                        .and_then(|source| source.as_str())
+                        .is_some_and(|source| {
+                            source == "registry+https://github.com/rust-lang/crates.io-index"
+                                || source == "sparse+https://index.crates.io/"
+                        })
+                })
+                .filter_map(|package| {
+                    Some((
+                        package.get("name")?.as_str()?.to_string(),
+                        package.get("version")?.as_str()?.to_string(),
+                    ))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Finds every `Cargo.lock` in a directory, and returns the union of their crates.io packages.
+pub fn lockfiles(directory: &str) -> Result<HashSet<(String, String)>> {
+    let mut packages = HashSet::new();
+    let mut lockfiles = 0;
+
+    walk(Path::new(directory), &mut |path, name| {
+        if name == "Cargo.lock" {
+            let found = lockfile_packages(&std::fs::read_to_string(path)?);
+            if found.is_empty() {
+                log::warn!("No crates.io packages found in {}.", path.display());
+            }
+            log::debug!("{} locks {} packages", path.display(), found.len());
+            packages.extend(found);
+            lockfiles += 1;
+        }
+        Ok(())
+    })?;
+
+    log::info!(
+        "Found {} packages in {} lockfiles in {}.",
+        packages.len(),
+        lockfiles,
+        directory
+    );
+
+    Ok(packages)
+}
@@ -0,0 +1,54 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// What happened to a file of the mirror.
+#[derive(Debug, Clone, Serialize)]
+pub struct Record<'a> {
+    pub path: &'a str,
+    pub url: &'a str,
+    /// `downloaded`, `skipped`, `not_found`, `failed` or `error` (writing to the mirror failed).
+    pub action: &'a str,
+    /// Size of the file in the mirror afterwards, if it exists.
+    pub bytes: Option<u64>,
+    pub duration_ms: u128,
+    pub error: Option<String>,
+}
+
+/// A newline-delimited JSON report with a record per file processed by a sync, for auditing what
+/// changed.
+pub struct Report {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Report {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&self, record: &Record<'_>) {
+        let mut file = self.file.lock().unwrap();
+        let result = serde_json::to_writer(&mut *file, record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| file.write_all(b"\n"));
+
+        if let Err(error) = result {
+            log::warn!("Unable to write to the file report: {}", error);
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
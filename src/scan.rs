@@ -0,0 +1,82 @@
+use futures_util::{stream, StreamExt};
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+/// The top-level directories of a mirror.
+pub const MIRROR_DIRECTORIES: [&str; 7] = [
+    "rustup",
+    "dist",
+    "index",
+    "filtered-index",
+    "crates",
+    "quickinstall",
+    "rustup-components-history",
+];
+
+/// Recursively collects all files below `directory` as mirror paths (e.g. `/crates/a/a-0.1.0.crate`)
+/// relative to `root`, along with their sizes.
+fn walk(root: &Path, directory: &Path, entries: &mut Vec<(String, u64)>) -> Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            walk(root, &entry.path(), entries)?;
+        } else if file_type.is_file() {
+            let path = entry.path();
+            if let Ok(relative) = path.strip_prefix(root) {
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                entries.push((format!("/{}", relative), entry.metadata()?.len()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Walks the given top-level directories (e.g. `crates` and `dist`) of the mirror in parallel, one
+/// blocking task per subdirectory, and returns a map of mirror path to file size.
+pub async fn scan(output_directory: &str, directories: &[&str]) -> Result<HashMap<String, u64>> {
+    // Use the extended form of the root, so deeply nested files can be read on Windows.
+    let root = crate::paths::extended(&PathBuf::from(output_directory));
+    let mut files = HashMap::new();
+    let mut subdirectories = Vec::new();
+
+    for directory in directories {
+        let path = root.join(directory);
+        if !path.is_dir() {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                subdirectories.push(entry.path());
+            } else if let Ok(size) = entry.metadata().map(|m| m.len()) {
+                files.insert(
+                    format!("/{}/{}", directory, entry.file_name().to_string_lossy()),
+                    size,
+                );
+            }
+        }
+    }
+
+    let parallelism = crate::download::num_cpus();
+
+    let mut results = stream::iter(subdirectories)
+        .map(|directory| {
+            let root = root.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut entries = Vec::new();
+                walk(&root, &directory, &mut entries).map(|_| entries)
+            })
+        })
+        .buffer_unordered(parallelism);
+
+    while let Some(result) = results.next().await {
+        files.extend(result.map_err(std::io::Error::other)??);
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,346 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const STATE_FILE: &str = ".squire/state.json";
+
+/// The digests and verified checksums of the files, appended to as they are recorded rather than
+/// written with the rest of the state, which would mean serializing all of them on every save.
+pub const DIGESTS_FILE: &str = ".squire/digests.jsonl";
+
+/// The version of the layout of the mirror and its metadata written by this version. Mirrors with an
+/// earlier layout are upgraded with `squire migrate`.
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// A BLAKE3 digest of a file in the mirror, recorded after its SHA-256 digest was verified.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalDigest {
+    pub size: u64,
+    pub blake3: String,
+    /// The SHA-256 digest it was verified against, empty for digests recorded by earlier versions.
+    #[serde(default)]
+    pub sha256: String,
+}
+
+/// The SHA-256 digest a file in the mirror was last verified against, with the size and modification
+/// time (in nanoseconds since the epoch) it had then. The file is not hashed again while they match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifiedChecksum {
+    pub size: u64,
+    pub modified: u64,
+    pub sha256: String,
+}
+
+/// A channel as it was last mirrored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Channel {
+    /// Date of the channel manifest.
+    pub date: String,
+    pub targets: Vec<String>,
+    /// Mirror paths of the archives referenced by the manifest.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// All targets and packages in the manifest, to tell which ones are new in the next sync.
+    #[serde(default)]
+    pub available_targets: Vec<String>,
+    #[serde(default)]
+    pub packages: Vec<String>,
+}
+
+/// A line of `DIGESTS_FILE`. Later lines for a path replace earlier ones.
+#[derive(Debug, Serialize, Deserialize)]
+struct DigestRecord {
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<LocalDigest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verified: Option<VerifiedChecksum>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Data {
+    /// Version of the layout of the mirror, 0 for mirrors from before it was recorded.
+    #[serde(default)]
+    layout: u32,
+    /// Section (`rustup`, `dist:<channel>`, `crates`, ...) to the time it last completed.
+    #[serde(default)]
+    sections: BTreeMap<String, String>,
+    #[serde(default)]
+    channels: BTreeMap<String, Channel>,
+    /// Manifest date of each stable release that has been mirrored, to the mirror paths of its
+    /// archives.
+    #[serde(default)]
+    stable_releases: BTreeMap<String, Vec<String>>,
+    /// URLs that could not be downloaded by the last sync.
+    #[serde(default)]
+    failed: Vec<String>,
+    /// Mirror path to local digest, from `DIGESTS_FILE` (or the state of earlier versions).
+    #[serde(default, skip_serializing)]
+    digests: HashMap<String, LocalDigest>,
+    /// Mirror path to the checksum it was last verified against.
+    #[serde(default, skip_serializing)]
+    verified: HashMap<String, VerifiedChecksum>,
+    /// Mirror path of files that were not found upstream, to the version of the manifest (the date of
+    /// a channel manifest) that referenced them.
+    #[serde(default)]
+    missing: HashMap<String, String>,
+}
+
+/// Information about the files of the mirror that is kept between runs, unlike the journal.
+pub struct State {
+    path: PathBuf,
+    data: Mutex<Data>,
+    digests_path: PathBuf,
+    /// Appends to `DIGESTS_FILE`, opened with the first record.
+    digests_file: Mutex<Option<BufWriter<File>>>,
+    /// Whether `DIGESTS_FILE` is rewritten with only the current records on the next save.
+    compact: Mutex<bool>,
+}
+
+/// Reads the records of `DIGESTS_FILE` into `data`, returning the number of lines. A line cut short by
+/// a crash is ignored.
+fn read_digests(path: &Path, data: &mut Data) -> Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error),
+    };
+
+    let mut lines = 0;
+    for line in BufReader::new(file).lines() {
+        lines += 1;
+        let record: DigestRecord = match serde_json::from_str(&line?) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        if let Some(digest) = record.digest {
+            data.digests.insert(record.path.clone(), digest);
+        }
+        if let Some(verified) = record.verified {
+            data.verified.insert(record.path, verified);
+        }
+    }
+
+    Ok(lines)
+}
+
+impl State {
+    pub fn open(output_directory: &str) -> Result<Self> {
+        let path = Path::new(output_directory).join(STATE_FILE);
+        let digests_path = Path::new(output_directory).join(DIGESTS_FILE);
+
+        // A mirror without a state is new, unless it has files from a version that did not record one.
+        let mut data: Data = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else if crate::scan::MIRROR_DIRECTORIES
+            .iter()
+            .any(|directory| Path::new(output_directory).join(directory).is_dir())
+        {
+            Data::default()
+        } else {
+            Data {
+                layout: LAYOUT_VERSION,
+                ..Default::default()
+            }
+        };
+
+        // The digests in the state of earlier versions are moved to `DIGESTS_FILE` on the next save,
+        // and the file is compacted once most of its lines have been replaced by later ones.
+        let legacy = !data.digests.is_empty() || !data.verified.is_empty();
+        let lines = read_digests(&digests_path, &mut data)?;
+        let compact = legacy || lines > 2 * (data.digests.len() + data.verified.len()) + 10_000;
+
+        Ok(Self {
+            path,
+            data: Mutex::new(data),
+            digests_path,
+            digests_file: Mutex::new(None),
+            compact: Mutex::new(compact),
+        })
+    }
+
+    /// Appends a record to `DIGESTS_FILE`. It is buffered, and flushed by `save`.
+    fn append_digest(&self, record: &DigestRecord) {
+        let mut file = self.digests_file.lock().unwrap();
+        let result = (|| -> Result<()> {
+            if file.is_none() {
+                if let Some(parent) = self.digests_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let opened = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.digests_path)?;
+                *file = Some(BufWriter::new(opened));
+            }
+            let writer = file.as_mut().unwrap();
+            serde_json::to_writer(&mut *writer, record)?;
+            writer.write_all(b"\n")
+        })();
+        drop(file);
+
+        // The record is kept in memory, and written by the compaction of the next save.
+        if let Err(error) = result {
+            log::warn!("Unable to write {}: {}", self.digests_path.display(), error);
+            *self.compact.lock().unwrap() = true;
+        }
+    }
+
+    /// Rewrites `DIGESTS_FILE` with one line per file.
+    fn compact_digests(&self, data: &Data) -> Result<()> {
+        // Pending lines are dropped, the records they hold are in `data`. Appending waits for the
+        // rewritten file.
+        let mut file = self.digests_file.lock().unwrap();
+        *file = None;
+
+        let tmp = self.digests_path.with_extension("jsonl.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let paths: std::collections::BTreeSet<&String> =
+            data.digests.keys().chain(data.verified.keys()).collect();
+        for path in paths {
+            let record = DigestRecord {
+                path: path.clone(),
+                digest: data.digests.get(path).cloned(),
+                verified: data.verified.get(path).cloned(),
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner().map_err(|error| error.into_error())?;
+        std::fs::rename(tmp, &self.digests_path)
+    }
+
+    pub fn layout(&self) -> u32 {
+        self.data.lock().unwrap().layout
+    }
+
+    pub fn set_layout(&self, layout: u32) {
+        self.data.lock().unwrap().layout = layout;
+    }
+
+    pub fn sections(&self) -> BTreeMap<String, String> {
+        self.data.lock().unwrap().sections.clone()
+    }
+
+    pub fn set_completed(&self, section: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.sections
+            .insert(section.to_string(), chrono::Local::now().to_rfc3339());
+    }
+
+    pub fn channels(&self) -> BTreeMap<String, Channel> {
+        self.data.lock().unwrap().channels.clone()
+    }
+
+    pub fn set_channel(&self, name: &str, channel: Channel) {
+        let mut data = self.data.lock().unwrap();
+        data.channels.insert(name.to_string(), channel);
+    }
+
+    pub fn stable_releases(&self) -> BTreeMap<String, Vec<String>> {
+        self.data.lock().unwrap().stable_releases.clone()
+    }
+
+    pub fn set_stable_release(&self, date: &str, files: Vec<String>) {
+        let mut data = self.data.lock().unwrap();
+        data.stable_releases.insert(date.to_string(), files);
+    }
+
+    pub fn remove_stable_release(&self, date: &str) {
+        self.data.lock().unwrap().stable_releases.remove(date);
+    }
+
+    pub fn failed(&self) -> Vec<String> {
+        self.data.lock().unwrap().failed.clone()
+    }
+
+    pub fn set_failed(&self, failed: Vec<String>) {
+        self.data.lock().unwrap().failed = failed;
+    }
+
+    pub fn digest(&self, path: &str) -> Option<LocalDigest> {
+        self.data.lock().unwrap().digests.get(path).cloned()
+    }
+
+    pub fn set_digest(&self, path: &str, digest: LocalDigest) {
+        // Recorded before it is appended, so a compaction in between does not lose it.
+        self.data
+            .lock()
+            .unwrap()
+            .digests
+            .insert(path.to_string(), digest.clone());
+        self.append_digest(&DigestRecord {
+            path: path.to_string(),
+            digest: Some(digest),
+            verified: None,
+        });
+    }
+
+    pub fn verified(&self, path: &str) -> Option<VerifiedChecksum> {
+        self.data.lock().unwrap().verified.get(path).cloned()
+    }
+
+    pub fn set_verified(&self, path: &str, verified: VerifiedChecksum) {
+        self.data
+            .lock()
+            .unwrap()
+            .verified
+            .insert(path.to_string(), verified.clone());
+        self.append_digest(&DigestRecord {
+            path: path.to_string(),
+            digest: None,
+            verified: Some(verified),
+        });
+    }
+
+    /// Removes the digests and verified checksums of the files for which `exists` is false, returning
+    /// how many files were forgotten.
+    pub fn forget_removed(&self, exists: impl Fn(&str) -> bool) -> usize {
+        let mut data = self.data.lock().unwrap();
+        let before = data.digests.len() + data.verified.len();
+        data.digests.retain(|path, _| exists(path));
+        data.verified.retain(|path, _| exists(path));
+
+        let forgotten = before - data.digests.len() - data.verified.len();
+        if forgotten > 0 {
+            *self.compact.lock().unwrap() = true;
+        }
+        forgotten
+    }
+
+    /// Whether the file was not found upstream when it was referenced by this version of a manifest.
+    pub fn is_missing(&self, path: &str, version: &str) -> bool {
+        let data = self.data.lock().unwrap();
+        data.missing.get(path).is_some_and(|v| v == version)
+    }
+
+    pub fn set_missing(&self, path: &str, version: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.missing.insert(path.to_string(), version.to_string());
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let data = self.data.lock().unwrap();
+
+        // The digests are written before the state, which no longer holds those of earlier versions.
+        let mut compact = self.compact.lock().unwrap();
+        if *compact {
+            self.compact_digests(&data)?;
+            *compact = false;
+        } else if let Some(file) = self.digests_file.lock().unwrap().as_mut() {
+            file.flush()?;
+        }
+
+        // Write to a temporary file and rename it, so a crash never leaves a half-written state.
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&*data)?)?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
@@ -0,0 +1,78 @@
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::format_size;
+use crate::scan::{self, MIRROR_DIRECTORIES};
+use crate::state::{State, STATE_FILE};
+
+/// Prints the state of a mirror: when each section last completed, the mirrored channels and targets,
+/// the size of each directory, and the downloads that failed in the last sync.
+pub async fn run(matches: &ArgMatches) -> Result<()> {
+    let output_directory = matches.value_of("OUTPUT-DIRECTORY").unwrap();
+
+    if !Path::new(output_directory).join(STATE_FILE).exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} has not been synced yet.", output_directory),
+        ));
+    }
+
+    let state = State::open(output_directory)?;
+
+    println!("Mirror: {}", output_directory);
+
+    println!("\nLast completed:");
+    for (section, time) in state.sections() {
+        println!("  {:<36} {}", section, time);
+    }
+
+    println!("\nChannels:");
+    for (name, channel) in state.channels() {
+        println!(
+            "  {:<16} {:<12} {}",
+            name,
+            channel.date,
+            channel.targets.join(", ")
+        );
+    }
+
+    let files = scan::scan(output_directory, &MIRROR_DIRECTORIES).await?;
+    let mut contents: BTreeMap<&str, (usize, u64)> = BTreeMap::new();
+    for (path, size) in &files {
+        let directory = path.trim_start_matches('/').split('/').next().unwrap();
+        let entry = contents.entry(directory).or_default();
+        entry.0 += 1;
+        entry.1 += size;
+    }
+
+    println!("\nContents:");
+    let (mut total_files, mut total_bytes) = (0, 0);
+    for directory in MIRROR_DIRECTORIES {
+        if let Some((files, bytes)) = contents.get(directory) {
+            println!(
+                "  {:<36} {:>8} files {:>12}",
+                directory,
+                files,
+                format_size(*bytes)
+            );
+            total_files += files;
+            total_bytes += bytes;
+        }
+    }
+    println!(
+        "  {:<36} {:>8} files {:>12}",
+        "total",
+        total_files,
+        format_size(total_bytes)
+    );
+
+    let failed = state.failed();
+    println!("\nFailed downloads ({}):", failed.len());
+    for url in failed {
+        println!("  {}", url);
+    }
+
+    Ok(())
+}
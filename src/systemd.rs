@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+/// Sends a state (e.g. `READY=1` or `STATUS=...`) to systemd when running as a `Type=notify` service,
+/// see sd_notify(3). Does nothing when not started by systemd.
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // Sockets starting with `@` are in the abstract namespace.
+        let address = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+
+    if let Err(error) = result {
+        log::debug!("Unable to notify systemd ({}): {}", state, error);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_: &str) {}
+
+/// The watchdog interval set with `WatchdogSec=` in the service, if it applies to this process.
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    std::env::var("WATCHDOG_USEC")
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Sends watchdog keepalives at half the interval systemd expects them, for as long as the runtime
+/// keeps running tasks. If the sync hangs so that they stop, systemd restarts the service.
+pub fn spawn_watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        log::debug!(
+            "Sending systemd watchdog keepalives every {:?}.",
+            interval / 2
+        );
+
+        tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(interval / 2);
+            loop {
+                keepalive.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
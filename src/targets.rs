@@ -0,0 +1,257 @@
+use regex::Regex;
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    /// A plain target triple (e.g. `x86_64-unknown-linux-gnu`). Matches that exact target if it is
+    /// present in the manifest, otherwise it is treated as a regular expression to remain compatible
+    /// with the original regex-only filter (e.g. `x86_64`).
+    Literal(String),
+    Glob(Regex),
+    Regex(Regex),
+}
+
+/// Selects target triples from a comma-separated list of exact triples, globs (`*-linux-gnu`) and
+/// regular expressions (`regex:^x86_64.*musl$`, or anything that is not a plain triple or glob),
+/// minus the targets matched by the exclusions (`--exclude-targets`).
+#[derive(Debug, Clone)]
+pub struct TargetFilter {
+    source: String,
+    patterns: Vec<Pattern>,
+    exclusions: Vec<Pattern>,
+}
+
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern).unwrap()
+}
+
+fn parse_patterns(s: &str) -> Result<Vec<Pattern>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let is_plain = |c: char| c.is_ascii_alphanumeric() || "_.-".contains(c);
+
+            if let Some(regex) = item.strip_prefix("regex:") {
+                Regex::new(regex)
+                    .map(Pattern::Regex)
+                    .map_err(|e| e.to_string())
+            } else if let Some(glob) = item.strip_prefix("glob:") {
+                Ok(Pattern::Glob(glob_to_regex(glob)))
+            } else if item.chars().all(is_plain) {
+                Ok(Pattern::Literal(item.to_string()))
+            } else if item.chars().all(|c| is_plain(c) || c == '*' || c == '?')
+                && !item.contains(".*")
+            {
+                Ok(Pattern::Glob(glob_to_regex(item)))
+            } else {
+                Regex::new(item)
+                    .map(Pattern::Regex)
+                    .map_err(|e| e.to_string())
+            }
+        })
+        .collect()
+}
+
+/// The `available` targets matched by a pattern.
+fn matching<'a>(pattern: &Pattern, available: &'a [String]) -> Result<Vec<&'a String>, String> {
+    Ok(match pattern {
+        Pattern::Literal(literal) if available.contains(literal) => {
+            available.iter().filter(|t| *t == literal).collect()
+        }
+        Pattern::Literal(literal) => {
+            let regex = Regex::new(literal).map_err(|e| e.to_string())?;
+            available.iter().filter(|t| regex.is_match(t)).collect()
+        }
+        Pattern::Glob(regex) | Pattern::Regex(regex) => {
+            available.iter().filter(|t| regex.is_match(t)).collect()
+        }
+    })
+}
+
+impl TargetFilter {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let patterns = parse_patterns(s)?;
+
+        if patterns.is_empty() {
+            return Err("No targets specified.".to_string());
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            patterns,
+            exclusions: Vec::new(),
+        })
+    }
+
+    /// Leaves out the targets matched by `s`, in the same syntax, from those that are selected. An
+    /// exclusion that matches none of them is fine, e.g. a target that is no longer built.
+    pub fn exclude(mut self, s: &str) -> Result<Self, String> {
+        self.exclusions.extend(parse_patterns(s)?);
+        Ok(self)
+    }
+
+    /// Returns the subset of `available` targets selected by this filter. Fails if any of the
+    /// patterns does not match a single available target, which usually indicates a typo.
+    pub fn select(&self, available: &[String]) -> Result<Vec<String>, String> {
+        let mut selected: Vec<String> = Vec::new();
+
+        for pattern in &self.patterns {
+            let matches = matching(pattern, available)?;
+
+            if matches.is_empty() {
+                let name = match pattern {
+                    Pattern::Literal(literal) => literal.clone(),
+                    Pattern::Glob(regex) | Pattern::Regex(regex) => regex.to_string(),
+                };
+                return Err(format!(
+                    "Target pattern \"{}\" (from \"{}\") does not match any available target.",
+                    name, self.source
+                ));
+            }
+
+            for target in matches {
+                if !selected.contains(target) {
+                    selected.push(target.clone());
+                }
+            }
+        }
+
+        for pattern in &self.exclusions {
+            let excluded = matching(pattern, &selected)?
+                .into_iter()
+                .cloned()
+                .collect::<Vec<String>>();
+            selected.retain(|target| !excluded.contains(target));
+        }
+
+        selected.sort();
+        Ok(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn available() -> Vec<String> {
+        [
+            "aarch64-unknown-linux-gnu",
+            "x86_64-pc-windows-msvc",
+            "x86_64-unknown-linux-gnu",
+            "x86_64-unknown-linux-musl",
+        ]
+        .iter()
+        .map(|target| target.to_string())
+        .collect()
+    }
+
+    fn select(filter: &str) -> Result<Vec<String>, String> {
+        TargetFilter::parse(filter)?.select(&available())
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(
+            select("x86_64-unknown-linux-gnu").unwrap(),
+            ["x86_64-unknown-linux-gnu"]
+        );
+    }
+
+    #[test]
+    fn literal_falls_back_to_regex() {
+        assert_eq!(
+            select("x86_64").unwrap(),
+            [
+                "x86_64-pc-windows-msvc",
+                "x86_64-unknown-linux-gnu",
+                "x86_64-unknown-linux-musl"
+            ]
+        );
+        assert_eq!(select("linux-gnu").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn glob() {
+        assert_eq!(
+            select("*-linux-gnu").unwrap(),
+            ["aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"]
+        );
+        assert_eq!(
+            select("glob:x86_64-*").unwrap(),
+            select("x86_64-*").unwrap()
+        );
+        // A glob is anchored, unlike a regular expression.
+        assert!(select("*-linux").is_err());
+    }
+
+    #[test]
+    fn regex() {
+        assert_eq!(
+            select("regex:^x86_64.*musl$").unwrap(),
+            ["x86_64-unknown-linux-musl"]
+        );
+        assert_eq!(
+            select("x86_64.*(gnu|msvc)").unwrap(),
+            ["x86_64-pc-windows-msvc", "x86_64-unknown-linux-gnu"]
+        );
+        assert!(TargetFilter::parse("regex:(").is_err());
+    }
+
+    #[test]
+    fn combined_without_duplicates() {
+        assert_eq!(
+            select("x86_64-unknown-linux-gnu, *-linux-gnu").unwrap(),
+            ["aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"]
+        );
+    }
+
+    #[test]
+    fn no_match() {
+        let error = select("x86_64-unknown-linux-gnu,riscv64gc-*").unwrap_err();
+        assert!(error.contains("does not match any available target"));
+        assert!(select("sparc").is_err());
+    }
+
+    #[test]
+    fn no_targets() {
+        assert_eq!(
+            TargetFilter::parse(" , ").unwrap_err(),
+            "No targets specified."
+        );
+    }
+
+    #[test]
+    fn exclusions() {
+        let filter = TargetFilter::parse("x86_64").unwrap();
+        assert_eq!(
+            filter
+                .clone()
+                .exclude("*-musl,x86_64-pc-windows-msvc")
+                .unwrap()
+                .select(&available())
+                .unwrap(),
+            ["x86_64-unknown-linux-gnu"]
+        );
+        assert_eq!(
+            filter
+                .clone()
+                .exclude("sparc-*")
+                .unwrap()
+                .select(&available())
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(filter.exclude("regex:(").is_err());
+    }
+}
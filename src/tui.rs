@@ -0,0 +1,248 @@
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Gauge, List, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::download::Stats;
+use crate::{format_size, logging};
+
+/// How often the dashboard is redrawn.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Number of log lines printed when the dashboard closes.
+const LINES_KEPT: usize = 20;
+
+/// The period that the download speed is averaged over.
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+/// A live view of the progress of a sync in the terminal, shown instead of the log lines until it is
+/// dropped.
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    pub fn start(stats: Arc<Stats>) -> Result<Self> {
+        let terminal = ratatui::try_init()?;
+        logging::capture(true);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || run(terminal, &stats, &stop))
+        };
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        ratatui::restore();
+        logging::capture(false);
+
+        // Keep the end of the sync (e.g. the summary) visible once the dashboard is gone.
+        for line in logging::captured(LINES_KEPT) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// The download speed over the last `SPEED_WINDOW`, from samples of the number of bytes received.
+#[derive(Default)]
+struct Speed {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Speed {
+    fn update(&mut self, received: u64) -> u64 {
+        let now = Instant::now();
+        self.samples.push_back((now, received));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) > SPEED_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        let (first_time, first_received) = self.samples.front().copied().unwrap();
+        bytes_per_second(received - first_received, now.duration_since(first_time))
+    }
+}
+
+fn bytes_per_second(bytes: u64, elapsed: Duration) -> u64 {
+    if elapsed.is_zero() {
+        0
+    } else {
+        (bytes as f64 / elapsed.as_secs_f64()) as u64
+    }
+}
+
+/// Formats a duration as `hh:mm:ss`, prefixed by the number of days if there are any.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+
+    match seconds / 86400 {
+        0 => time,
+        days => format!("{}d {}", days, time),
+    }
+}
+
+fn run(mut terminal: DefaultTerminal, stats: &Stats, stop: &AtomicBool) {
+    let started = Instant::now();
+    let mut speed = Speed::default();
+
+    while !stop.load(Ordering::Relaxed) {
+        let rate = speed.update(stats.received.load(Ordering::Relaxed));
+        if let Err(error) = terminal.draw(|frame| draw(frame, stats, started, rate)) {
+            log::warn!("Unable to draw the dashboard: {}", error);
+            return;
+        }
+
+        // Quitting the dashboard interrupts the sync, like Ctrl-C does without it. Everything that
+        // completed is kept in the journal.
+        if let Ok(true) = event::poll(TICK) {
+            if let Ok(Event::Key(key)) = event::read() {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if ctrl_c || key.code == KeyCode::Char('q') {
+                    ratatui::restore();
+                    logging::capture(false);
+                    log::warn!("Interrupted, the next run resumes where this one stopped.");
+                    std::process::exit(130);
+                }
+            }
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, stats: &Stats, started: Instant, rate: u64) {
+    let [section, totals, transfers, logs] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(5),
+        Constraint::Length(12),
+    ])
+    .areas(frame.area());
+
+    draw_section(frame, section, stats, started);
+
+    let totals_line = format!(
+        "Downloaded {} files ({}) · skipped {} · not found {} · failed {} · retries {} · {}/s",
+        stats.downloaded.load(Ordering::Relaxed),
+        format_size(stats.bytes.load(Ordering::Relaxed)),
+        stats.skipped.load(Ordering::Relaxed),
+        stats.not_found.lock().unwrap().len(),
+        stats.failed.lock().unwrap().len(),
+        stats.retries.load(Ordering::Relaxed),
+        format_size(rate)
+    );
+    frame.render_widget(
+        Paragraph::new(totals_line).block(Block::bordered().title("Totals")),
+        totals,
+    );
+
+    draw_transfers(frame, transfers, stats);
+
+    let lines = logging::captured(logs.height.saturating_sub(2) as usize);
+    frame.render_widget(List::new(lines).block(Block::bordered().title("Log")), logs);
+}
+
+/// The section being synced, with its progress and ETA when the number of files is known.
+fn draw_section(frame: &mut Frame, area: Rect, stats: &Stats, started: Instant) {
+    let section = stats.section.lock().unwrap().clone();
+    let (name, section_started) = section.unwrap_or_else(|| ("starting".to_string(), started));
+    let block = Block::bordered().title(format!(
+        "Syncing {} · {} elapsed",
+        name,
+        format_duration(started.elapsed())
+    ));
+
+    let done = stats.done.load(Ordering::Relaxed);
+    let total = stats.total.load(Ordering::Relaxed);
+
+    if total == 0 {
+        frame.render_widget(
+            Paragraph::new(format!("{} files processed", done)).block(block),
+            area,
+        );
+        return;
+    }
+
+    let eta = (done > 0).then(|| {
+        section_started
+            .elapsed()
+            .mul_f64((total - done.min(total)) as f64 / done as f64)
+    });
+    let label = format!(
+        "{}/{} files · ETA {}",
+        done,
+        total,
+        eta.map_or("unknown".to_string(), format_duration)
+    );
+
+    frame.render_widget(
+        Gauge::default()
+            .block(block)
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio((done as f64 / total as f64).min(1.0))
+            .label(label),
+        area,
+    );
+}
+
+fn draw_transfers(frame: &mut Frame, area: Rect, stats: &Stats) {
+    let transfers: Vec<_> = stats.transfers.lock().unwrap().values().cloned().collect();
+    let title = format!("Active downloads ({})", transfers.len());
+
+    let rows = transfers.iter().map(|transfer| {
+        let received = transfer.received.load(Ordering::Relaxed);
+        let progress = match transfer.length {
+            Some(length) => format!("{} / {}", format_size(received), format_size(length)),
+            None => format_size(received),
+        };
+        let speed = bytes_per_second(received, transfer.started.elapsed());
+
+        Row::new(vec![
+            transfer.url.clone(),
+            progress,
+            format!("{}/s", format_size(speed)),
+        ])
+    });
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(24),
+            Constraint::Length(12),
+        ],
+    )
+    .header(Row::new(vec!["URL", "Progress", "Speed"]).style(Style::default().fg(Color::Yellow)))
+    .block(Block::bordered().title(title));
+
+    frame.render_widget(table, area);
+}
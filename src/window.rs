@@ -0,0 +1,186 @@
+use chrono::{DateTime, FixedOffset, NaiveTime, TimeZone, Utc};
+use std::fmt;
+use std::time::Duration;
+
+/// The hours of the day that downloads run in (`--sync-window`), e.g. `01:00-06:00` in local time or
+/// `22:00-04:30 +02:00` at a UTC offset. A window that ends before it starts spans midnight.
+#[derive(Debug, Clone)]
+pub struct SyncWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// The UTC offset of the times, local time if missing.
+    offset: Option<FixedOffset>,
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|_| format!("Invalid time: {}", s.trim()))
+}
+
+fn parse_offset(s: &str) -> Result<FixedOffset, String> {
+    if s.eq_ignore_ascii_case("utc") || s == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+
+    let (sign, rest) = match (s.strip_prefix('+'), s.strip_prefix('-')) {
+        (Some(rest), _) => (1, rest),
+        (_, Some(rest)) => (-1, rest),
+        _ => return Err(format!("Invalid UTC offset: {}", s)),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let (hours, minutes): (i32, i32) = hours
+        .parse()
+        .ok()
+        .zip(minutes.parse().ok())
+        .filter(|(hours, minutes)| *hours <= 14 && *minutes < 60)
+        .ok_or_else(|| format!("Invalid UTC offset: {}", s))?;
+
+    Ok(FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).unwrap())
+}
+
+impl SyncWindow {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (range, offset) = match s.split_once(char::is_whitespace) {
+            Some((range, offset)) => (range, Some(parse_offset(offset.trim())?)),
+            None => (s, None),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid sync window: {}, e.g. 01:00-06:00", s))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+
+        if start == end {
+            return Err(format!("The sync window {} is empty.", s));
+        }
+
+        Ok(Self { start, end, offset })
+    }
+
+    /// How long until the window opens, or `None` if it is open. Windows in local time follow its
+    /// daylight saving time, those at a UTC offset do not.
+    pub fn until_open(&self) -> Option<Duration> {
+        match self.offset {
+            Some(offset) => self.until_open_at(Utc::now().with_timezone(&offset)),
+            None => self.until_open_at(chrono::Local::now()),
+        }
+    }
+
+    fn until_open_at<Tz: TimeZone>(&self, now: DateTime<Tz>) -> Option<Duration> {
+        let time = now.time();
+        let open = if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        };
+        if open {
+            return None;
+        }
+
+        let mut start = now.naive_local().date().and_time(self.start);
+        if start <= now.naive_local() {
+            start += chrono::Duration::days(1);
+        }
+        // A start skipped by a daylight saving time change is an hour later.
+        let start = now
+            .timezone()
+            .from_local_datetime(&start)
+            .earliest()
+            .or_else(|| {
+                now.timezone()
+                    .from_local_datetime(&(start + chrono::Duration::hours(1)))
+                    .earliest()
+            })?;
+
+        // A second past the start, so the window is open when the wait is over.
+        Some((start - now).to_std().unwrap_or_default() + Duration::from_secs(1))
+    }
+}
+
+impl fmt::Display for SyncWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )?;
+        match self.offset {
+            Some(offset) => write!(f, " {}", offset),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(offset: i32, hour: u32, minute: u32) -> DateTime<FixedOffset> {
+        FixedOffset::east(offset)
+            .ymd(2021, 3, 1)
+            .and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn parse() {
+        let window = SyncWindow::parse("01:00-06:30").unwrap();
+        assert_eq!(window.to_string(), "01:00-06:30");
+        assert!(window.offset.is_none());
+
+        let window = SyncWindow::parse(" 22:00-04:30 +02:00 ").unwrap();
+        assert_eq!(window.to_string(), "22:00-04:30 +02:00");
+        assert_eq!(
+            SyncWindow::parse("22:00-04:30 -5").unwrap().to_string(),
+            "22:00-04:30 -05:00"
+        );
+        assert_eq!(
+            SyncWindow::parse("22:00-04:30 UTC").unwrap().to_string(),
+            "22:00-04:30 +00:00"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(SyncWindow::parse("01:00-01:00")
+            .unwrap_err()
+            .contains("empty"));
+        assert!(SyncWindow::parse("01:00").is_err());
+        assert!(SyncWindow::parse("01:00-25:00").is_err());
+        assert!(SyncWindow::parse("1am-6am").is_err());
+        assert!(SyncWindow::parse("01:00-06:00 +15:00").is_err());
+        assert!(SyncWindow::parse("01:00-06:00 +02:60").is_err());
+        assert!(SyncWindow::parse("01:00-06:00 CET").is_err());
+        assert!(SyncWindow::parse("01:00-06:00 é").is_err());
+    }
+
+    #[test]
+    fn until_open() {
+        let window = SyncWindow::parse("01:00-06:00 +00:00").unwrap();
+        assert_eq!(window.until_open_at(at(0, 1, 0)), None);
+        assert_eq!(window.until_open_at(at(0, 5, 59)), None);
+        assert_eq!(
+            window.until_open_at(at(0, 0, 30)),
+            Some(Duration::from_secs(30 * 60 + 1))
+        );
+        // After the window it opens the next day.
+        assert_eq!(
+            window.until_open_at(at(0, 6, 0)),
+            Some(Duration::from_secs(19 * 3600 + 1))
+        );
+    }
+
+    #[test]
+    fn until_open_across_midnight() {
+        let window = SyncWindow::parse("22:00-04:30 +02:00").unwrap();
+        assert_eq!(window.until_open_at(at(2 * 3600, 23, 0)), None);
+        assert_eq!(window.until_open_at(at(2 * 3600, 3, 0)), None);
+        assert_eq!(
+            window.until_open_at(at(2 * 3600, 4, 30)),
+            Some(Duration::from_secs(17 * 3600 + 30 * 60 + 1))
+        );
+        assert_eq!(
+            window.until_open_at(at(2 * 3600, 21, 0)),
+            Some(Duration::from_secs(3600 + 1))
+        );
+    }
+}